@@ -0,0 +1,9 @@
+-- Subscriber rows that could not be turned into a leaf, recorded by each validated tree build.
+-- The rows stay in subscriber_storage; operators fix them there and the next build clears them here.
+CREATE TABLE subscriber_quarantine (
+    wallet_address      TEXT PRIMARY KEY,        -- Raw value from subscriber_storage
+    expiration_ts       BIGINT NOT NULL,
+    reason              TEXT NOT NULL,           -- Why the row was rejected
+    duplicate_of        VARCHAR(42),             -- Accepted row it collides with, for duplicates
+    quarantined_at      TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
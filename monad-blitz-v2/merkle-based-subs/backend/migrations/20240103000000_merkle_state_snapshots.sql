@@ -0,0 +1,15 @@
+-- How each root in merkle_state was built, so the tree can be rebuilt for historical proofs
+ALTER TABLE merkle_state
+    ADD COLUMN tree_layout  VARCHAR(16) NOT NULL DEFAULT 'layered', -- 'layered' or 'standard'
+    ADD COLUMN sort_leaves  BOOLEAN NOT NULL DEFAULT TRUE;
+
+-- The subscriber set behind each root in merkle_state
+CREATE TABLE merkle_state_subscribers (
+    state_id            INTEGER NOT NULL REFERENCES merkle_state(id) ON DELETE CASCADE,
+    value_index         INTEGER NOT NULL,         -- Leaf order passed to the tree builder
+    wallet_address      VARCHAR(42) NOT NULL,
+    expiration_ts       BIGINT NOT NULL,
+    PRIMARY KEY (state_id, value_index)
+);
+
+CREATE INDEX idx_merkle_state_root_hash ON merkle_state (root_hash);
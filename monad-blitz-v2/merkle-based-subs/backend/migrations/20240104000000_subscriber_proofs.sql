@@ -0,0 +1,8 @@
+-- Precomputed proofs of every subscriber, one set per published root
+CREATE TABLE subscriber_proofs (
+    root_hash           VARCHAR(66) NOT NULL,
+    wallet_address      VARCHAR(42) NOT NULL,
+    expiration_ts       BIGINT NOT NULL,
+    proof               BYTEA NOT NULL,          -- Concatenated 32-byte siblings, leaf first
+    PRIMARY KEY (root_hash, wallet_address)
+);
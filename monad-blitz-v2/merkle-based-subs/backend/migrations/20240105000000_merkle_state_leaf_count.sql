@@ -0,0 +1,4 @@
+-- Number of leaves behind each root, so an empty set (root = the empty root) can be told
+-- apart from a root recorded before snapshots existed. NULL for those older rows.
+ALTER TABLE merkle_state
+    ADD COLUMN leaf_count INTEGER;
@@ -0,0 +1,5 @@
+-- Subscription tier of each subscriber, committed in tiered leaves
+-- abi.encode(address, uint256 planId, uint256 expiration); see merkle::tiers.
+-- Existing subscribers keep plan 0.
+ALTER TABLE subscriber_storage
+    ADD COLUMN plan_id INTEGER NOT NULL DEFAULT 0 CHECK (plan_id >= 0);
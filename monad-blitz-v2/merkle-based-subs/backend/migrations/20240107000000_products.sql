@@ -0,0 +1,15 @@
+-- Products a wallet can subscribe to; the id is committed in product leaves as uint256 productId
+CREATE TABLE products (
+    id                  SERIAL PRIMARY KEY,
+    name                TEXT NOT NULL UNIQUE,
+    created_at          TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Product 0 holds every subscription from before products existed
+INSERT INTO products (id, name) VALUES (0, 'default');
+
+-- One subscription per (wallet, product) instead of one per wallet
+ALTER TABLE subscriber_storage
+    ADD COLUMN product_id INTEGER NOT NULL DEFAULT 0 REFERENCES products(id);
+ALTER TABLE subscriber_storage DROP CONSTRAINT subscriber_storage_pkey;
+ALTER TABLE subscriber_storage ADD PRIMARY KEY (wallet_address, product_id);
@@ -0,0 +1,7 @@
+-- 256-bit entitlement bitmap of each subscriber, committed in entitled leaves
+-- (uint8(2), address, uint256 entitlements, uint256 expiration), led by LeafKind::Entitled;
+-- see merkle::entitlements. Big-endian, so entitlement k is bit k of the uint256.
+-- Existing subscribers start with no entitlements.
+ALTER TABLE subscriber_storage
+    ADD COLUMN entitlements BYTEA NOT NULL DEFAULT decode(repeat('00', 32), 'hex')
+        CHECK (length(entitlements) = 32);
@@ -0,0 +1,25 @@
+-- Usage-quota subscriptions: credits a subscriber may consume per epoch, committed in credit
+-- leaves (uint8(3), address, uint256 epoch, uint256 credits, uint256 expiration), led by
+-- LeafKind::Credit; see merkle::credits.
+-- NULL keeps a subscriber time-based only.
+ALTER TABLE subscriber_storage
+    ADD COLUMN credit_allowance BIGINT CHECK (credit_allowance >= 0);
+
+-- One row per epoch; only the open epoch has no ended_at
+CREATE TABLE credit_epochs (
+    epoch               BIGINT PRIMARY KEY CHECK (epoch >= 0),
+    started_at          TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ended_at            TIMESTAMP,
+    root_hash           VARCHAR(66)              -- Root committing the epoch's credits
+);
+CREATE UNIQUE INDEX credit_epochs_single_open ON credit_epochs ((ended_at IS NULL))
+    WHERE ended_at IS NULL;
+
+-- Credits committed to each subscriber for an epoch, and how many were consumed off-chain
+CREATE TABLE credit_balances (
+    epoch               BIGINT NOT NULL REFERENCES credit_epochs(epoch),
+    wallet_address      VARCHAR(42) NOT NULL,
+    credits             BIGINT NOT NULL CHECK (credits >= 0),
+    consumed            BIGINT NOT NULL DEFAULT 0 CHECK (consumed >= 0 AND consumed <= credits),
+    PRIMARY KEY (epoch, wallet_address)
+);
@@ -0,0 +1,8 @@
+-- Addresses are stored as written (checksummed or lowercase) and looked up with
+-- lower(wallet_address) = normalized address, which the primary keys cannot serve
+CREATE INDEX subscriber_storage_lower_wallet
+    ON subscriber_storage (lower(wallet_address), product_id);
+CREATE INDEX subscriber_proofs_lower_wallet
+    ON subscriber_proofs (root_hash, lower(wallet_address));
+CREATE INDEX credit_balances_lower_wallet
+    ON credit_balances (epoch, lower(wallet_address));
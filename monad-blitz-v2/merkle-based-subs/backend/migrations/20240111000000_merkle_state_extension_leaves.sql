@@ -0,0 +1,12 @@
+-- The extension leaves behind each root in merkle_state (see merkle::published), after the
+-- plain leaves of merkle_state_subscribers. Columns a kind does not use are NULL.
+CREATE TABLE merkle_state_extension_leaves (
+    state_id            INTEGER NOT NULL REFERENCES merkle_state(id) ON DELETE CASCADE,
+    value_index         INTEGER NOT NULL,         -- Leaf order passed to the tree builder
+    kind                VARCHAR(16) NOT NULL,     -- 'tiered' or 'product'
+    wallet_address      VARCHAR(42) NOT NULL,
+    expiration_ts       BIGINT NOT NULL,
+    product_id          INTEGER,                  -- 'product'
+    plan_id             INTEGER,                  -- 'tiered' and 'product'
+    PRIMARY KEY (state_id, value_index)
+);
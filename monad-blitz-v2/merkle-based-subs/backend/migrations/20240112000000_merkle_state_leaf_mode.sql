@@ -0,0 +1,7 @@
+-- The deployment each root's leaves are bound to (see merkle::deployment::LeafMode), so
+-- bound roots can be rebuilt for historical proofs. Both NULL for unbound leaves.
+ALTER TABLE merkle_state
+    ADD COLUMN chain_id          BIGINT,
+    ADD COLUMN contract_address  VARCHAR(42),
+    ADD CONSTRAINT merkle_state_deployment_complete
+        CHECK ((chain_id IS NULL) = (contract_address IS NULL));
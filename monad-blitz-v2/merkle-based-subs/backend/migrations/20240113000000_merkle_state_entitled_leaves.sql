@@ -0,0 +1,3 @@
+-- Entitled extension leaves of the published root: the bitmap each one committed
+ALTER TABLE merkle_state_extension_leaves
+    ADD COLUMN entitlements BYTEA CHECK (length(entitlements) = 32); -- 'entitled'
@@ -0,0 +1,4 @@
+-- Credit extension leaves of the published root: the epoch and credits each one committed
+ALTER TABLE merkle_state_extension_leaves
+    ADD COLUMN epoch    BIGINT CHECK (epoch >= 0),    -- 'credit'
+    ADD COLUMN credits  BIGINT CHECK (credits >= 0);  -- 'credit'
@@ -0,0 +1,5 @@
+-- Subscriptions to every product are validated, so a wallet can have one quarantined row
+-- per product. Rows quarantined before this keep the default product.
+ALTER TABLE subscriber_quarantine ADD COLUMN product_id INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE subscriber_quarantine DROP CONSTRAINT subscriber_quarantine_pkey;
+ALTER TABLE subscriber_quarantine ADD PRIMARY KEY (wallet_address, product_id);
@@ -0,0 +1,11 @@
+-- Roll-overs only credit the rows validation accepts, at most one per normalized wallet, so
+-- balances can be looked up by lower(wallet_address). Balances of case variants credited
+-- before that are dropped, keeping the first variant in byte order as validation does.
+DELETE FROM credit_balances b
+    USING credit_balances o
+    WHERE o.epoch = b.epoch
+      AND lower(o.wallet_address) = lower(b.wallet_address)
+      AND o.wallet_address COLLATE "C" < b.wallet_address COLLATE "C";
+DROP INDEX credit_balances_lower_wallet;
+CREATE UNIQUE INDEX credit_balances_lower_wallet
+    ON credit_balances (epoch, lower(wallet_address));
@@ -6,8 +6,8 @@ use std::env;
 use std::fs;
 use std::time::Duration;
 
-mod merkle;
-mod model;
+use backend::merkle;
+use backend::model::Expiration;
 
 pub async fn get_db_pool() -> Result<PgPool> {
     let database_url =
@@ -27,6 +27,15 @@ pub async fn get_db_pool() -> Result<PgPool> {
 async fn main() -> Result<()> {
     dotenvy::dotenv().context("Failed to load .env file")?;
 
+    // Only regenerate the cross-implementation test vectors, without touching the DB or chain
+    if let Ok(vectors_path) = env::var("TEST_VECTORS_PATH") {
+        let vectors = merkle::vectors::generate_test_vectors()?;
+        merkle::vectors::write_test_vectors(&vectors_path, &vectors)?;
+        merkle::vectors::check_test_vectors(&merkle::vectors::read_test_vectors(&vectors_path)?)?;
+        println!("✅ Wrote {} test vector cases to {}", vectors.cases.len(), vectors_path);
+        return Ok(());
+    }
+
     let pool = get_db_pool().await?;
     println!("✅ Successfully connected to database!");
 
@@ -67,12 +76,13 @@ async fn main() -> Result<()> {
     // (the contract uses msg.sender to reconstruct the leaf)
     let signer_address = eth_client.signer_address();
     println!("   Backend wallet (signer): {}", signer_address);
-    let signer_expiration = (Utc::now().timestamp() + (30 * 24 * 60 * 60)) as i64;
+    let signer_expiration =
+        Expiration::from_unix(Utc::now().timestamp() + (30 * 24 * 60 * 60))?;
     let last_updated = Utc::now().naive_utc();
     sqlx::query!(
-        "INSERT INTO subscriber_storage (wallet_address, expiration_ts, last_updated_at) VALUES ($1, $2, $3) ON CONFLICT (wallet_address) DO UPDATE SET expiration_ts = $2, last_updated_at = $3",
+        "INSERT INTO subscriber_storage (wallet_address, expiration_ts, last_updated_at) VALUES ($1, $2, $3) ON CONFLICT (wallet_address, product_id) DO UPDATE SET expiration_ts = $2, last_updated_at = $3",
         signer_address,
-        signer_expiration,
+        signer_expiration.as_i64(),
         last_updated
     )
     .execute(&pool)
@@ -80,21 +90,88 @@ async fn main() -> Result<()> {
     println!("   ✅ Backend wallet added as subscriber (exp: {})", signer_expiration);
 
     println!("\n🔍 Checking contract current root...");
-    match eth_client.get_current_root().await {
+    let onchain_root = match eth_client.get_current_root().await {
         Ok(current_root) => {
             println!("   ✅ Contract is accessible");
             println!("   Current root: 0x{}", hex::encode(current_root));
+            hex::encode(current_root)
         }
         Err(e) => {
             println!("   ⚠️  Could not fetch current root (maybe contract not deployed?): {}", e);
+            String::new()
+        }
+    };
+
+    // Usage-quota subscriptions: start the next credit epoch once the open one has ended,
+    // rolling unused credits over, before the open epoch's credits are published below
+    if let Ok(epoch_seconds) = env::var("CREDIT_EPOCH_SECONDS") {
+        let epoch_seconds: i64 = epoch_seconds
+            .parse()
+            .context("CREDIT_EPOCH_SECONDS must be a number of seconds")?;
+        let epoch_length = chrono::TimeDelta::try_seconds(epoch_seconds)
+            .context("CREDIT_EPOCH_SECONDS is out of range")?;
+        if let Some(started) = merkle::credits::roll_over_if_ended(&pool, epoch_length).await? {
+            println!("\n🪙 Started credit epoch {}", started.epoch);
         }
     }
 
     // 1. Build Merkle Tree from database (OZ-compatible sorted-pair tree)
-    let (root_hash, tree, subscriber_data) = merkle::tree::build_tree_from_db(&pool).await?;
+    // The root commits the plain subscriber leaves followed by tiered, product, entitled and
+    // credit leaves (see merkle::published)
+    // Malformed rows are skipped and written to subscriber_quarantine instead of aborting
+    let tree_options = merkle::tree::TreeOptions::default();
+    // Leaves stay unbound: the deployed contract rebuilds them without the deployment
+    let (published_tree, report) = merkle::published::build_published_tree_from_db(
+        &pool,
+        tree_options,
+        merkle::deployment::LeafMode::Unbound,
+        merkle::validation::ValidationPolicy::SkipAndReport,
+    )
+    .await?;
+    let root_hash = published_tree.root_hex.clone();
+    let tree = &published_tree.tree;
     println!("\n🌲 Merkle Tree Built (OpenZeppelin-compatible):");
     println!("   Root Hash: 0x{}", root_hash);
-    println!("   Total subscribers: {}", subscriber_data.len());
+    println!("   Total subscribers: {}", report.accepted.len());
+    println!(
+        "   Tiered subscribers: {}",
+        published_tree.tiered_subscribers().len()
+    );
+    println!(
+        "   Product subscriptions: {}",
+        published_tree.product_subscriptions().len()
+    );
+    println!(
+        "   Subscribers with entitlements: {}",
+        published_tree.entitled_subscribers().len()
+    );
+    if tree.is_empty() {
+        println!("   ℹ️  No subscribers: publishing the empty root");
+    }
+    if !report.is_clean() {
+        println!(
+            "   ⚠️  Quarantined {} rejected and {} duplicate rows",
+            report.rejected.len(),
+            report.duplicates.len()
+        );
+        for row in &report.rejected {
+            println!("      {} (product {}): {}", row.wallet_address, row.product_id, row.reason);
+        }
+    }
+    let subscriber_data = published_tree.subscribers.clone();
+
+    // Show exactly what the new root changes compared to the last published one
+    let published = match merkle::history::load_latest_synced_tree(&pool).await? {
+        Some(historical) => historical.snapshot(),
+        // Nothing published from this backend yet: every subscriber is new
+        None => merkle::diff::Snapshot::new(onchain_root, Vec::new()),
+    };
+    let new_snapshot = merkle::diff::Snapshot::new(root_hash.clone(), subscriber_data.clone());
+    let diff = merkle::diff::diff_snapshots(&published, &new_snapshot);
+    println!("\n📋 Changes since the last published root:");
+    for line in diff.to_string().lines() {
+        println!("   {}", line);
+    }
 
     // 2. Convert hex root to bytes
     let root_bytes: [u8; 32] = hex::decode(&root_hash)?
@@ -111,24 +188,38 @@ async fn main() -> Result<()> {
                 println!("   🔍 View on explorer: {}/tx/{}", explorer_url.trim_end_matches('/'), tx_hash);
             }
 
-            // 4. Store the transaction in database
-            merkle::updatestate::update_merkle_state(
-                &pool,
-                &root_hash,
-                Some(tx_hash),
-            )
-            .await?;
+            // 4. Store the transaction in database, with the leaf snapshot behind the root
+            merkle::history::record_merkle_state(&pool, &published_tree, Some(tx_hash)).await?;
             println!("✅ Saved to database with tx hash");
+
+            // The root commits the open credit epoch's credits too
+            if let Some(credit) = published_tree.credit_subscribers().first() {
+                merkle::credits::record_credit_root(&pool, credit.epoch, &root_hash).await?;
+            }
+
+            // Precompute every subscriber's proof for the new root
+            let proofs = merkle::export::export_proofs(tree, &subscriber_data)?;
+            merkle::export::store_proofs(&pool, &proofs).await?;
+            if let Ok(export_dir) = env::var("PROOF_EXPORT_DIR") {
+                merkle::export::write_proof_files(&export_dir, &proofs)?;
+                println!("✅ Exported {} proofs to {}", proofs.len(), export_dir);
+            }
         }
         Err(e) => {
             eprintln!("❌ Failed to update on-chain: {}", e);
             eprintln!("💡 Tip: Make sure the contract address is correct and you have MON on Monad testnet.");
 
             // Still save to database but mark as not synced
-            merkle::updatestate::update_merkle_state(&pool, &root_hash, None).await?;
+            merkle::history::record_merkle_state(&pool, &published_tree, None).await?;
         }
     }
 
+    let credit_subscribers = published_tree.credit_subscribers();
+    if let Some(epoch) = credit_subscribers.first().map(|s| s.epoch) {
+        println!("\n🪙 Credit epoch {}:", epoch);
+        println!("   Subscribers with credits: {}", credit_subscribers.len());
+    }
+
     // 5. Off-chain verification test (any subscriber)
     println!("\n🔐 Testing Off-Chain Proof Verification...");
     if let Some((first_user, expiration)) = subscriber_data.first() {
@@ -136,7 +227,7 @@ async fn main() -> Result<()> {
         println!("   Expiration: {}", expiration);
 
         if let Some(proof) =
-            merkle::tree::get_proof_for_user(&tree, &subscriber_data, first_user)
+            merkle::tree::get_proof_for_user(tree, &subscriber_data, first_user)
         {
             let is_valid = merkle::tree::verify_subscription(
                 &root_hash,
@@ -152,11 +243,30 @@ async fn main() -> Result<()> {
         }
     }
 
+    // Tiered proof of the signer's plan against the same root
+    let tiered_subscribers = published_tree.tiered_subscribers();
+    if let Some((plan_id, expiration, proof)) =
+        merkle::tiers::get_tiered_proof_for_user(tree, &tiered_subscribers, &signer_address)
+    {
+        let is_valid = merkle::tiers::verify_tiered_subscription(
+            &root_hash,
+            &proof,
+            &signer_address,
+            plan_id,
+            expiration,
+        )?;
+        println!(
+            "   Tiered verification (plan {}): {}",
+            plan_id,
+            if is_valid { "✓ VALID" } else { "✗ INVALID" }
+        );
+    }
+
     // 6. ON-CHAIN verification 🔗 (using the backend wallet which IS a subscriber)
     println!("\n🔗 Testing On-Chain Proof Verification...");
     println!("   Signer address: {}", signer_address);
     if let Some(proof) =
-        merkle::tree::get_proof_for_user(&tree, &subscriber_data, &signer_address)
+        merkle::tree::get_proof_for_user(tree, &subscriber_data, &signer_address)
     {
         // Print proof in copy-paste format for the frontend
         let proof_hex: Vec<String> = proof
@@ -184,7 +294,7 @@ async fn main() -> Result<()> {
         // Now call the contract
         println!("   Sending verifySubscription tx to contract...");
         match eth_client
-            .verify_subscription_onchain(proof, signer_expiration as u64)
+            .verify_subscription_onchain(proof, signer_expiration)
             .await
         {
             Ok(tx_hash) => {
@@ -206,9 +316,9 @@ async fn main() -> Result<()> {
     println!("\n🧪 Testing Tampering Detection...");
     if let Some((first_user, _)) = subscriber_data.first() {
         if let Some(proof) =
-            merkle::tree::get_proof_for_user(&tree, &subscriber_data, first_user)
+            merkle::tree::get_proof_for_user(tree, &subscriber_data, first_user)
         {
-            let fake_expiration = 9999999999i64;
+            let fake_expiration = Expiration::new(9999999999)?;
             let is_valid_tamper = merkle::tree::verify_subscription(
                 &root_hash,
                 &proof,
@@ -0,0 +1,598 @@
+use anyhow::{Context, Result};
+use chrono::{TimeDelta, Utc};
+use ethers::abi::Token;
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use super::encoding::{LeafEncoding, LeafKind};
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::index::IndexedValue;
+use super::tree::{
+    check_unique_leaves, get_proof_for_value, subscriber_values, verify_value, MerkleTree, Proof,
+    TreeOptions,
+};
+use super::validation::{normalize_address, validate_subscribers};
+use crate::model::{CreditEpoch, Epoch, Expiration, DEFAULT_PRODUCT};
+
+// Credit leaves commit how many credits (API calls, ...) a subscriber may consume in an epoch:
+//
+//   leaf = keccak256(bytes.concat(keccak256(abi.encode(
+//              uint8(3), address, uint256 epoch, uint256 credits, uint256 expiration))))
+//
+// The root only proves the allowance. Consumption is metered off-chain in `credit_balances`,
+// one row per (epoch, wallet), where `consume_credits` decrements atomically and rejects
+// overdrafts. Starting an epoch closes the open one and commits each subscriber's
+// `credit_allowance` plus whatever was left unused, capped at one allowance (and at the
+// BIGINT range), so the next root carries the rolled-over quotas. Only wallets validation
+// accepts get a balance, so an epoch has at most one per normalized address.
+// The leading `LeafKind::Credit` keeps credit leaves apart from product leaves, which have
+// the same types after it.
+
+/// Leaf encoding of credit leaves: `abi.encode(uint8, address, uint256, uint256, uint256)`
+pub const CREDIT_LEAF_ENCODING: [&str; 5] = ["uint8", "address", "uint256", "uint256", "uint256"];
+
+/// A subscriber's credits for one epoch, as committed in its leaf
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreditSubscriber {
+    pub wallet_address: String,
+    pub epoch: Epoch,
+    pub credits: u64,
+    pub expiration: Expiration,
+}
+
+impl IndexedValue for CreditSubscriber {
+    type Key = String;
+
+    fn key(&self) -> String {
+        normalize_address(&self.wallet_address)
+    }
+
+    fn leaf<H: MerkleHasher>(&self) -> Result<[u8; 32]> {
+        compute_credit_leaf_with::<H>(
+            &self.wallet_address,
+            self.epoch,
+            self.credits,
+            self.expiration,
+        )
+    }
+}
+
+/// Result of `consume_credits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consumption {
+    /// The credits were deducted; `remaining` are left this epoch
+    Consumed { remaining: u64 },
+    /// Rejected without deducting anything: only `remaining` credits are left
+    Overdraft { remaining: u64 },
+}
+
+/// The `(uint8, address, uint256, uint256, uint256)` encoding of credit leaves
+pub fn credit_encoding() -> LeafEncoding {
+    LeafEncoding::new(&CREDIT_LEAF_ENCODING).expect("valid Solidity types")
+}
+
+/// Leaf values `(LeafKind::Credit, address, epoch, credits, expiration)`,
+/// in `credit_encoding()` order
+pub fn credit_values(
+    address_hex: &str,
+    epoch: Epoch,
+    credits: u64,
+    expiration: Expiration,
+) -> Result<Vec<Token>> {
+    let mut values = subscriber_values(address_hex, expiration)?;
+    values.insert(0, LeafKind::Credit.token());
+    values.insert(2, Token::Uint(U256::from(epoch)));
+    values.insert(3, Token::Uint(U256::from(credits)));
+    Ok(values)
+}
+
+/// Credit leaf matching the contract's `keccak256(bytes.concat(keccak256(abi.encode(
+/// uint8(3), address, epoch, credits, expiration))))`
+pub fn compute_credit_leaf(
+    address_hex: &str,
+    epoch: Epoch,
+    credits: u64,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    compute_credit_leaf_with::<Keccak256Hasher>(address_hex, epoch, credits, expiration)
+}
+
+/// `compute_credit_leaf` with any hasher
+pub fn compute_credit_leaf_with<H: MerkleHasher>(
+    address_hex: &str,
+    epoch: Epoch,
+    credits: u64,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    let values = credit_values(address_hex, epoch, credits, expiration)?;
+    credit_encoding().leaf_hash_with::<H>(&values)
+}
+
+// ───────────────────────────────────────────────────
+// Allowances, epochs and consumption in the database
+// ───────────────────────────────────────────────────
+
+/// Set the credits a subscriber gets per epoch, or None to make it time-based only.
+/// Takes effect when the next epoch starts.
+/// Of several case variants of the wallet, only the row validation accepts is updated,
+/// as in `entitlements::set_entitlements`.
+pub async fn set_credit_allowance(
+    pool: &PgPool,
+    wallet_address: &str,
+    allowance: Option<u64>,
+) -> Result<()> {
+    let allowance = allowance.map(i64::try_from).transpose()?;
+    let updated = sqlx::query!(
+        "UPDATE subscriber_storage SET credit_allowance = $3, last_updated_at = $4
+         WHERE product_id = $2 AND wallet_address = (
+             SELECT wallet_address FROM subscriber_storage
+             WHERE lower(wallet_address) = $1 AND product_id = $2 AND expiration_ts >= 0
+             ORDER BY wallet_address COLLATE \"C\" LIMIT 1)",
+        normalize_address(wallet_address),
+        DEFAULT_PRODUCT as i32,
+        allowance,
+        Utc::now().naive_utc()
+    )
+    .execute(pool)
+    .await
+    .with_context(|| format!("Failed to store credit allowance of {}", wallet_address))?;
+
+    if updated.rows_affected() == 0 {
+        return Err(anyhow::anyhow!("No subscriber {}", wallet_address));
+    }
+    Ok(())
+}
+
+/// The open epoch, if any epoch was ever started
+pub async fn current_credit_epoch(pool: &PgPool) -> Result<Option<CreditEpoch>> {
+    let epoch = sqlx::query_as!(
+        CreditEpoch,
+        "SELECT epoch, started_at, ended_at, root_hash FROM credit_epochs WHERE ended_at IS NULL"
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(epoch)
+}
+
+/// Close the open epoch and start the next one (epoch 0 if none was ever started),
+/// committing each credit subscriber's allowance plus its rolled-over unused credits.
+/// Rows that validation rejects or finds duplicated get no balance.
+/// Consumption waits for this to finish, so no credit is spent in a closed epoch.
+pub async fn start_credit_epoch(pool: &PgPool) -> Result<CreditEpoch> {
+    start_credit_epoch_after(pool, None)
+        .await?
+        .context("Credit epoch was not started")
+}
+
+/// `start_credit_epoch`, unless the open epoch is younger than `min_age`.
+/// The age is checked under the lock on the open epoch, so of several concurrent callers
+/// only the first one rolls over.
+async fn start_credit_epoch_after(
+    pool: &PgPool,
+    min_age: Option<TimeDelta>,
+) -> Result<Option<CreditEpoch>> {
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+
+    let open = sqlx::query!(
+        "SELECT epoch, started_at FROM credit_epochs WHERE ended_at IS NULL FOR UPDATE"
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let (Some(open), Some(min_age)) = (&open, min_age) {
+        // An end past the representable range is never reached
+        let ends_at = open.started_at.checked_add_signed(min_age);
+        if ends_at.is_none_or(|ends_at| now < ends_at) {
+            return Ok(None);
+        }
+    }
+    let accepted: Vec<String> = validate_subscribers(pool)
+        .await?
+        .accepted
+        .into_iter()
+        .map(|(address, _)| address)
+        .collect();
+
+    let previous = open.map(|open| open.epoch);
+    if let Some(previous) = previous {
+        sqlx::query!(
+            "UPDATE credit_epochs SET ended_at = $2 WHERE epoch = $1",
+            previous,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let epoch = sqlx::query_as!(
+        CreditEpoch,
+        "INSERT INTO credit_epochs (epoch, started_at) VALUES ($1, $2)
+         RETURNING epoch, started_at, ended_at, root_hash",
+        previous.map_or(0, |previous| previous + 1),
+        now
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO credit_balances (epoch, wallet_address, credits)
+         SELECT $1, s.wallet_address,
+                LEAST(s.credit_allowance::numeric
+                          + LEAST(COALESCE(b.credits - b.consumed, 0), s.credit_allowance),
+                      9223372036854775807)::bigint
+         FROM subscriber_storage s
+         LEFT JOIN credit_balances b ON b.epoch = $2 AND b.wallet_address = s.wallet_address
+         WHERE s.product_id = $3 AND s.credit_allowance IS NOT NULL
+           AND s.wallet_address = ANY($4)",
+        epoch.epoch,
+        previous,
+        DEFAULT_PRODUCT as i32,
+        &accepted
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to roll over credit balances")?;
+
+    tx.commit().await?;
+    Ok(Some(epoch))
+}
+
+/// Start the next epoch if the open one is at least `epoch_length` old, or the first one
+/// if none was ever started. Returns the new epoch, or None if the open one keeps running.
+/// Fails if `epoch_length` is not positive.
+pub async fn roll_over_if_ended(
+    pool: &PgPool,
+    epoch_length: TimeDelta,
+) -> Result<Option<CreditEpoch>> {
+    if epoch_length <= TimeDelta::zero() {
+        return Err(anyhow::anyhow!(
+            "Credit epoch length must be positive, got {}",
+            epoch_length
+        ));
+    }
+    start_credit_epoch_after(pool, Some(epoch_length)).await
+}
+
+/// Remember the published root that commits `epoch`'s credits
+pub async fn record_credit_root(pool: &PgPool, epoch: Epoch, root_hex: &str) -> Result<()> {
+    sqlx::query!(
+        "UPDATE credit_epochs SET root_hash = $2 WHERE epoch = $1",
+        i64::try_from(epoch)?,
+        root_hex
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Deduct `amount` credits from the subscriber's balance in the open epoch.
+/// The check and the deduction are one statement, so concurrent calls never overdraw.
+/// A call that arrives during a roll-over waits for it and deducts from the new epoch.
+/// Errors if no epoch is open or the subscriber has no credits this epoch.
+pub async fn consume_credits(
+    pool: &PgPool,
+    wallet_address: &str,
+    amount: u64,
+) -> Result<Consumption> {
+    let amount = i64::try_from(amount)?;
+    let wallet = normalize_address(wallet_address);
+    let mut tx = pool.begin().await?;
+
+    // Shares the lock `start_credit_epoch` takes, so a roll-over sees every deduction.
+    // If a roll-over held it, the epoch we waited for is closed by the time we get it, and
+    // only the next statement sees the epoch that replaced it: look once more.
+    let mut epoch = None;
+    for _ in 0..2 {
+        epoch =
+            sqlx::query_scalar!("SELECT epoch FROM credit_epochs WHERE ended_at IS NULL FOR SHARE")
+                .fetch_optional(&mut *tx)
+                .await?;
+        if epoch.is_some() {
+            break;
+        }
+    }
+    let epoch = epoch.context("No credit epoch is open")?;
+
+    // An epoch has at most one balance per normalized wallet (credit_balances_lower_wallet)
+    let remaining = sqlx::query_scalar!(
+        r#"UPDATE credit_balances SET consumed = consumed + $3
+           WHERE epoch = $1 AND lower(wallet_address) = $2 AND credits - consumed >= $3
+           RETURNING credits - consumed AS "remaining!""#,
+        epoch,
+        wallet,
+        amount
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let consumption = match remaining {
+        Some(remaining) => Consumption::Consumed {
+            remaining: u64::try_from(remaining)?,
+        },
+        None => {
+            let remaining = sqlx::query_scalar!(
+                r#"SELECT credits - consumed AS "remaining!" FROM credit_balances
+                   WHERE epoch = $1 AND lower(wallet_address) = $2"#,
+                epoch,
+                wallet
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .with_context(|| format!("{} has no credits in epoch {}", wallet_address, epoch))?;
+            Consumption::Overdraft {
+                remaining: u64::try_from(remaining)?,
+            }
+        }
+    };
+
+    tx.commit().await?;
+    Ok(consumption)
+}
+
+/// Load the credits committed for `epoch`, sorted by wallet_address like `load_subscribers`.
+pub async fn load_credit_subscribers(pool: &PgPool, epoch: Epoch) -> Result<Vec<CreditSubscriber>> {
+    query_credit_subscribers(pool, epoch, None).await
+}
+
+/// Same as `load_credit_subscribers`, but only for `wallets`, e.g. the rows validation
+/// accepted.
+pub async fn load_credit_subscribers_for(
+    pool: &PgPool,
+    epoch: Epoch,
+    wallets: &[String],
+) -> Result<Vec<CreditSubscriber>> {
+    query_credit_subscribers(pool, epoch, Some(wallets)).await
+}
+
+async fn query_credit_subscribers(
+    pool: &PgPool,
+    epoch: Epoch,
+    wallets: Option<&[String]>,
+) -> Result<Vec<CreditSubscriber>> {
+    let rows = sqlx::query!(
+        "SELECT b.wallet_address, b.credits, s.expiration_ts
+         FROM credit_balances b
+         JOIN subscriber_storage s ON s.wallet_address = b.wallet_address AND s.product_id = $2
+         WHERE b.epoch = $1 AND ($3::varchar[] IS NULL OR b.wallet_address = ANY($3))",
+        i64::try_from(epoch)?,
+        DEFAULT_PRODUCT as i32,
+        wallets as Option<&[String]>
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut subscribers = rows
+        .into_iter()
+        .map(|row| {
+            let expiration = Expiration::from_unix(row.expiration_ts).with_context(|| {
+                format!("Invalid expiration for subscriber {}", row.wallet_address)
+            })?;
+            Ok(CreditSubscriber {
+                credits: u64::try_from(row.credits)?,
+                wallet_address: row.wallet_address,
+                epoch,
+                expiration,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    subscribers.sort_by(|a, b| a.wallet_address.cmp(&b.wallet_address));
+    Ok(subscribers)
+}
+
+/// Build a tree of credit leaves only for the open epoch.
+/// `subscribers[i]` is the value behind the i-th leaf, as for `build_tree_from_db`.
+/// The published root commits the same leaves (see `published`); use this for a standalone
+/// credit root.
+pub async fn build_credit_tree_from_db<H: MerkleHasher>(
+    pool: &PgPool,
+    options: TreeOptions,
+) -> Result<(String, MerkleTree<H>, Vec<CreditSubscriber>)> {
+    let epoch = current_credit_epoch(pool)
+        .await?
+        .context("No credit epoch is open")?;
+    let subscribers = load_credit_subscribers(pool, Epoch::try_from(epoch.epoch)?).await?;
+
+    let leaves = subscribers
+        .iter()
+        .map(|s| {
+            compute_credit_leaf_with::<H>(&s.wallet_address, s.epoch, s.credits, s.expiration)
+                .with_context(|| {
+                    format!("Invalid wallet address for subscriber {}", s.wallet_address)
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let plain: Vec<(String, Expiration)> = subscribers
+        .iter()
+        .map(|s| (s.wallet_address.clone(), s.expiration))
+        .collect();
+    check_unique_leaves(&leaves, &plain)?;
+
+    let tree = MerkleTree::<H>::from_leaves_with_options(&leaves, options);
+    Ok((hex::encode(tree.root()), tree, subscribers))
+}
+
+/// Get the credit proof of a user, with the epoch, credits and expiration it proves.
+/// Returns None if the user has no credits in `subscribers`.
+/// Scans `subscribers` and the leaves; a `ProofIndex<CreditSubscriber, H>` serves repeated
+/// lookups without scanning.
+pub fn get_credit_proof_for_user<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscribers: &[CreditSubscriber],
+    user_pubkey: &str,
+) -> Option<(Epoch, u64, Expiration, Proof<H>)> {
+    let user = normalize_address(user_pubkey);
+    let subscriber = subscribers
+        .iter()
+        .find(|s| normalize_address(&s.wallet_address) == user)?;
+
+    let values = credit_values(
+        &subscriber.wallet_address,
+        subscriber.epoch,
+        subscriber.credits,
+        subscriber.expiration,
+    )
+    .ok()?;
+    let proof = get_proof_for_value(tree, &credit_encoding(), &values)?;
+    Some((
+        subscriber.epoch,
+        subscriber.credits,
+        subscriber.expiration,
+        proof,
+    ))
+}
+
+/// Off-chain verification that the user was allotted `credits` in `epoch`, until
+/// `expiration_ts`. Says nothing about how many are left; see `consume_credits`.
+pub fn verify_credit_subscription<H: MerkleHasher>(
+    root_hex: &str,
+    proof: &Proof<H>,
+    user_pubkey: &str,
+    epoch: Epoch,
+    credits: u64,
+    expiration_ts: Expiration,
+) -> Result<bool> {
+    let values = credit_values(user_pubkey, epoch, credits, expiration_ts)?;
+    verify_value(root_hex, proof, &credit_encoding(), &values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::PgConnectOptions;
+    use std::str::FromStr;
+
+    const ALICE: &str = "0xa1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1";
+    const BOB: &str = "0xb0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0";
+
+    /// A fresh database named `name` with every migration applied, since credit epochs are
+    /// global state. Needs DATABASE_URL; None without it.
+    async fn scratch_database(name: &str) -> Option<PgPool> {
+        let database_url = std::env::var("DATABASE_URL").ok()?;
+        let admin = PgPool::connect(&database_url).await.unwrap();
+        for statement in [
+            format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", name),
+            format!("CREATE DATABASE {}", name),
+        ] {
+            sqlx::query(&statement).execute(&admin).await.unwrap();
+        }
+
+        let options = PgConnectOptions::from_str(&database_url)
+            .unwrap()
+            .database(name);
+        let pool = PgPool::connect_with(options).await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        Some(pool)
+    }
+
+    async fn drop_database(pool: PgPool, name: &str) {
+        pool.close().await;
+        let admin = PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
+            .await
+            .unwrap();
+        sqlx::query(&format!("DROP DATABASE {} WITH (FORCE)", name))
+            .execute(&admin)
+            .await
+            .unwrap();
+    }
+
+    async fn add_subscriber(pool: &PgPool, address: &str, expiration_ts: i64, allowance: i64) {
+        sqlx::query(
+            "INSERT INTO subscriber_storage (wallet_address, expiration_ts, credit_allowance)
+             VALUES ($1, $2, $3)",
+        )
+        .bind(address)
+        .bind(expiration_ts)
+        .bind(allowance)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn consume_deducts_and_rejects_overdrafts() {
+        let name = "credits_test_consume";
+        let Some(pool) = scratch_database(name).await else {
+            return;
+        };
+        add_subscriber(&pool, ALICE, 1_700_000_000, 10).await;
+        assert!(consume_credits(&pool, ALICE, 1).await.is_err());
+
+        start_credit_epoch(&pool).await.unwrap();
+        assert_eq!(
+            consume_credits(&pool, ALICE, 4).await.unwrap(),
+            Consumption::Consumed { remaining: 6 }
+        );
+        assert_eq!(
+            consume_credits(&pool, ALICE, 7).await.unwrap(),
+            Consumption::Overdraft { remaining: 6 }
+        );
+        // Any spelling of the wallet draws from the same balance
+        let upper_case = format!("0X{}", ALICE[2..].to_uppercase());
+        assert_eq!(
+            consume_credits(&pool, &upper_case, 6).await.unwrap(),
+            Consumption::Consumed { remaining: 0 }
+        );
+        assert!(consume_credits(&pool, BOB, 1).await.is_err());
+
+        drop_database(pool, name).await;
+    }
+
+    #[tokio::test]
+    async fn roll_over_carries_unused_credits_of_accepted_wallets() {
+        let name = "credits_test_roll_over";
+        let Some(pool) = scratch_database(name).await else {
+            return;
+        };
+        let expiration_ts = 1_700_000_000;
+        add_subscriber(&pool, ALICE, expiration_ts, 10).await;
+        // Of BOB's two spellings validation accepts the first in byte order; the other one
+        // and the rejected row are quarantined, so they get no balance
+        let bob_checksummed = format!("0x{}", BOB[2..].to_uppercase());
+        add_subscriber(&pool, BOB, expiration_ts, 10).await;
+        add_subscriber(&pool, &bob_checksummed, expiration_ts, 10).await;
+        add_subscriber(&pool, "0x1111111111111111111111111111111111111111", -1, 10).await;
+        // Allowance plus carry-over past the BIGINT range
+        let whale = "0xc0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0";
+        add_subscriber(&pool, whale, expiration_ts, i64::MAX - 1).await;
+
+        let first = start_credit_epoch(&pool).await.unwrap();
+        assert_eq!(first.epoch, 0);
+        let committed = load_credit_subscribers(&pool, 0).await.unwrap();
+        assert_eq!(committed.len(), 3);
+        consume_credits(&pool, ALICE, 4).await.unwrap();
+
+        let second = start_credit_epoch(&pool).await.unwrap();
+        assert_eq!(second.epoch, 1);
+        assert!(current_credit_epoch(&pool)
+            .await
+            .unwrap()
+            .unwrap()
+            .ended_at
+            .is_none());
+        let mut credits: Vec<_> = load_credit_subscribers(&pool, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.wallet_address, s.credits))
+            .collect();
+        credits.sort();
+        assert_eq!(
+            credits,
+            [
+                (bob_checksummed, 20),
+                (ALICE.to_string(), 16),
+                (whale.to_string(), i64::MAX as u64),
+            ]
+        );
+
+        // The new epoch has only just started
+        let hour = TimeDelta::try_hours(1).unwrap();
+        assert!(roll_over_if_ended(&pool, hour).await.unwrap().is_none());
+        assert!(roll_over_if_ended(&pool, TimeDelta::zero()).await.is_err());
+
+        drop_database(pool, name).await;
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use ethers::abi::Token;
+use ethers::types::{Address, U256};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::str::FromStr;
+
+use super::encoding::LeafEncoding;
+use super::hasher::MerkleHasher;
+use super::index::ProofIndex;
+use super::tree::{
+    check_unique_leaves, load_subscribers, subscriber_values, verify_value, BuiltTree, MerkleTree,
+    Proof, TreeOptions,
+};
+use super::validation::normalize_address;
+use crate::model::Expiration;
+
+// Bound leaves commit the deployment they are published to, ahead of the usual values:
+//
+//   leaf = keccak256(bytes.concat(keccak256(abi.encode(
+//              uint256 chainId, address contract, <values of the unbound leaf>...))))
+//
+// which the contract rebuilds with `block.chainid` and `address(this)`. A proof for a
+// testnet deployment then never verifies against mainnet, or against a second contract on
+// the same chain, even when both publish the same subscribers.
+// Any leaf shape can be bound: plain, tiered and product leaves all take the same prefix.
+
+/// Leading leaf types of a bound leaf: `uint256 chainId, address contract`
+pub const DEPLOYMENT_LEAF_PREFIX: [&str; 2] = ["uint256", "address"];
+
+/// The chain and contract a root is published to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Deployment {
+    pub chain_id: u64,
+    pub contract: Address,
+}
+
+impl Deployment {
+    pub fn new(chain_id: u64, contract_hex: &str) -> Result<Self> {
+        let contract = Address::from_str(contract_hex).context("Invalid contract address")?;
+        Ok(Deployment { chain_id, contract })
+    }
+
+    /// Leaf values `(chainId, contract)`, in `DEPLOYMENT_LEAF_PREFIX` order
+    pub fn values(&self) -> Vec<Token> {
+        vec![
+            Token::Uint(U256::from(self.chain_id)),
+            Token::Address(self.contract),
+        ]
+    }
+}
+
+/// Whether leaves commit the deployment they are published to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeafMode {
+    /// Leaves as the contract builds them today; proofs verify on any deployment
+    /// publishing the same root
+    #[default]
+    Unbound,
+    /// Leaves prefixed with the chain id and contract address
+    Bound(Deployment),
+}
+
+impl LeafMode {
+    pub fn deployment(&self) -> Option<&Deployment> {
+        match self {
+            LeafMode::Unbound => None,
+            LeafMode::Bound(deployment) => Some(deployment),
+        }
+    }
+
+    /// The encoding of leaves in this mode, given the encoding of the unbound leaf
+    pub fn encoding(&self, base: &LeafEncoding) -> LeafEncoding {
+        match self {
+            LeafMode::Unbound => base.clone(),
+            LeafMode::Bound(_) => {
+                let mut type_names: Vec<String> = DEPLOYMENT_LEAF_PREFIX
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect();
+                type_names.extend(base.type_names());
+                LeafEncoding::new(&type_names).expect("valid Solidity types")
+            }
+        }
+    }
+
+    /// The leaf values in this mode, given the values of the unbound leaf
+    pub fn values(&self, base: Vec<Token>) -> Vec<Token> {
+        match self {
+            LeafMode::Unbound => base,
+            LeafMode::Bound(deployment) => {
+                let mut values = deployment.values();
+                values.extend(base);
+                values
+            }
+        }
+    }
+
+    /// The leaf in this mode, for an unbound leaf of any shape
+    pub fn leaf_hash_with<H: MerkleHasher>(
+        &self,
+        base_encoding: &LeafEncoding,
+        base_values: Vec<Token>,
+    ) -> Result<[u8; 32]> {
+        self.encoding(base_encoding)
+            .leaf_hash_with::<H>(&self.values(base_values))
+    }
+}
+
+/// Subscriber leaf `(address, expiration)` in `mode`
+pub fn compute_leaf_in_mode<H: MerkleHasher>(
+    mode: &LeafMode,
+    address_hex: &str,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    let values = subscriber_values(address_hex, expiration)?;
+    mode.leaf_hash_with::<H>(&LeafEncoding::subscriber(), values)
+}
+
+/// Same as `build_tree_from_db_with_hasher`, with the leaves built in `mode`
+pub async fn build_tree_from_db_in_mode<H: MerkleHasher>(
+    pool: &PgPool,
+    options: TreeOptions,
+    mode: &LeafMode,
+) -> Result<BuiltTree<H>> {
+    let subscribers = load_subscribers(pool).await?;
+
+    let leaves = subscribers
+        .iter()
+        .map(|(address, exp)| {
+            compute_leaf_in_mode::<H>(mode, address, *exp)
+                .with_context(|| format!("Invalid wallet address for subscriber {}", address))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    check_unique_leaves(&leaves, &subscribers)?;
+
+    let tree = MerkleTree::<H>::from_leaves_with_options(&leaves, options);
+    Ok((hex::encode(tree.root()), tree, subscribers))
+}
+
+/// Index a tree built in `mode`, e.g. by `build_tree_from_db_in_mode`, for repeated lookups.
+pub fn index_tree_in_mode<H: MerkleHasher>(
+    built: BuiltTree<H>,
+    mode: &LeafMode,
+) -> Result<ProofIndex<(String, Expiration), H>> {
+    let leaves = built
+        .2
+        .iter()
+        .map(|(address, exp)| compute_leaf_in_mode::<H>(mode, address, *exp))
+        .collect::<Result<Vec<_>>>()?;
+    ProofIndex::with_leaves(built, &leaves)
+}
+
+/// Get the proof of a user's subscription in a tree built in `mode`.
+/// Returns None if the user is not in `subscribers`.
+/// Scans `subscribers` and the leaves; `index_tree_in_mode` serves repeated lookups.
+pub fn get_proof_for_user_in_mode<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscribers: &[(String, Expiration)],
+    user_pubkey: &str,
+    mode: &LeafMode,
+) -> Option<Proof<H>> {
+    let user = normalize_address(user_pubkey);
+    let (address, exp) = subscribers
+        .iter()
+        .find(|(address, _)| normalize_address(address) == user)?;
+    let leaf = compute_leaf_in_mode::<H>(mode, address, *exp).ok()?;
+    tree.get_proof(&leaf)
+}
+
+/// Off-chain verification of a leaf of any shape in `mode`. With `LeafMode::Bound`, the
+/// proof only verifies if the leaf was built for exactly that chain id and contract.
+pub fn verify_value_in_mode<H: MerkleHasher>(
+    root_hex: &str,
+    proof: &Proof<H>,
+    mode: &LeafMode,
+    base_encoding: &LeafEncoding,
+    base_values: Vec<Token>,
+) -> Result<bool> {
+    let values = mode.values(base_values);
+    verify_value(root_hex, proof, &mode.encoding(base_encoding), &values)
+}
+
+/// Off-chain verification of a subscription proof in `mode`, see `verify_value_in_mode`
+pub fn verify_subscription_in_mode<H: MerkleHasher>(
+    root_hex: &str,
+    proof: &Proof<H>,
+    user_pubkey: &str,
+    expiration_ts: Expiration,
+    mode: &LeafMode,
+) -> Result<bool> {
+    let values = subscriber_values(user_pubkey, expiration_ts)?;
+    verify_value_in_mode(root_hex, proof, mode, &LeafEncoding::subscriber(), values)
+}
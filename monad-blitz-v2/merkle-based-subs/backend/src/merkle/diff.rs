@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use super::hasher::MerkleHasher;
+use super::tree::BuiltTree;
+use super::validation::normalize_address;
+use crate::model::Expiration;
+
+/// The subscriber set behind one root, e.g. the last one published on-chain.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub root_hex: String,
+    pub subscribers: Vec<(String, Expiration)>,
+}
+
+impl Snapshot {
+    pub fn new(root_hex: impl Into<String>, subscribers: Vec<(String, Expiration)>) -> Self {
+        Snapshot {
+            root_hex: root_hex.into(),
+            subscribers,
+        }
+    }
+
+    /// Snapshot of a built tree
+    pub fn of<H: MerkleHasher>((root_hex, _, subscribers): &BuiltTree<H>) -> Self {
+        Snapshot::new(root_hex.clone(), subscribers.clone())
+    }
+}
+
+/// A subscriber present in both snapshots with a different expiration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Renewal {
+    pub wallet_address: String,
+    pub old_expiration: Expiration,
+    pub new_expiration: Expiration,
+}
+
+/// What changes between two snapshots. Subscribers are matched by normalized address,
+/// so a change of address casing alone is not reported.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotDiff {
+    pub old_root: String,
+    pub new_root: String,
+    /// Only in the new snapshot
+    pub added: Vec<(String, Expiration)>,
+    /// Only in the old snapshot
+    pub removed: Vec<(String, Expiration)>,
+    pub renewed: Vec<Renewal>,
+    pub unchanged: Vec<(String, Expiration)>,
+}
+
+impl SnapshotDiff {
+    /// True if no subscriber was added, removed or renewed
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.renewed.is_empty()
+    }
+}
+
+/// Diff two snapshots. Lists keep the order of the snapshot they come from:
+/// the new one for added, renewed and unchanged, the old one for removed.
+pub fn diff_snapshots(old: &Snapshot, new: &Snapshot) -> SnapshotDiff {
+    let old_by_address: HashMap<String, Expiration> = old
+        .subscribers
+        .iter()
+        .map(|(address, exp)| (normalize_address(address), *exp))
+        .collect();
+    let new_by_address: HashMap<String, Expiration> = new
+        .subscribers
+        .iter()
+        .map(|(address, exp)| (normalize_address(address), *exp))
+        .collect();
+
+    let mut diff = SnapshotDiff {
+        old_root: old.root_hex.clone(),
+        new_root: new.root_hex.clone(),
+        ..SnapshotDiff::default()
+    };
+
+    for (address, new_exp) in &new.subscribers {
+        match old_by_address.get(&normalize_address(address)) {
+            None => diff.added.push((address.clone(), *new_exp)),
+            Some(old_exp) if old_exp != new_exp => diff.renewed.push(Renewal {
+                wallet_address: address.clone(),
+                old_expiration: *old_exp,
+                new_expiration: *new_exp,
+            }),
+            Some(_) => diff.unchanged.push((address.clone(), *new_exp)),
+        }
+    }
+
+    diff.removed = old
+        .subscribers
+        .iter()
+        .filter(|(address, _)| !new_by_address.contains_key(&normalize_address(address)))
+        .cloned()
+        .collect();
+
+    diff
+}
+
+/// Diff two built trees, e.g. the published one and a fresh `build_tree_from_db`.
+pub fn diff_trees<H: MerkleHasher>(old: &BuiltTree<H>, new: &BuiltTree<H>) -> SnapshotDiff {
+    diff_snapshots(&Snapshot::of(old), &Snapshot::of(new))
+}
+
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Root: 0x{} -> 0x{}", self.old_root, self.new_root)?;
+        writeln!(
+            f,
+            "{} added, {} removed, {} renewed, {} unchanged",
+            self.added.len(),
+            self.removed.len(),
+            self.renewed.len(),
+            self.unchanged.len()
+        )?;
+        for (address, exp) in &self.added {
+            writeln!(f, "+ {} (expiration {})", address, exp)?;
+        }
+        for (address, exp) in &self.removed {
+            writeln!(f, "- {} (expiration {})", address, exp)?;
+        }
+        for renewal in &self.renewed {
+            writeln!(
+                f,
+                "~ {} ({} -> {})",
+                renewal.wallet_address, renewal.old_expiration, renewal.new_expiration
+            )?;
+        }
+        Ok(())
+    }
+}
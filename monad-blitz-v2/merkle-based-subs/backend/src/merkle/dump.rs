@@ -0,0 +1,337 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use ethers::abi::Token;
+
+use super::encoding::{LeafEncoding, SUBSCRIBER_LEAF_ENCODING};
+use super::tree::{
+    subscriber_values, BuiltTree, OzMerkleTree, TreeLayout, TreeOptions, EMPTY_ROOT,
+};
+use crate::model::Expiration;
+
+/// Format tag written by `StandardMerkleTree.dump()`
+pub const STANDARD_V1: &str = "standard-v1";
+
+/// JSON dump in the `standard-v1` format of `@openzeppelin/merkle-tree`.
+/// Can be passed to `StandardMerkleTree.load()` on the JS side and vice versa.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StandardTreeDump {
+    pub format: String,
+    pub leaf_encoding: Vec<String>,
+    /// Hex-encoded node array, root first
+    pub tree: Vec<String>,
+    pub values: Vec<DumpValue>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpValue {
+    pub value: Vec<Value>,
+    pub tree_index: usize,
+}
+
+/// Dump a standard-layout tree together with the leaf values it was built from.
+/// `values[i]` must be the value behind the i-th leaf passed to the tree builder.
+pub fn dump_values(
+    tree: &OzMerkleTree,
+    encoding: &LeafEncoding,
+    values: &[Vec<Token>],
+) -> Result<StandardTreeDump> {
+    let nodes = tree
+        .standard_tree()
+        .context("Only trees with TreeLayout::Standard can be dumped as standard-v1")?;
+
+    if values.len() != tree.leaf_count() {
+        return Err(anyhow::anyhow!(
+            "Tree has {} leaves but {} values were given",
+            tree.leaf_count(),
+            values.len()
+        ));
+    }
+
+    let mut dumped = Vec::with_capacity(values.len());
+    for (value_index, value) in values.iter().enumerate() {
+        let tree_index = tree.tree_index(value_index).unwrap();
+        if nodes[tree_index] != encoding.leaf_hash(value)? {
+            return Err(anyhow::anyhow!(
+                "Value {} does not match leaf at tree index {}",
+                value_index,
+                tree_index
+            ));
+        }
+
+        dumped.push(DumpValue {
+            value: encoding.values_to_json(value)?,
+            tree_index,
+        });
+    }
+
+    // An empty tree has no nodes: its configured empty root stands in for the node array,
+    // so loading the dump gives back the same root
+    let root = [tree.root()];
+    let nodes = if nodes.is_empty() { &root[..] } else { nodes };
+
+    Ok(StandardTreeDump {
+        format: STANDARD_V1.to_string(),
+        leaf_encoding: encoding.type_names(),
+        tree: nodes
+            .iter()
+            .map(|h| format!("0x{}", hex::encode(h)))
+            .collect(),
+        values: dumped,
+    })
+}
+
+/// Load a `standard-v1` dump with any leaf encoding, recomputing every leaf and internal node.
+/// Fails if any stored hash (including the root) disagrees with the recomputed tree.
+/// A dump without values loads as an empty tree whose empty root is the dump's only node
+/// (`EMPTY_ROOT` if the node array is empty too).
+pub fn load_values(
+    dump: &StandardTreeDump,
+) -> Result<(LeafEncoding, Vec<Vec<Token>>, OzMerkleTree)> {
+    if dump.format != STANDARD_V1 {
+        return Err(anyhow::anyhow!("Unknown dump format '{}'", dump.format));
+    }
+    let encoding = LeafEncoding::new(&dump.leaf_encoding)?;
+
+    if dump.values.is_empty() {
+        let empty_root = match dump.tree.as_slice() {
+            [] => EMPTY_ROOT,
+            [root] => decode_node(root)?,
+            nodes => {
+                return Err(anyhow::anyhow!(
+                    "Dump without values has {} nodes, expected only its empty root",
+                    nodes.len()
+                ))
+            }
+        };
+        let options = TreeOptions {
+            layout: TreeLayout::Standard,
+            empty_root,
+            ..TreeOptions::default()
+        };
+        return Ok((
+            encoding,
+            Vec::new(),
+            OzMerkleTree::from_leaves_with_options(&[], options),
+        ));
+    }
+
+    let mut values = Vec::with_capacity(dump.values.len());
+    let mut leaves = Vec::with_capacity(dump.values.len());
+    let mut tree_indices = Vec::with_capacity(dump.values.len());
+
+    for entry in &dump.values {
+        let value = encoding.values_from_json(&entry.value)?;
+        leaves.push(encoding.leaf_hash(&value)?);
+        tree_indices.push(entry.tree_index);
+        values.push(value);
+    }
+
+    let tree = OzMerkleTree::from_standard_parts(&leaves, &tree_indices)?;
+
+    let nodes = tree.standard_tree().unwrap();
+    if dump.tree.len() != nodes.len() {
+        return Err(anyhow::anyhow!(
+            "Dump has {} nodes, expected {}",
+            dump.tree.len(),
+            nodes.len()
+        ));
+    }
+    for (i, (stored, computed)) in dump.tree.iter().zip(nodes).enumerate() {
+        if decode_node(stored)? != *computed {
+            return Err(anyhow::anyhow!(
+                "Node {} does not match the recomputed tree",
+                i
+            ));
+        }
+    }
+
+    Ok((encoding, values, tree))
+}
+
+/// Dump a standard-layout tree together with the subscribers it was built from.
+/// `subscribers[i]` must be the value behind the i-th leaf passed to the tree builder,
+/// which is the case for the list returned by `build_tree_from_db_with_layout`.
+pub fn dump_tree(
+    tree: &OzMerkleTree,
+    subscribers: &[(String, Expiration)],
+) -> Result<StandardTreeDump> {
+    let values = subscribers
+        .iter()
+        .map(|(address, expiration)| subscriber_values(address, *expiration))
+        .collect::<Result<Vec<_>>>()?;
+
+    dump_values(tree, &LeafEncoding::subscriber(), &values)
+}
+
+/// Load a `standard-v1` dump of subscriber leaves.
+/// Returns the same `(root_hex, tree, subscribers)` triple as `build_tree_from_db`.
+pub fn load_tree(dump: &StandardTreeDump) -> Result<BuiltTree> {
+    let (encoding, values, tree) = load_values(dump)?;
+    if encoding != LeafEncoding::subscriber() {
+        return Err(anyhow::anyhow!(
+            "Unsupported leaf encoding {:?}, expected {:?}",
+            dump.leaf_encoding,
+            SUBSCRIBER_LEAF_ENCODING
+        ));
+    }
+
+    let mut subscribers = Vec::with_capacity(values.len());
+    for value in values {
+        let (address, expiration) = match value.as_slice() {
+            [Token::Address(address), Token::Uint(expiration)] => (*address, *expiration),
+            _ => unreachable!("checked against the subscriber encoding"),
+        };
+        let expiration = Expiration::try_from(expiration)?;
+        subscribers.push((format!("0x{}", hex::encode(address.as_bytes())), expiration));
+    }
+
+    Ok((hex::encode(tree.root()), tree, subscribers))
+}
+
+fn decode_node(node_hex: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(node_hex.trim_start_matches("0x")).context("Invalid node hex")?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Node must be 32 bytes"))
+}
+
+pub fn write_dump(path: impl AsRef<Path>, dump: &StandardTreeDump) -> Result<()> {
+    let json = serde_json::to_string_pretty(dump)?;
+    fs::write(path, json).context("Failed to write tree dump")?;
+    Ok(())
+}
+
+pub fn read_dump(path: impl AsRef<Path>) -> Result<StandardTreeDump> {
+    let json = fs::read_to_string(path).context("Failed to read tree dump")?;
+    serde_json::from_str(&json).context("Failed to parse tree dump JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::tree::{TreeLayout, TreeOptions};
+
+    /// `StandardMerkleTree.of(values, ["address", "uint256"]).dump()` for the values of the
+    /// `@openzeppelin/merkle-tree` README
+    const OZ_README_DUMP: &str = r#"{
+        "format": "standard-v1",
+        "leafEncoding": ["address", "uint256"],
+        "tree": [
+            "0xd4dee0beab2d53f2cc83e567171bd2820e49898130a22622b10ead383e90bd77",
+            "0xeb02c421cfa48976e66dfb29120745909ea3a0f843456c263cf8f1253483e283",
+            "0xb92c48e9d7abe27fd8dfd6b5dfdbfb1c9a463f80c712b66f3a5180a090cccafc"
+        ],
+        "values": [
+            {
+                "value": ["0x1111111111111111111111111111111111111111", "5000000000000000000"],
+                "treeIndex": 1
+            },
+            {
+                "value": ["0x2222222222222222222222222222222222222222", "2500000000000000000"],
+                "treeIndex": 2
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn loads_and_redumps_an_openzeppelin_dump() {
+        let dump: StandardTreeDump = serde_json::from_str(OZ_README_DUMP).unwrap();
+        let (encoding, values, tree) = load_values(&dump).unwrap();
+        assert_eq!(
+            hex::encode(tree.root()),
+            "d4dee0beab2d53f2cc83e567171bd2820e49898130a22622b10ead383e90bd77"
+        );
+
+        let redumped = dump_values(&tree, &encoding, &values).unwrap();
+        assert_eq!(
+            serde_json::to_value(&redumped).unwrap(),
+            serde_json::to_value(&dump).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_a_tampered_dump() {
+        let mut dump: StandardTreeDump = serde_json::from_str(OZ_README_DUMP).unwrap();
+        dump.values[0].value[1] = Value::String("5000000000000000001".to_string());
+        assert!(load_values(&dump).is_err());
+
+        let mut dump: StandardTreeDump = serde_json::from_str(OZ_README_DUMP).unwrap();
+        dump.values[0].tree_index = 2;
+        assert!(load_values(&dump).is_err());
+    }
+
+    #[test]
+    fn subscriber_trees_round_trip() {
+        let subscribers: Vec<(String, Expiration)> = (1..=5u8)
+            .map(|i| {
+                let address = format!("0x{}", hex::encode([i; 20]));
+                (
+                    address,
+                    Expiration::new(1_700_000_000 + u64::from(i)).unwrap(),
+                )
+            })
+            .collect();
+        let leaves = subscribers
+            .iter()
+            .map(|(address, exp)| crate::merkle::tree::compute_leaf(address, *exp).unwrap())
+            .collect::<Vec<_>>();
+
+        for sort_leaves in [true, false] {
+            let options = TreeOptions {
+                layout: TreeLayout::Standard,
+                sort_leaves,
+                ..TreeOptions::default()
+            };
+            let tree = OzMerkleTree::from_leaves_with_options(&leaves, options);
+            let dump = dump_tree(&tree, &subscribers).unwrap();
+
+            let json = serde_json::to_string(&dump).unwrap();
+            let (root_hex, loaded, loaded_subscribers) =
+                load_tree(&serde_json::from_str(&json).unwrap()).unwrap();
+            assert_eq!(root_hex, hex::encode(tree.root()));
+            assert_eq!(loaded.sorts_leaves(), sort_leaves);
+            assert_eq!(loaded_subscribers, subscribers);
+        }
+    }
+
+    #[test]
+    fn empty_trees_round_trip_with_their_empty_root() {
+        for empty_root in [EMPTY_ROOT, [0xee; 32]] {
+            let options = TreeOptions {
+                layout: TreeLayout::Standard,
+                empty_root,
+                ..TreeOptions::default()
+            };
+            let tree = OzMerkleTree::from_leaves_with_options(&[], options);
+            let dump = dump_tree(&tree, &[]).unwrap();
+            assert_eq!(dump.tree, vec![format!("0x{}", hex::encode(empty_root))]);
+
+            let json = serde_json::to_string(&dump).unwrap();
+            let (root_hex, loaded, subscribers) =
+                load_tree(&serde_json::from_str(&json).unwrap()).unwrap();
+            assert_eq!(root_hex, hex::encode(empty_root));
+            assert!(loaded.is_empty());
+            assert!(subscribers.is_empty());
+        }
+
+        // No nodes at all: the default empty root
+        let mut dump = dump_tree(
+            &OzMerkleTree::from_leaves_with_layout(&[], TreeLayout::Standard),
+            &[],
+        )
+        .unwrap();
+        dump.tree.clear();
+        assert_eq!(load_tree(&dump).unwrap().0, hex::encode(EMPTY_ROOT));
+
+        // Nodes without values
+        let mut dump: StandardTreeDump = serde_json::from_str(OZ_README_DUMP).unwrap();
+        dump.values.clear();
+        assert!(load_values(&dump).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+use anyhow::{Context, Result};
+use ethers::abi::{self, param_type::Reader, ParamType, Token};
+use ethers::types::{Address, Sign, I256, U256};
+use serde_json::Value;
+use std::str::FromStr;
+
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+
+/// Leaf encoding of subscriber leaves: `abi.encode(address, uint256)`
+pub const SUBSCRIBER_LEAF_ENCODING: [&str; 2] = ["address", "uint256"];
+
+/// Solidity type of the `LeafKind` that leads every extended leaf shape
+pub const LEAF_KIND_TYPE: &str = "uint8";
+
+/// Discriminator encoded as the first value of every extended leaf shape (product, entitled,
+/// credit), so two shapes never share an encoding even when their other values have the same
+/// types: a plan id can never read as a bitmap, nor a product id as a credit epoch.
+/// Plain and tiered leaves have no kind, as the deployed contract and the tiered overload
+/// encode them; their two- and three-value encodings are shorter than any kind-led shape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum LeafKind {
+    Product = 1,
+    Entitled = 2,
+    Credit = 3,
+}
+
+impl LeafKind {
+    /// The `uint8` leaf value
+    pub fn token(self) -> Token {
+        Token::Uint(U256::from(self as u8))
+    }
+}
+
+/// The Solidity types a leaf is `abi.encode`d from, like StandardMerkleTree's `leafEncoding`.
+/// Supports address, uintN, intN, bytesN, bool, string and bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafEncoding {
+    types: Vec<ParamType>,
+}
+
+impl LeafEncoding {
+    /// Parse Solidity type names, e.g. `["address", "uint64", "bool"]`
+    pub fn new<S: AsRef<str>>(type_names: &[S]) -> Result<Self> {
+        if type_names.is_empty() {
+            return Err(anyhow::anyhow!("Leaf encoding needs at least one type"));
+        }
+
+        let types = type_names
+            .iter()
+            .map(|name| {
+                let name = name.as_ref();
+                let ty = Reader::read(name)
+                    .map_err(|e| anyhow::anyhow!("Invalid Solidity type '{}': {}", name, e))?;
+                match ty {
+                    ParamType::Address
+                    | ParamType::Uint(_)
+                    | ParamType::Int(_)
+                    | ParamType::FixedBytes(_)
+                    | ParamType::Bool
+                    | ParamType::String
+                    | ParamType::Bytes => Ok(ty),
+                    _ => Err(anyhow::anyhow!("Unsupported leaf type '{}'", name)),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(LeafEncoding { types })
+    }
+
+    /// The `(address, uint256)` encoding used for subscriber leaves
+    pub fn subscriber() -> Self {
+        LeafEncoding {
+            types: vec![ParamType::Address, ParamType::Uint(256)],
+        }
+    }
+
+    pub fn types(&self) -> &[ParamType] {
+        &self.types
+    }
+
+    /// Canonical type names, as written to `leafEncoding` in a dump
+    pub fn type_names(&self) -> Vec<String> {
+        self.types.iter().map(|t| t.to_string()).collect()
+    }
+
+    /// Check that `values` match the encoding, including the bit width of uintN/intN
+    /// and the length of bytesN.
+    pub fn check(&self, values: &[Token]) -> Result<()> {
+        if values.len() != self.types.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} leaf values, got {}",
+                self.types.len(),
+                values.len()
+            ));
+        }
+
+        for (ty, value) in self.types.iter().zip(values) {
+            if !value.type_check(ty) {
+                return Err(anyhow::anyhow!("Value {:?} is not a {}", value, ty));
+            }
+            match (ty, value) {
+                (ParamType::Uint(bits), Token::Uint(v)) if *bits < 256 && v.bits() > *bits => {
+                    return Err(anyhow::anyhow!("Value {} does not fit in {}", v, ty));
+                }
+                (ParamType::Int(bits), Token::Int(v)) if *bits < 256 => {
+                    let v = I256::from_raw(*v);
+                    let limit = I256::from_raw(U256::one() << (*bits - 1));
+                    if v >= limit || v < -limit {
+                        return Err(anyhow::anyhow!("Value {} does not fit in {}", v, ty));
+                    }
+                }
+                (ParamType::FixedBytes(len), Token::FixedBytes(b)) if b.len() != *len => {
+                    return Err(anyhow::anyhow!(
+                        "Value 0x{} is not a {}",
+                        hex::encode(b),
+                        ty
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `abi.encode(values...)`
+    pub fn encode(&self, values: &[Token]) -> Result<Vec<u8>> {
+        self.check(values)?;
+        Ok(abi::encode(values))
+    }
+
+    /// OZ StandardMerkleTree leaf: `keccak256(bytes.concat(keccak256(abi.encode(values...))))`
+    pub fn leaf_hash(&self, values: &[Token]) -> Result<[u8; 32]> {
+        self.leaf_hash_with::<Keccak256Hasher>(values)
+    }
+
+    /// The leaf with any hasher: `H::hash_leaf(abi.encode(values...))`,
+    /// the same double hash unless `H` separates leaf and node domains
+    pub fn leaf_hash_with<H: MerkleHasher>(&self, values: &[Token]) -> Result<[u8; 32]> {
+        let encoded = self.encode(values)?;
+        Ok(H::hash_leaf(&encoded))
+    }
+
+    /// Parse leaf values from JSON as they appear in a `standard-v1` dump.
+    /// Integers may be decimal strings, 0x-prefixed hex strings or JSON numbers.
+    /// Hex strings take one `0x` or `0X` prefix, optional for bytes.
+    pub fn values_from_json(&self, values: &[Value]) -> Result<Vec<Token>> {
+        if values.len() != self.types.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} leaf values, got {}",
+                self.types.len(),
+                values.len()
+            ));
+        }
+
+        let tokens = self
+            .types
+            .iter()
+            .zip(values)
+            .map(|(ty, value)| {
+                token_from_json(ty, value)
+                    .with_context(|| format!("Value {} is not a valid {}", value, ty))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.check(&tokens)?;
+        Ok(tokens)
+    }
+
+    /// Render leaf values as JSON, the inverse of `values_from_json`.
+    /// Integers become decimal strings, as `StandardMerkleTree.dump()` writes bigints.
+    pub fn values_to_json(&self, values: &[Token]) -> Result<Vec<Value>> {
+        self.check(values)?;
+        Ok(values.iter().map(token_to_json).collect())
+    }
+}
+
+fn token_from_json(ty: &ParamType, value: &Value) -> Result<Token> {
+    let token = match (ty, value) {
+        (ParamType::Address, Value::String(s)) => Token::Address(Address::from_str(s)?),
+        (ParamType::Uint(_), _) => Token::Uint(parse_u256(value)?),
+        (ParamType::Int(_), _) => Token::Int(parse_i256(value)?.into_raw()),
+        (ParamType::FixedBytes(_), Value::String(s)) => Token::FixedBytes(parse_hex(s)?),
+        (ParamType::Bytes, Value::String(s)) => Token::Bytes(parse_hex(s)?),
+        (ParamType::Bool, Value::Bool(b)) => Token::Bool(*b),
+        (ParamType::String, Value::String(s)) => Token::String(s.clone()),
+        _ => return Err(anyhow::anyhow!("Wrong JSON type")),
+    };
+    Ok(token)
+}
+
+fn token_to_json(token: &Token) -> Value {
+    match token {
+        Token::Address(a) => Value::String(format!("0x{}", hex::encode(a.as_bytes()))),
+        Token::Uint(v) => Value::String(v.to_string()),
+        Token::Int(v) => Value::String(I256::from_raw(*v).to_string()),
+        Token::FixedBytes(b) | Token::Bytes(b) => Value::String(format!("0x{}", hex::encode(b))),
+        Token::Bool(b) => Value::Bool(*b),
+        Token::String(s) => Value::String(s.clone()),
+        // Rejected by `LeafEncoding::new`, so never part of a checked leaf
+        _ => Value::Null,
+    }
+}
+
+fn parse_u256(value: &Value) -> Result<U256> {
+    match value {
+        Value::Number(n) => n
+            .as_u64()
+            .map(U256::from)
+            .context("Not an unsigned integer"),
+        Value::String(s) => match strip_hex_prefix(s) {
+            // from_str_radix would strip a second prefix itself
+            Some(hex_digits)
+                if hex_digits.is_empty() || !hex_digits.bytes().all(|b| b.is_ascii_hexdigit()) =>
+            {
+                Err(anyhow::anyhow!("Invalid hex digits"))
+            }
+            Some(hex_digits) => Ok(U256::from_str_radix(hex_digits, 16)?),
+            None => Ok(U256::from_dec_str(s)?),
+        },
+        _ => Err(anyhow::anyhow!("Wrong JSON type")),
+    }
+}
+
+fn parse_i256(value: &Value) -> Result<I256> {
+    match value {
+        Value::Number(n) => n.as_i64().map(I256::from).context("Not an integer"),
+        Value::String(s) => {
+            let (sign, digits) = match s.strip_prefix('-') {
+                Some(digits) => (Sign::Negative, digits),
+                None => (Sign::Positive, s.as_str()),
+            };
+            let abs = parse_u256(&Value::String(digits.to_string()))?;
+            if abs.is_zero() {
+                // "-0" would otherwise be reported as an overflow
+                return Ok(I256::zero());
+            }
+            I256::checked_from_sign_and_abs(sign, abs).context("Out of int256 range")
+        }
+        _ => Err(anyhow::anyhow!("Wrong JSON type")),
+    }
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    hex::decode(strip_hex_prefix(s).unwrap_or(s)).context("Invalid hex")
+}
+
+/// Strip a single `0x` or `0X` prefix, the one prefix rule for integers and bytes
+fn strip_hex_prefix(s: &str) -> Option<&str> {
+    match s.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("0x") => Some(&s[2..]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encoding(types: &[&str]) -> LeafEncoding {
+        LeafEncoding::new(types).unwrap()
+    }
+
+    #[test]
+    fn check_enforces_bit_widths_and_lengths() {
+        let uint8 = encoding(&["uint8"]);
+        assert!(uint8.check(&[Token::Uint(U256::from(255))]).is_ok());
+        assert!(uint8.check(&[Token::Uint(U256::from(256))]).is_err());
+        assert!(encoding(&["uint256"])
+            .check(&[Token::Uint(U256::MAX)])
+            .is_ok());
+
+        let int8 = encoding(&["int8"]);
+        for (v, ok) in [(127, true), (128, false), (-128, true), (-129, false)] {
+            let token = Token::Int(I256::from(v).into_raw());
+            assert_eq!(int8.check(&[token]).is_ok(), ok, "{}", v);
+        }
+
+        let bytes4 = encoding(&["bytes4"]);
+        assert!(bytes4.check(&[Token::FixedBytes(vec![0; 4])]).is_ok());
+        assert!(bytes4.check(&[Token::FixedBytes(vec![0; 3])]).is_err());
+
+        // Wrong type and wrong count
+        assert!(uint8.check(&[Token::Bool(true)]).is_err());
+        assert!(uint8.check(&[]).is_err());
+    }
+
+    #[test]
+    fn values_from_json_accepts_every_integer_form() {
+        let encoding = encoding(&["address", "uint256", "bytes2", "bool", "string"]);
+        let address = "0xabcdefabcdefabcdefabcdefabcdefabcdefabcd";
+        let expected = encoding
+            .values_from_json(&[
+                json!(address),
+                json!("255"),
+                json!("0xbeef"),
+                json!(true),
+                json!("plan"),
+            ])
+            .unwrap();
+        for amount in [json!(255), json!("0xff"), json!("0XFF")] {
+            let values = [
+                json!(address),
+                amount,
+                json!("0XBEEF"),
+                json!(true),
+                json!("plan"),
+            ];
+            assert_eq!(encoding.values_from_json(&values).unwrap(), expected);
+        }
+
+        for (index, bad) in [
+            (1, json!("0x")),
+            (1, json!("0x0xff")),
+            (1, json!(-1)),
+            (2, json!("0x0xbeef")),
+            (2, json!("0xbeefbeef")),
+            (3, json!("true")),
+        ] {
+            let mut values = vec![
+                json!(address),
+                json!("255"),
+                json!("0xbeef"),
+                json!(true),
+                json!("plan"),
+            ];
+            values[index] = bad.clone();
+            assert!(encoding.values_from_json(&values).is_err(), "{}", bad);
+        }
+        assert!(encoding.values_from_json(&[json!(address)]).is_err());
+    }
+
+    #[test]
+    fn parse_i256_handles_signs_and_bounds() {
+        assert_eq!(parse_i256(&json!("-0")).unwrap(), I256::zero());
+        assert_eq!(parse_i256(&json!("-5")).unwrap(), I256::from(-5));
+        assert_eq!(parse_i256(&json!(-5)).unwrap(), I256::from(-5));
+        assert_eq!(parse_i256(&json!("-0x10")).unwrap(), I256::from(-16));
+        assert_eq!(
+            parse_i256(&json!(I256::MIN.to_string())).unwrap(),
+            I256::MIN
+        );
+        assert_eq!(
+            parse_i256(&json!(I256::MAX.to_string())).unwrap(),
+            I256::MAX
+        );
+
+        let too_large = (U256::one() << 255).to_string();
+        assert!(parse_i256(&json!(too_large)).is_err());
+        assert!(parse_i256(&json!("--1")).is_err());
+    }
+
+    #[test]
+    fn values_to_json_round_trips() {
+        let encoding = encoding(&["address", "uint64", "int16", "bytes", "bool", "string"]);
+        let values = vec![
+            json!("0xabcdefabcdefabcdefabcdefabcdefabcdefabcd"),
+            json!("18446744073709551615"),
+            json!("-32768"),
+            json!("0x00ff"),
+            json!(false),
+            json!("gold"),
+        ];
+        let tokens = encoding.values_from_json(&values).unwrap();
+        assert_eq!(encoding.values_to_json(&tokens).unwrap(), values);
+
+        // Out-of-range values are rejected on the way out too
+        let mut tokens = tokens;
+        tokens[1] = Token::Uint(U256::from(u64::MAX) + 1);
+        assert!(encoding.values_to_json(&tokens).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use ethers::abi::Token;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+
+use super::encoding::{LeafEncoding, LeafKind};
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::index::IndexedValue;
+use super::tree::{
+    check_unique_leaves, get_proof_for_value, subscriber_values, verify_value, MerkleTree, Proof,
+    TreeOptions,
+};
+use super::validation::normalize_address;
+use crate::model::{Entitlement, Entitlements, Expiration, DEFAULT_PRODUCT};
+
+// Entitled leaves commit the subscriber's entitlement bitmap next to the expiration:
+//
+//   leaf = keccak256(bytes.concat(keccak256(abi.encode(
+//              uint8(2), address, uint256 entitlements, uint256 expiration))))
+//
+// so the contract can gate each feature with `entitlements & required == required`.
+// The leading `LeafKind::Entitled` keeps entitled leaves apart from every other shape in the
+// published root (see `published`), so a plan id never reads as a bitmap.
+
+/// Leaf encoding of entitled subscriber leaves: `abi.encode(uint8, address, uint256, uint256)`
+pub const ENTITLED_LEAF_ENCODING: [&str; 4] = ["uint8", "address", "uint256", "uint256"];
+
+/// A subscriber with the entitlements committed in its leaf
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntitledSubscriber {
+    pub wallet_address: String,
+    pub entitlements: Entitlements,
+    pub expiration: Expiration,
+}
+
+impl IndexedValue for EntitledSubscriber {
+    type Key = String;
+
+    fn key(&self) -> String {
+        normalize_address(&self.wallet_address)
+    }
+
+    fn leaf<H: MerkleHasher>(&self) -> Result<[u8; 32]> {
+        compute_entitled_leaf_with::<H>(&self.wallet_address, self.entitlements, self.expiration)
+    }
+}
+
+/// A subscriber's proof with the bitmap it proves, as served to frontends.
+/// Everything needed to call `verifySubscription(proof, entitlements, expiration)`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntitledProofEntry {
+    /// Hex root, no `0x`, as stored in `merkle_state`
+    pub root: String,
+    pub address: String,
+    pub entitlements: Entitlements,
+    pub expiration: Expiration,
+    /// 0x-prefixed leaf hash
+    pub leaf: String,
+    /// 0x-prefixed siblings, leaf first (Solidity `bytes32[]`)
+    pub proof: Vec<String>,
+}
+
+/// The `(uint8, address, uint256, uint256)` encoding of entitled leaves
+pub fn entitled_encoding() -> LeafEncoding {
+    LeafEncoding::new(&ENTITLED_LEAF_ENCODING).expect("valid Solidity types")
+}
+
+/// Leaf values `(LeafKind::Entitled, address, entitlements, expiration)`,
+/// in `entitled_encoding()` order
+pub fn entitled_values(
+    address_hex: &str,
+    entitlements: Entitlements,
+    expiration: Expiration,
+) -> Result<Vec<Token>> {
+    let mut values = subscriber_values(address_hex, expiration)?;
+    values.insert(0, LeafKind::Entitled.token());
+    values.insert(2, Token::Uint(entitlements.to_u256()));
+    Ok(values)
+}
+
+/// Entitled leaf matching the contract's `keccak256(bytes.concat(keccak256(abi.encode(
+/// uint8(2), address, entitlements, expiration))))`
+pub fn compute_entitled_leaf(
+    address_hex: &str,
+    entitlements: Entitlements,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    compute_entitled_leaf_with::<Keccak256Hasher>(address_hex, entitlements, expiration)
+}
+
+/// `compute_entitled_leaf` with any hasher
+pub fn compute_entitled_leaf_with<H: MerkleHasher>(
+    address_hex: &str,
+    entitlements: Entitlements,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    let values = entitled_values(address_hex, entitlements, expiration)?;
+    entitled_encoding().leaf_hash_with::<H>(&values)
+}
+
+// ───────────────────────────────────────────────────
+// Entitlements in the database
+// ───────────────────────────────────────────────────
+
+/// Position of entitlement k for Postgres `set_bit`, which numbers bits from the right
+/// within each byte, bytes first to last. The column is big-endian.
+fn column_bit(entitlement: Entitlement) -> i64 {
+    let entitlement = i64::from(entitlement);
+    (31 - entitlement / 8) * 8 + entitlement % 8
+}
+
+/// Set one bit of a subscriber's bitmap in a single statement, so concurrent grants and
+/// revokes of different entitlements never overwrite each other.
+///
+/// Of several case variants of the wallet, only the row validation accepts is updated: the
+/// first with a valid expiration in byte order, as `validation::validate_rows` visits them.
+/// The others stay quarantined with their own bitmaps.
+async fn set_entitlement_bit(
+    pool: &PgPool,
+    wallet_address: &str,
+    entitlement: Entitlement,
+    granted: bool,
+) -> Result<Entitlements> {
+    let bitmap = sqlx::query_scalar!(
+        "UPDATE subscriber_storage
+         SET entitlements = set_bit(entitlements, $3, $4), last_updated_at = $5
+         WHERE product_id = $2 AND wallet_address = (
+             SELECT wallet_address FROM subscriber_storage
+             WHERE lower(wallet_address) = $1 AND product_id = $2 AND expiration_ts >= 0
+             ORDER BY wallet_address COLLATE \"C\" LIMIT 1)
+         RETURNING entitlements",
+        normalize_address(wallet_address),
+        DEFAULT_PRODUCT as i32,
+        column_bit(entitlement),
+        i32::from(granted),
+        Utc::now().naive_utc()
+    )
+    .fetch_optional(pool)
+    .await
+    .with_context(|| {
+        format!(
+            "Failed to update entitlement {} of {}",
+            entitlement, wallet_address
+        )
+    })?
+    .with_context(|| format!("No subscriber {}", wallet_address))?;
+
+    Entitlements::from_be_slice(&bitmap)
+}
+
+/// Grant one entitlement to a subscriber and return the new bitmap.
+/// Takes effect in the next published root.
+pub async fn grant_entitlement(
+    pool: &PgPool,
+    wallet_address: &str,
+    entitlement: Entitlement,
+) -> Result<Entitlements> {
+    set_entitlement_bit(pool, wallet_address, entitlement, true).await
+}
+
+/// Revoke one entitlement from a subscriber and return the new bitmap.
+/// Proofs under already published roots keep proving it until the next root.
+pub async fn revoke_entitlement(
+    pool: &PgPool,
+    wallet_address: &str,
+    entitlement: Entitlement,
+) -> Result<Entitlements> {
+    set_entitlement_bit(pool, wallet_address, entitlement, false).await
+}
+
+/// Replace a subscriber's whole bitmap, on the same row `set_entitlement_bit` updates
+pub async fn set_entitlements(
+    pool: &PgPool,
+    wallet_address: &str,
+    entitlements: Entitlements,
+) -> Result<()> {
+    let updated = sqlx::query!(
+        "UPDATE subscriber_storage SET entitlements = $3, last_updated_at = $4
+         WHERE product_id = $2 AND wallet_address = (
+             SELECT wallet_address FROM subscriber_storage
+             WHERE lower(wallet_address) = $1 AND product_id = $2 AND expiration_ts >= 0
+             ORDER BY wallet_address COLLATE \"C\" LIMIT 1)",
+        normalize_address(wallet_address),
+        DEFAULT_PRODUCT as i32,
+        &entitlements.to_be_bytes()[..],
+        Utc::now().naive_utc()
+    )
+    .execute(pool)
+    .await
+    .with_context(|| format!("Failed to store entitlements of {}", wallet_address))?;
+
+    if updated.rows_affected() == 0 {
+        return Err(anyhow::anyhow!("No subscriber {}", wallet_address));
+    }
+    Ok(())
+}
+
+/// Load every `DEFAULT_PRODUCT` subscriber with its entitlements, sorted by wallet_address
+/// like `load_subscribers`.
+pub async fn load_entitled_subscribers(pool: &PgPool) -> Result<Vec<EntitledSubscriber>> {
+    query_entitled_subscribers(pool, None).await
+}
+
+/// Same as `load_entitled_subscribers`, but only for `wallets`, e.g. the rows validation
+/// accepted.
+pub async fn load_entitled_subscribers_for(
+    pool: &PgPool,
+    wallets: &[String],
+) -> Result<Vec<EntitledSubscriber>> {
+    query_entitled_subscribers(pool, Some(wallets)).await
+}
+
+async fn query_entitled_subscribers(
+    pool: &PgPool,
+    wallets: Option<&[String]>,
+) -> Result<Vec<EntitledSubscriber>> {
+    let rows = sqlx::query!(
+        "SELECT wallet_address, entitlements, expiration_ts FROM subscriber_storage
+             WHERE product_id = $1 AND ($2::varchar[] IS NULL OR wallet_address = ANY($2))",
+        DEFAULT_PRODUCT as i32,
+        wallets as Option<&[String]>
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut subscribers = rows
+        .into_iter()
+        .map(|row| {
+            let expiration = Expiration::from_unix(row.expiration_ts).with_context(|| {
+                format!("Invalid expiration for subscriber {}", row.wallet_address)
+            })?;
+            Ok(EntitledSubscriber {
+                entitlements: Entitlements::from_be_slice(&row.entitlements).with_context(
+                    || format!("Invalid entitlements for subscriber {}", row.wallet_address),
+                )?,
+                wallet_address: row.wallet_address,
+                expiration,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    subscribers.sort_by(|a, b| a.wallet_address.cmp(&b.wallet_address));
+    Ok(subscribers)
+}
+
+/// Build a tree of entitled leaves from `subscriber_storage`.
+/// `subscribers[i]` is the value behind the i-th leaf, as for `build_tree_from_db`.
+pub async fn build_entitled_tree_from_db<H: MerkleHasher>(
+    pool: &PgPool,
+    options: TreeOptions,
+) -> Result<(String, MerkleTree<H>, Vec<EntitledSubscriber>)> {
+    let subscribers = load_entitled_subscribers(pool).await?;
+
+    let leaves = subscribers
+        .iter()
+        .map(|s| {
+            compute_entitled_leaf_with::<H>(&s.wallet_address, s.entitlements, s.expiration)
+                .with_context(|| {
+                    format!("Invalid wallet address for subscriber {}", s.wallet_address)
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let plain: Vec<(String, Expiration)> = subscribers
+        .iter()
+        .map(|s| (s.wallet_address.clone(), s.expiration))
+        .collect();
+    check_unique_leaves(&leaves, &plain)?;
+
+    let tree = MerkleTree::<H>::from_leaves_with_options(&leaves, options);
+    Ok((hex::encode(tree.root()), tree, subscribers))
+}
+
+/// Get an entitled proof for a user, with the bitmap and expiration it proves.
+/// Returns None if the user is not in `subscribers`.
+/// Scans `subscribers` and the leaves; a `ProofIndex<EntitledSubscriber, H>` serves repeated
+/// lookups without scanning.
+pub fn get_entitled_proof_for_user<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscribers: &[EntitledSubscriber],
+    user_pubkey: &str,
+) -> Option<(Entitlements, Expiration, Proof<H>)> {
+    let user = normalize_address(user_pubkey);
+    let subscriber = subscribers
+        .iter()
+        .find(|s| normalize_address(&s.wallet_address) == user)?;
+
+    let values = entitled_values(
+        &subscriber.wallet_address,
+        subscriber.entitlements,
+        subscriber.expiration,
+    )
+    .ok()?;
+    let proof = get_proof_for_value(tree, &entitled_encoding(), &values)?;
+    Some((subscriber.entitlements, subscriber.expiration, proof))
+}
+
+/// The proof response of every subscriber, in the order of `subscribers`.
+/// Looks each leaf up in a position map, so the export takes O(n log n).
+pub fn export_entitled_proofs<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscribers: &[EntitledSubscriber],
+) -> Result<Vec<EntitledProofEntry>> {
+    let root = hex::encode(tree.root());
+    let to_hex = |hash: &[u8; 32]| format!("0x{}", hex::encode(hash));
+    let positions: HashMap<[u8; 32], usize> = tree
+        .leaf_positions()
+        .into_iter()
+        .map(|(position, leaf)| (leaf, position))
+        .collect();
+
+    subscribers
+        .iter()
+        .map(|s| {
+            let leaf = s.leaf::<H>()?;
+            let proof = positions
+                .get(&leaf)
+                .and_then(|&position| tree.get_proof_at(position))
+                .with_context(|| format!("No proof for subscriber {}", s.wallet_address))?;
+            Ok(EntitledProofEntry {
+                root: root.clone(),
+                address: s.wallet_address.clone(),
+                entitlements: s.entitlements,
+                expiration: s.expiration,
+                leaf: to_hex(&leaf),
+                proof: proof.iter().map(to_hex).collect(),
+            })
+        })
+        .collect()
+}
+
+/// Off-chain verification that the user holds `entitlements` until `expiration_ts`, and that
+/// those include every entitlement in `required`. Pass `Entitlements::NONE` to only check
+/// the proof.
+pub fn verify_entitled_subscription<H: MerkleHasher>(
+    root_hex: &str,
+    proof: &Proof<H>,
+    user_pubkey: &str,
+    entitlements: Entitlements,
+    expiration_ts: Expiration,
+    required: Entitlements,
+) -> Result<bool> {
+    if !entitlements.contains_all(required) {
+        return Ok(false);
+    }
+    let values = entitled_values(user_pubkey, entitlements, expiration_ts)?;
+    verify_value(root_hex, proof, &entitled_encoding(), &values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::tree::TreeLayout;
+
+    fn subscribers() -> Vec<EntitledSubscriber> {
+        (0..9u8)
+            .map(|i| EntitledSubscriber {
+                wallet_address: format!("0x{}", hex::encode([0xa0 + i; 20])),
+                entitlements: Entitlements::NONE.with(i).with(200),
+                expiration: Expiration::new(1_700_000_000).unwrap(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn export_matches_scanned_proofs() {
+        let subscribers = subscribers();
+        let leaves: Vec<_> = subscribers
+            .iter()
+            .map(|s| s.leaf::<Keccak256Hasher>().unwrap())
+            .collect();
+        for layout in [TreeLayout::Layered, TreeLayout::Standard] {
+            let options = TreeOptions {
+                layout,
+                ..TreeOptions::default()
+            };
+            let tree = MerkleTree::<Keccak256Hasher>::from_leaves_with_options(&leaves, options);
+            let entries = export_entitled_proofs(&tree, &subscribers).unwrap();
+
+            assert_eq!(entries.len(), subscribers.len());
+            for (entry, s) in entries.iter().zip(&subscribers) {
+                let (_, _, scanned) =
+                    get_entitled_proof_for_user(&tree, &subscribers, &s.wallet_address).unwrap();
+                let scanned: Vec<_> = scanned
+                    .iter()
+                    .map(|h| format!("0x{}", hex::encode(h)))
+                    .collect();
+                assert_eq!(entry.proof, scanned);
+                assert_eq!(entry.entitlements, s.entitlements);
+            }
+        }
+    }
+
+    #[test]
+    fn column_bit_numbers_bytes_big_endian() {
+        assert_eq!(column_bit(0), 248);
+        assert_eq!(column_bit(7), 255);
+        assert_eq!(column_bit(8), 240);
+        assert_eq!(column_bit(255), 7);
+    }
+
+    /// Checks `column_bit` against Postgres' own `set_bit`. Needs DATABASE_URL; skipped
+    /// without it.
+    #[tokio::test]
+    async fn set_bit_sets_entitlement_k() {
+        let Ok(database_url) = std::env::var("DATABASE_URL") else {
+            return;
+        };
+        let pool = PgPool::connect(&database_url).await.unwrap();
+
+        for entitlement in 0..=Entitlement::MAX {
+            let bitmap: Vec<u8> =
+                sqlx::query_scalar("SELECT set_bit(decode(repeat('00', 32), 'hex'), $1, 1)")
+                    .bind(column_bit(entitlement))
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+            assert_eq!(
+                Entitlements::from_be_slice(&bitmap).unwrap(),
+                Entitlements::NONE.with(entitlement),
+                "entitlement {}",
+                entitlement
+            );
+        }
+    }
+
+    /// Grants and bitmap writes reach only the case variant validation accepts. Needs
+    /// DATABASE_URL; skipped without it.
+    #[tokio::test]
+    async fn updates_only_the_accepted_case_variant() {
+        let Ok(database_url) = std::env::var("DATABASE_URL") else {
+            return;
+        };
+        let pool = PgPool::connect(&database_url).await.unwrap();
+        let lower_case = format!("0x{}", "e1".repeat(20));
+        let upper_case = format!("0x{}", "E1".repeat(20));
+        for address in [&lower_case, &upper_case] {
+            sqlx::query(
+                "INSERT INTO subscriber_storage (wallet_address, expiration_ts) VALUES ($1, 1)",
+            )
+            .bind(address)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let bitmap_of = |address: String| {
+            let pool = pool.clone();
+            async move {
+                let bytes: Vec<u8> = sqlx::query_scalar(
+                    "SELECT entitlements FROM subscriber_storage
+                     WHERE wallet_address = $1 AND product_id = 0",
+                )
+                .bind(address)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+                Entitlements::from_be_slice(&bytes).unwrap()
+            }
+        };
+
+        // "0xE1..." sorts before "0xe1..." byte-wise, so it is the accepted row
+        let granted = grant_entitlement(&pool, &lower_case, 3).await.unwrap();
+        assert_eq!(granted, Entitlements::NONE.with(3));
+        assert_eq!(bitmap_of(upper_case.clone()).await, granted);
+        assert_eq!(bitmap_of(lower_case.clone()).await, Entitlements::NONE);
+
+        let replaced = Entitlements::NONE.with(9);
+        set_entitlements(&pool, &lower_case, replaced)
+            .await
+            .unwrap();
+        assert_eq!(bitmap_of(upper_case.clone()).await, replaced);
+        assert_eq!(bitmap_of(lower_case.clone()).await, Entitlements::NONE);
+
+        sqlx::query("DELETE FROM subscriber_storage WHERE lower(wallet_address) = $1")
+            .bind(&lower_case)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
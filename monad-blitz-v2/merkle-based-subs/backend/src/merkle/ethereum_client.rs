@@ -4,19 +4,35 @@ use std::sync::Arc;
 use std::convert::TryFrom;
 use std::str::FromStr;
 
+use super::deployment::Deployment;
+use super::tree::Proof;
+use crate::model::{Expiration, PlanId, ProductId};
+
 // Generate contract bindings — includes verifySubscription for on-chain proof verification
+//
+// The contract is smart-contracts/contracts/MerkleUpdater.sol. The instance deployed before
+// the tiered and product overloads were added only has verifySubscription(bytes32[], uint256);
+// calls to the overloads revert there until the contract is redeployed.
 abigen!(
     MerkleUpdater,
     r#"[
         function updateMerkleRoot(bytes32 newRoot) external returns (bool)
         function currentRoot() external view returns (bytes32)
         function verifySubscription(bytes32[] proof, uint256 expiration) external
+        function verifySubscription(bytes32[] proof, uint256 planId, uint256 expiration) external
+        function verifySubscription(bytes32[] proof, uint256 productId, uint256 planId, uint256 expiration) external
     ]"#,
+    methods {
+        verifySubscription(bytes32[],uint256,uint256) as verify_tiered_subscription;
+        verifySubscription(bytes32[],uint256,uint256,uint256) as verify_product_subscription;
+    },
 );
 
+type SignerClient = SignerMiddleware<Provider<Http>, LocalWallet>;
+
 pub struct EthereumClient {
     pub provider: Provider<Http>,
-    pub contract: MerkleUpdater<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    pub contract: MerkleUpdater<SignerClient>,
 }
 
 impl EthereumClient {
@@ -27,7 +43,7 @@ impl EthereumClient {
     ) -> Result<Self> {
         let provider = Provider::<Http>::try_from(rpc_url).context("Invalid RPC URL")?;
 
-        let chain_id = provider.get_chainid().await?.as_u64();
+        let chain_id = fetch_chain_id(&provider).await?;
 
         // 0x prefix removal for hex parsing
         let pk_clean = private_key_hex.trim_start_matches("0x");
@@ -75,6 +91,15 @@ impl EthereumClient {
         format!("0x{}", hex::encode(addr.as_bytes())) // lowercase to match DB convention
     }
 
+    /// The chain id of the provider and the contract address, as committed by bound leaves
+    /// (see `deployment::LeafMode::Bound`)
+    pub async fn deployment(&self) -> Result<Deployment> {
+        Ok(Deployment {
+            chain_id: fetch_chain_id(&self.provider).await?,
+            contract: self.contract.address(),
+        })
+    }
+
     pub async fn get_current_root(&self) -> Result<[u8; 32]> {
         let root: [u8; 32] = self.contract.current_root().call().await?;
         Ok(root)
@@ -84,16 +109,63 @@ impl EthereumClient {
     /// The contract checks:
     ///   1. expiration > block.timestamp (not expired)
     ///   2. MerkleProof.verify(proof, currentRoot, leaf) where leaf = double-hash of (msg.sender, expiration)
+    ///
     /// Returns the tx hash on success.
+    /// Takes a Keccak256 `Proof`, the only hasher the contract understands.
     pub async fn verify_subscription_onchain(
         &self,
-        proof: Vec<[u8; 32]>,
-        expiration: u64,
+        proof: Proof,
+        expiration: Expiration,
     ) -> Result<String> {
         let call = self
             .contract
-            .verify_subscription(proof, U256::from(expiration));
+            .verify_subscription(proof.into_siblings(), expiration.to_u256());
+        self.send_verification(call).await
+    }
 
+    /// Verify a tiered subscription on-chain by calling
+    /// `verifySubscription(bytes32[], uint256 planId, uint256 expiration)`.
+    /// Same checks as `verify_subscription_onchain`, with the leaf built from
+    /// (msg.sender, planId, expiration); see `tiers::compute_tiered_leaf`.
+    /// The root must commit tiered leaves, and the contract must have the overload.
+    pub async fn verify_tiered_subscription_onchain(
+        &self,
+        proof: Proof,
+        plan_id: PlanId,
+        expiration: Expiration,
+    ) -> Result<String> {
+        let call = self.contract.verify_tiered_subscription(
+            proof.into_siblings(),
+            U256::from(plan_id),
+            expiration.to_u256(),
+        );
+        self.send_verification(call).await
+    }
+
+    /// Verify a product subscription on-chain by calling
+    /// `verifySubscription(bytes32[], uint256 productId, uint256 planId, uint256 expiration)`.
+    /// Same checks as `verify_subscription_onchain`, with the leaf built from
+    /// (uint8(1), msg.sender, productId, planId, expiration); see
+    /// `products::compute_product_leaf`.
+    /// The root must commit product leaves, and the contract must have the overload.
+    pub async fn verify_product_subscription_onchain(
+        &self,
+        proof: Proof,
+        product_id: ProductId,
+        plan_id: PlanId,
+        expiration: Expiration,
+    ) -> Result<String> {
+        let call = self.contract.verify_product_subscription(
+            proof.into_siblings(),
+            U256::from(product_id),
+            U256::from(plan_id),
+            expiration.to_u256(),
+        );
+        self.send_verification(call).await
+    }
+
+    /// Send a `verifySubscription` call and wait for it to be mined
+    async fn send_verification(&self, call: ContractCall<SignerClient, ()>) -> Result<String> {
         let pending_tx = call
             .send()
             .await
@@ -121,3 +193,14 @@ impl EthereumClient {
         Ok(format!("{:?}", receipt.transaction_hash))
     }
 }
+
+/// The provider's chain id, which fails instead of truncating if it does not fit in 64 bits
+async fn fetch_chain_id(provider: &Provider<Http>) -> Result<u64> {
+    let chain_id = provider
+        .get_chainid()
+        .await
+        .context("Failed to fetch the chain id")?;
+    chain_id
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Chain id {} does not fit in 64 bits", chain_id))
+}
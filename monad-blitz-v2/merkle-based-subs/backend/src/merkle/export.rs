@@ -0,0 +1,381 @@
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::parallel::compute_leaves_parallel;
+use super::tree::{compute_leaf, OzMerkleTree, Proof};
+use super::validation::normalize_address;
+use crate::model::Expiration;
+
+/// Magic bytes opening a binary proof export
+pub const PROOFS_MAGIC: [u8; 4] = *b"MPRF";
+/// Version of the binary proof export layout
+pub const PROOFS_VERSION: u8 = 1;
+
+/// One subscriber's proof, as written to the export files.
+/// Everything a frontend needs to call `verifySubscription(proof, expiration)`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProofEntry {
+    /// Hex root, no `0x`, as stored in `merkle_state`
+    pub root: String,
+    pub address: String,
+    pub expiration: Expiration,
+    /// 0x-prefixed leaf hash
+    pub leaf: String,
+    /// 0x-prefixed siblings, leaf first (Solidity `bytes32[]`)
+    pub proof: Vec<String>,
+}
+
+/// Every subscriber's proof, in the order of `subscribers`.
+///
+/// Leaf positions are looked up once (directly in the standard layout, through a
+/// leaf -> position map in the layered one), then each proof is a single O(log n)
+/// climb, so the whole export is O(n log n). Leaves and proofs are computed in parallel.
+pub fn export_proofs(
+    tree: &OzMerkleTree,
+    subscribers: &[(String, Expiration)],
+) -> Result<Vec<ProofEntry>> {
+    let root = hex::encode(tree.root());
+    let leaves = compute_leaves_parallel(subscribers)?;
+
+    let by_leaf: HashMap<[u8; 32], usize> = match tree.tree_index(0) {
+        Some(_) => HashMap::new(),
+        None => tree
+            .leaf_positions()
+            .into_iter()
+            .map(|(position, leaf)| (leaf, position))
+            .collect(),
+    };
+
+    subscribers
+        .par_iter()
+        .zip(leaves.par_iter())
+        .enumerate()
+        .map(|(value_index, ((address, expiration), leaf))| {
+            let position = tree
+                .tree_index(value_index)
+                .or_else(|| by_leaf.get(leaf).copied())
+                .with_context(|| format!("Subscriber {} is not in the tree", address))?;
+            let proof = tree
+                .get_proof_at(position)
+                .with_context(|| format!("No proof for subscriber {}", address))?;
+
+            Ok(ProofEntry {
+                root: root.clone(),
+                address: address.clone(),
+                expiration: *expiration,
+                leaf: format!("0x{}", hex::encode(leaf)),
+                proof: proof
+                    .iter()
+                    .map(|node| format!("0x{}", hex::encode(node)))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Write one JSON object per line
+pub fn write_proofs_jsonl(path: impl AsRef<Path>, entries: &[ProofEntry]) -> Result<()> {
+    let file = File::create(path).context("Failed to create proof export")?;
+    let mut writer = BufWriter::new(file);
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_proofs_jsonl(path: impl AsRef<Path>) -> Result<Vec<ProofEntry>> {
+    let file = File::open(path).context("Failed to open proof export")?;
+    BufReader::new(file)
+        .lines()
+        .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .map(|line| serde_json::from_str(&line?).context("Failed to parse proof export line"))
+        .collect()
+}
+
+/// Write one `<lowercase address>.json` file per subscriber into `dir`, for static hosting.
+pub fn write_proof_files(dir: impl AsRef<Path>, entries: &[ProofEntry]) -> Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir).context("Failed to create proof export directory")?;
+    entries.par_iter().try_for_each(|entry| {
+        let path = dir.join(format!("{}.json", normalize_address(&entry.address)));
+        let json = serde_json::to_string(entry)?;
+        fs::write(&path, json).with_context(|| format!("Failed to write {}", path.display()))
+    })
+}
+
+/// Compact binary export. All integers are big-endian:
+///
+/// ```text
+/// "MPRF" | version u8 | root [32] | count u32
+/// count x ( address [20] | expiration u64 | siblings u8 | siblings x [32] )
+/// ```
+///
+/// `root` is written even without entries, e.g. `tree.root()` of an empty tree. Every entry is
+/// checked against it before `path` is created, so a failed export leaves an existing file intact.
+pub fn write_proofs_binary(
+    path: impl AsRef<Path>,
+    root: [u8; 32],
+    entries: &[ProofEntry],
+) -> Result<()> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&PROOFS_MAGIC);
+    bytes.push(PROOFS_VERSION);
+    bytes.extend_from_slice(&root);
+    bytes.extend_from_slice(&u32::try_from(entries.len())?.to_be_bytes());
+
+    for entry in entries {
+        if decode_fixed::<32>(&entry.root).context("Invalid root")? != root {
+            return Err(anyhow::anyhow!(
+                "Proof of {} is for root {}, not 0x{}",
+                entry.address,
+                entry.root,
+                hex::encode(root)
+            ));
+        }
+        let address = decode_fixed::<20>(&entry.address)
+            .with_context(|| format!("Invalid address {}", entry.address))?;
+        bytes.extend_from_slice(&address);
+        bytes.extend_from_slice(&entry.expiration.as_u64().to_be_bytes());
+        bytes.push(u8::try_from(entry.proof.len())?);
+        for node in &entry.proof {
+            bytes.extend_from_slice(&decode_fixed::<32>(node).context("Invalid proof node")?);
+        }
+    }
+
+    fs::write(path, bytes).context("Failed to write proof export")
+}
+
+/// Read a binary export written by `write_proofs_binary`. Leaves are recomputed.
+/// The header's entry count is not trusted for allocation: a file that ends early, or has
+/// bytes left after the last entry, is rejected.
+pub fn read_proofs_binary(path: impl AsRef<Path>) -> Result<Vec<ProofEntry>> {
+    let file = File::open(path).context("Failed to open proof export")?;
+    let mut reader = BufReader::new(file);
+
+    let header: [u8; 5] = read_array(&mut reader)?;
+    if header[..4] != PROOFS_MAGIC || header[4] != PROOFS_VERSION {
+        return Err(anyhow::anyhow!(
+            "Not a version {} proof export",
+            PROOFS_VERSION
+        ));
+    }
+    let root = hex::encode(read_array::<32>(&mut reader)?);
+    let count = u32::from_be_bytes(read_array(&mut reader)?);
+
+    // Grows as entries are read, so a lying count cannot allocate ahead of the data
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let address = format!("0x{}", hex::encode(read_array::<20>(&mut reader)?));
+        let expiration = Expiration::new(u64::from_be_bytes(read_array(&mut reader)?))?;
+        let [siblings] = read_array::<1>(&mut reader)?;
+        let proof = (0..siblings)
+            .map(|_| Ok(format!("0x{}", hex::encode(read_array::<32>(&mut reader)?))))
+            .collect::<Result<Vec<_>>>()?;
+
+        let leaf = compute_leaf(&address, expiration)?;
+        entries.push(ProofEntry {
+            root: root.clone(),
+            address,
+            expiration,
+            leaf: format!("0x{}", hex::encode(leaf)),
+            proof,
+        });
+    }
+
+    if !reader.fill_buf()?.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Proof export has data past its {} entries",
+            count
+        ));
+    }
+
+    Ok(entries)
+}
+
+/// Store the proofs in `subscriber_proofs`, replacing any earlier set for the same root.
+pub async fn store_proofs(pool: &PgPool, entries: &[ProofEntry]) -> Result<()> {
+    let Some(root) = entries.first().map(|e| e.root.clone()) else {
+        return Ok(());
+    };
+
+    let mut addresses = Vec::with_capacity(entries.len());
+    let mut expirations = Vec::with_capacity(entries.len());
+    let mut proofs = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if entry.root != root {
+            return Err(anyhow::anyhow!(
+                "All proofs in an export must share one root"
+            ));
+        }
+        addresses.push(entry.address.clone());
+        expirations.push(entry.expiration.as_i64());
+        let mut proof = Vec::with_capacity(32 * entry.proof.len());
+        for node in &entry.proof {
+            proof.extend_from_slice(&decode_fixed::<32>(node).context("Invalid proof node")?);
+        }
+        proofs.push(proof);
+    }
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM subscriber_proofs WHERE root_hash = $1", root)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO subscriber_proofs (root_hash, wallet_address, expiration_ts, proof)
+         SELECT $1, * FROM UNNEST($2::varchar[], $3::bigint[], $4::bytea[])",
+        root,
+        &addresses,
+        &expirations,
+        &proofs
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// A stored proof for `user_pubkey` under `root_hex`, with the expiration it proves.
+pub async fn load_stored_proof(
+    pool: &PgPool,
+    root_hex: &str,
+    user_pubkey: &str,
+) -> Result<Option<(Expiration, Proof)>> {
+    let row = sqlx::query!(
+        "SELECT expiration_ts, proof FROM subscriber_proofs
+         WHERE root_hash = $1 AND lower(wallet_address) = $2",
+        root_hex.trim_start_matches("0x"),
+        normalize_address(user_pubkey)
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let proof = row
+        .proof
+        .chunks_exact(32)
+        .map(|node| node.try_into().expect("chunk of 32 bytes"))
+        .collect();
+    Ok(Some((
+        Expiration::from_unix(row.expiration_ts)?,
+        Proof::new(proof),
+    )))
+}
+
+fn decode_fixed<const N: usize>(hex_str: &str) -> Result<[u8; N]> {
+    let bytes = hex::decode(hex_str.trim_start_matches("0x"))?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Expected {} bytes", N))
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader
+        .read_exact(&mut buf)
+        .context("Truncated proof export")?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::tree::EMPTY_ROOT;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{}-{}", std::process::id(), name))
+    }
+
+    fn subscribers(n: u8) -> Vec<(String, Expiration)> {
+        (0..n)
+            .map(|i| {
+                let address = format!("0x{}", hex::encode([0xa0 + i; 20]));
+                (
+                    address,
+                    Expiration::new(1_700_000_000 + u64::from(i)).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn binary_export_round_trips() {
+        let subscribers = subscribers(5);
+        let leaves = compute_leaves_parallel(&subscribers).unwrap();
+        let tree = OzMerkleTree::from_leaves(&leaves);
+        let entries = export_proofs(&tree, &subscribers).unwrap();
+
+        let path = temp_path("proofs.bin");
+        write_proofs_binary(&path, tree.root(), &entries).unwrap();
+        assert_eq!(read_proofs_binary(&path).unwrap(), entries);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn empty_binary_export_keeps_the_root() {
+        let tree = OzMerkleTree::from_leaves(&[]);
+        let path = temp_path("empty-proofs.bin");
+        write_proofs_binary(&path, tree.root(), &[]).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes[5..37], EMPTY_ROOT);
+        assert!(read_proofs_binary(&path).unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_or_miscounted_binary_exports_are_rejected() {
+        let subscribers = subscribers(3);
+        let leaves = compute_leaves_parallel(&subscribers).unwrap();
+        let tree = OzMerkleTree::from_leaves(&leaves);
+        let entries = export_proofs(&tree, &subscribers).unwrap();
+
+        let path = temp_path("bad-proofs.bin");
+        write_proofs_binary(&path, tree.root(), &entries).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        // Cut off inside the last proof
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(read_proofs_binary(&path).is_err());
+
+        // A count far beyond the data, and one short of it
+        for count in [u32::MAX, 2] {
+            let mut lying = bytes.clone();
+            lying[37..41].copy_from_slice(&count.to_be_bytes());
+            fs::write(&path, &lying).unwrap();
+            assert!(read_proofs_binary(&path).is_err(), "count {}", count);
+        }
+
+        // Cut off inside the header
+        fs::write(&path, &bytes[..39]).unwrap();
+        assert!(read_proofs_binary(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_binary_export_leaves_the_file_alone() {
+        let subscribers = subscribers(3);
+        let leaves = compute_leaves_parallel(&subscribers).unwrap();
+        let tree = OzMerkleTree::from_leaves(&leaves);
+        let entries = export_proofs(&tree, &subscribers).unwrap();
+
+        let path = temp_path("kept-proofs.bin");
+        fs::write(&path, b"previous export").unwrap();
+        assert!(write_proofs_binary(&path, [1u8; 32], &entries).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"previous export");
+        fs::remove_file(&path).unwrap();
+    }
+}
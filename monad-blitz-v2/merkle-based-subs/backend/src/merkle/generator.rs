@@ -3,6 +3,8 @@ use chrono::Utc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use sqlx::PgPool;
 
+use crate::model::Expiration;
+
 pub async fn generate_and_store_keys(pool: &PgPool, count: usize) -> Result<()> {
     for i in 0..count {
         let start_time = SystemTime::now()
@@ -13,7 +15,8 @@ pub async fn generate_and_store_keys(pool: &PgPool, count: usize) -> Result<()>
         let pubkey = format!("0x{:020x}{:020x}", start_time, i);
 
         // 2. Set expiration (e.g., 30 days from now)
-        let expiration_ts = (Utc::now().timestamp() + (30 * 24 * 60 * 60)) as i64;
+        let expiration_ts =
+            Expiration::from_unix(Utc::now().timestamp() + (30 * 24 * 60 * 60))?;
 
         // 3. Set last updated timestamp (using naive datetime for the DB)
         let last_updated_at = Utc::now().naive_utc();
@@ -22,7 +25,7 @@ pub async fn generate_and_store_keys(pool: &PgPool, count: usize) -> Result<()>
         sqlx::query!(
             "INSERT INTO subscriber_storage (wallet_address, expiration_ts, last_updated_at) VALUES ($1, $2, $3)",
             pubkey,
-            expiration_ts,
+            expiration_ts.as_i64(),
             last_updated_at
         )
         .execute(pool)
@@ -0,0 +1,314 @@
+use ark_bn254::Fr;
+use ark_ff::{BigInteger, PrimeField};
+use light_poseidon::{Poseidon, PoseidonHasher as _};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+/// Hash function a `MerkleTree` commits with.
+///
+/// Implementors are zero-sized markers; the tree, its proofs and multiproofs carry the hasher
+/// as a type parameter, so a proof can only be verified with the hasher that produced it.
+/// The hasher exists only at the type level: neither `TreeOptions` nor `merkle_state` record
+/// it, and historical trees are always rebuilt with `Keccak256Hasher`.
+pub trait MerkleHasher:
+    Debug + Clone + Copy + PartialEq + Eq + Default + Send + Sync + 'static
+{
+    /// Short name, e.g. for logs and file names.
+    /// Wrappers such as `DomainSeparated` include the name of the hasher they wrap.
+    fn name() -> Cow<'static, str>;
+
+    /// Hash arbitrary bytes
+    fn hash(data: &[u8]) -> [u8; 32];
+
+    /// Hash an `abi.encode`d leaf value into a leaf: `hash(hash(encoded))`, as OZ does.
+    fn hash_leaf(encoded: &[u8]) -> [u8; 32] {
+        Self::hash(&Self::hash(encoded))
+    }
+
+    /// Hash a left and a right child into their parent, keeping their order: `hash(left ++ right)`.
+    fn hash_ordered(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        let mut combined = [0u8; 64];
+        combined[..32].copy_from_slice(&left);
+        combined[32..].copy_from_slice(&right);
+        Self::hash(&combined)
+    }
+
+    /// Hash two child nodes into their parent with OZ's sorted-pair rule:
+    /// `hash_ordered(min(a, b), max(a, b))`.
+    fn hash_pair(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+        if a <= b {
+            Self::hash_ordered(a, b)
+        } else {
+            Self::hash_ordered(b, a)
+        }
+    }
+}
+
+/// Keccak256, as used by OpenZeppelin's `MerkleProof` and StandardMerkleTree. The default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Keccak256Hasher;
+
+impl MerkleHasher for Keccak256Hasher {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed("keccak256")
+    }
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        use sha3::{Digest, Keccak256};
+        Keccak256::digest(data).into()
+    }
+}
+
+/// SHA-256, for verifiers on chains without a cheap Keccak precompile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Sha256Hasher;
+
+impl MerkleHasher for Sha256Hasher {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed("sha256")
+    }
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(data).into()
+    }
+}
+
+/// Prefix of a leaf hash under `DomainSeparated`
+pub const LEAF_PREFIX: u8 = 0x00;
+/// Prefix of an internal node hash under `DomainSeparated`
+pub const NODE_PREFIX: u8 = 0x01;
+
+/// `H` with leaves and internal nodes hashed in separate domains, as in RFC 6962:
+/// `leaf = H(0x00 ++ H(encoded))` and `node = H(0x01 ++ left ++ right)`.
+///
+/// The OZ double hash already keeps a 64-byte leaf value from being read as a pair of
+/// children, but it relies on the leaf encoding; with prefixes no leaf can equal an internal
+/// node whatever the encoding. Roots differ from plain `H` trees, so proofs need a verifier
+/// that applies the same prefixes (OZ's `MerkleProof` does not).
+///
+/// Like every hasher this is a type parameter only: build and verify with
+/// `MerkleTree<DomainSeparated<H>>`, and keep track of it yourself, since published history
+/// does not record which hasher a root was built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DomainSeparated<H: MerkleHasher>(PhantomData<H>);
+
+impl<H: MerkleHasher> MerkleHasher for DomainSeparated<H> {
+    fn name() -> Cow<'static, str> {
+        Cow::Owned(format!("domain-separated-{}", H::name()))
+    }
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        H::hash(data)
+    }
+
+    fn hash_leaf(encoded: &[u8]) -> [u8; 32] {
+        let mut data = [0u8; 33];
+        data[0] = LEAF_PREFIX;
+        data[1..].copy_from_slice(&H::hash(encoded));
+        H::hash(&data)
+    }
+
+    fn hash_ordered(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        let mut data = [0u8; 65];
+        data[0] = NODE_PREFIX;
+        data[1..33].copy_from_slice(&left);
+        data[33..].copy_from_slice(&right);
+        H::hash(&data)
+    }
+}
+
+/// Poseidon over the BN254 scalar field with circom's parameters, for ZK circuits.
+///
+/// Nodes are field elements written as 32 big-endian bytes; children are hashed as
+/// `poseidon(left, right)`, after sorting them by value for sorted-pair trees.
+/// Byte strings are split into 31-byte big-endian chunks, which always fit in the field,
+/// and absorbed two at a time: `state = poseidon(state, chunk)` starting from
+/// `state = byte length`.
+///
+/// That byte absorption is this crate's own scheme, not a standard Poseidon sponge or any
+/// circomlib template: a circuit has to chain `Poseidon(2)` the same way to recompute a
+/// leaf. Only `hash_ordered` is plain circom `Poseidon(2)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoseidonHasher;
+
+/// Bytes per chunk when hashing a byte string: 31 bytes stay below the BN254 modulus
+const POSEIDON_CHUNK: usize = 31;
+
+thread_local! {
+    // Building the round constants is far more expensive than one permutation
+    static POSEIDON2: RefCell<Poseidon<Fr>> =
+        RefCell::new(Poseidon::<Fr>::new_circom(2).expect("circom parameters for 2 inputs"));
+}
+
+fn poseidon2(a: Fr, b: Fr) -> Fr {
+    POSEIDON2.with(|poseidon| {
+        poseidon
+            .borrow_mut()
+            .hash(&[a, b])
+            .expect("two inputs match the parameters")
+    })
+}
+
+fn field_to_bytes(f: Fr) -> [u8; 32] {
+    let bytes = f.into_bigint().to_bytes_be();
+    let mut out = [0u8; 32];
+    out[32 - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+impl MerkleHasher for PoseidonHasher {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed("poseidon-bn254")
+    }
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        let mut state = Fr::from(data.len() as u64);
+        for chunk in data.chunks(POSEIDON_CHUNK) {
+            state = poseidon2(state, Fr::from_be_bytes_mod_order(chunk));
+        }
+        field_to_bytes(state)
+    }
+
+    // Nodes are canonical field elements, so the default `hash_pair` byte order is field order
+    fn hash_ordered(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        field_to_bytes(poseidon2(
+            Fr::from_be_bytes_mod_order(&left),
+            Fr::from_be_bytes_mod_order(&right),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::tree::{MerkleTree, Proof, TreeLayout, TreeOptions};
+
+    type Separated = DomainSeparated<Keccak256Hasher>;
+
+    fn leaves<H: MerkleHasher>(n: u8) -> Vec<[u8; 32]> {
+        (0..n).map(|i| H::hash_leaf(&[i; 64])).collect()
+    }
+
+    fn from_hex(hex_str: &str) -> [u8; 32] {
+        hex::decode(hex_str).unwrap().try_into().unwrap()
+    }
+
+    fn word(value: u64) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        out[24..].copy_from_slice(&value.to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn known_answers() {
+        assert_eq!(
+            Keccak256Hasher::hash(b""),
+            from_hex("c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470")
+        );
+        assert_eq!(
+            Sha256Hasher::hash(b"abc"),
+            from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        // circomlibjs poseidon([1, 2]) and poseidon([0, 0])
+        assert_eq!(
+            PoseidonHasher::hash_ordered(word(1), word(2)),
+            from_hex("115cc0f5e7d690413df64c6b9662e9cf2a3617f2743245519e19607a4417189a")
+        );
+        assert_eq!(
+            PoseidonHasher::hash_ordered(word(0), word(0)),
+            from_hex("2098f5fb9e239eab3ceac3f27b81e481dc3124d55ffed523a839ee8446b64864")
+        );
+    }
+
+    #[test]
+    fn poseidon_absorbs_bytes_in_31_byte_chunks() {
+        // Three bytes: one chunk, state starts at the byte length
+        assert_eq!(
+            PoseidonHasher::hash(&[0, 0, 2]),
+            PoseidonHasher::hash_ordered(word(3), word(2))
+        );
+
+        // 32 bytes: a full 31-byte chunk, then the last byte
+        let data = [1u8; 32];
+        let mut first = [0u8; 32];
+        first[1..].copy_from_slice(&data[..31]);
+        let state = PoseidonHasher::hash_ordered(word(32), first);
+        assert_eq!(
+            PoseidonHasher::hash(&data),
+            PoseidonHasher::hash_ordered(state, word(1))
+        );
+
+        // The length keeps leading zero bytes significant
+        assert_ne!(PoseidonHasher::hash(&[2]), PoseidonHasher::hash(&[0, 2]));
+    }
+
+    #[test]
+    fn domain_separated_prefixes_leaves_and_nodes() {
+        let encoded = [7u8; 64];
+        let mut leaf_data = vec![LEAF_PREFIX];
+        leaf_data.extend_from_slice(&Keccak256Hasher::hash(&encoded));
+        assert_eq!(
+            Separated::hash_leaf(&encoded),
+            Keccak256Hasher::hash(&leaf_data)
+        );
+
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        let mut node_data = vec![NODE_PREFIX];
+        node_data.extend_from_slice(&a);
+        node_data.extend_from_slice(&b);
+        assert_eq!(
+            Separated::hash_pair(b, a),
+            Keccak256Hasher::hash(&node_data)
+        );
+
+        // A 64-byte leaf value never hashes like the pair it could be read as
+        assert_ne!(
+            Separated::hash_leaf(&[a, b].concat()),
+            Separated::hash_ordered(a, b)
+        );
+    }
+
+    #[test]
+    fn domain_separated_proofs_verify_only_with_the_prefixes() {
+        for layout in [TreeLayout::Layered, TreeLayout::Standard] {
+            let options = TreeOptions {
+                layout,
+                ..TreeOptions::default()
+            };
+            let leaves = leaves::<Separated>(6);
+            let tree = MerkleTree::<Separated>::from_leaves_with_options(&leaves, options);
+            let plain = MerkleTree::<Keccak256Hasher>::from_leaves_with_options(&leaves, options);
+            assert_ne!(tree.root(), plain.root());
+
+            for leaf in &leaves {
+                let proof = tree.get_proof(leaf).unwrap();
+                assert!(MerkleTree::<Separated>::verify(&tree.root(), &proof, leaf));
+
+                let siblings = Proof::<Keccak256Hasher>::new(proof.to_vec());
+                assert!(!MerkleTree::<Keccak256Hasher>::verify(
+                    &tree.root(),
+                    &siblings,
+                    leaf
+                ));
+            }
+
+            if layout == TreeLayout::Standard {
+                let multi = tree.get_multi_proof(&leaves[1..4]).unwrap();
+                assert!(MerkleTree::<Separated>::verify_multi(&tree.root(), &multi));
+            }
+        }
+    }
+
+    #[test]
+    fn names_include_the_inner_hasher() {
+        assert_eq!(Separated::name(), "domain-separated-keccak256");
+        assert_eq!(
+            DomainSeparated::<Sha256Hasher>::name(),
+            "domain-separated-sha256"
+        );
+        assert_ne!(DomainSeparated::<PoseidonHasher>::name(), Separated::name());
+    }
+}
@@ -0,0 +1,427 @@
+use anyhow::{Context, Result};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::PgPool;
+
+use super::credits::CreditSubscriber;
+use super::deployment::{Deployment, LeafMode};
+use super::diff::Snapshot;
+use super::entitlements::EntitledSubscriber;
+use super::products::ProductSubscription;
+use super::published::{ExtensionLeaf, PublishedTree};
+use super::tiers::TieredSubscriber;
+use super::tree::{OzMerkleTree, Proof, TreeLayout, TreeOptions};
+use super::validation::normalize_address;
+use crate::model::{Entitlements, Epoch, Expiration, PlanId, ProductId};
+
+/// Which `merkle_state` root to look up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootRef {
+    /// `merkle_state.id`
+    Id(i32),
+    /// Root hash, hex with or without `0x`. If the same root was recorded more than once,
+    /// the latest row is used.
+    Hash(String),
+}
+
+/// A past root rebuilt from its stored snapshot of plain and extension leaves
+pub struct HistoricalTree {
+    pub state_id: i32,
+    pub root_hex: String,
+    pub is_synced_on_chain: bool,
+    pub tx_signature: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+    pub tree: OzMerkleTree,
+    pub subscribers: Vec<(String, Expiration)>,
+    /// Behind the value indices after the subscribers, as in `PublishedTree`
+    pub extensions: Vec<ExtensionLeaf>,
+    /// How every leaf was built, as recorded with the root
+    pub mode: LeafMode,
+}
+
+impl HistoricalTree {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.root_hex.clone(), self.subscribers.clone())
+    }
+
+    /// The rebuilt tree with the values behind its leaves, for serving proofs
+    pub fn into_published(self) -> PublishedTree {
+        PublishedTree {
+            root_hex: self.root_hex,
+            tree: self.tree,
+            subscribers: self.subscribers,
+            extensions: self.extensions,
+            mode: self.mode,
+        }
+    }
+}
+
+/// Record a new root in `merkle_state` together with the options, leaf mode, subscribers and
+/// extension leaves it was built from, so it can be rebuilt later.
+/// Returns the new `merkle_state.id`.
+pub async fn record_merkle_state(
+    pool: &PgPool,
+    published: &PublishedTree,
+    tx_signature: Option<String>,
+) -> Result<i32> {
+    let options = published.tree.options();
+    let subscribers = &published.subscribers;
+    let is_synced = tx_signature.is_some();
+    let created_at = Utc::now().naive_utc();
+    let value_indices: Vec<i32> = (0..subscribers.len() as i32).collect();
+    let addresses: Vec<String> = subscribers.iter().map(|(a, _)| a.clone()).collect();
+    let expirations: Vec<i64> = subscribers.iter().map(|(_, e)| e.as_i64()).collect();
+    let (chain_id, contract_address) = match published.mode.deployment() {
+        Some(deployment) => (
+            Some(i64::try_from(deployment.chain_id).context("Chain id does not fit in BIGINT")?),
+            Some(format!("0x{}", hex::encode(deployment.contract.as_bytes()))),
+        ),
+        None => (None, None),
+    };
+
+    let mut tx = pool.begin().await?;
+
+    let state_id = sqlx::query_scalar!(
+        "INSERT INTO merkle_state (root_hash, is_synced_on_chain, tx_signature, created_at, tree_layout, sort_leaves, leaf_count,
+                                   chain_id, contract_address)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+         RETURNING id",
+        published.root_hex,
+        is_synced,
+        tx_signature,
+        created_at,
+        options.layout.name(),
+        options.sort_leaves,
+        i32::try_from(published.tree.leaf_count())?,
+        chain_id,
+        contract_address
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO merkle_state_subscribers (state_id, value_index, wallet_address, expiration_ts)
+         SELECT $1, * FROM UNNEST($2::integer[], $3::varchar[], $4::bigint[])",
+        state_id,
+        &value_indices,
+        &addresses,
+        &expirations
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // One array per column; only the columns of each leaf's kind are set
+    let extensions = &published.extensions;
+    let mut ext_indices = Vec::with_capacity(extensions.len());
+    let mut kinds = Vec::with_capacity(extensions.len());
+    let mut ext_addresses = Vec::with_capacity(extensions.len());
+    let mut ext_expirations = Vec::with_capacity(extensions.len());
+    let mut product_ids: Vec<Option<i32>> = vec![None; extensions.len()];
+    let mut plan_ids: Vec<Option<i32>> = vec![None; extensions.len()];
+    let mut entitlements: Vec<Option<Vec<u8>>> = vec![None; extensions.len()];
+    let mut epochs: Vec<Option<i64>> = vec![None; extensions.len()];
+    let mut credits: Vec<Option<i64>> = vec![None; extensions.len()];
+    for (i, extension) in extensions.iter().enumerate() {
+        ext_indices.push(i32::try_from(published.extension_value_index(i))?);
+        kinds.push(extension.kind_name().to_string());
+        ext_addresses.push(extension.wallet_address().to_string());
+        ext_expirations.push(extension.expiration().as_i64());
+        match extension {
+            ExtensionLeaf::Tiered(s) => {
+                plan_ids[i] = Some(i32::try_from(s.plan_id)?);
+            }
+            ExtensionLeaf::Product(s) => {
+                product_ids[i] = Some(i32::try_from(s.product_id)?);
+                plan_ids[i] = Some(i32::try_from(s.plan_id)?);
+            }
+            ExtensionLeaf::Entitled(s) => {
+                entitlements[i] = Some(s.entitlements.to_be_bytes().to_vec());
+            }
+            ExtensionLeaf::Credit(s) => {
+                epochs[i] = Some(i64::try_from(s.epoch)?);
+                credits[i] = Some(i64::try_from(s.credits)?);
+            }
+        }
+    }
+
+    sqlx::query!(
+        "INSERT INTO merkle_state_extension_leaves
+             (state_id, value_index, kind, wallet_address, expiration_ts, product_id, plan_id,
+              entitlements, epoch, credits)
+         SELECT $1, * FROM UNNEST($2::integer[], $3::varchar[], $4::varchar[], $5::bigint[],
+                                  $6::integer[], $7::integer[], $8::bytea[], $9::bigint[],
+                                  $10::bigint[])",
+        state_id,
+        &ext_indices,
+        &kinds,
+        &ext_addresses,
+        &ext_expirations,
+        &product_ids as &[Option<i32>],
+        &plan_ids as &[Option<i32>],
+        &entitlements as &[Option<Vec<u8>>],
+        &epochs as &[Option<i64>],
+        &credits as &[Option<i64>]
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(state_id)
+}
+
+/// Rebuild the tree behind a past root. Fails if the root has no stored snapshot
+/// (it was recorded before snapshots existed) or the rebuilt root does not match.
+/// A root recorded for an empty set rebuilds to an empty tree whose empty root is that root.
+pub async fn load_historical_tree(pool: &PgPool, root: &RootRef) -> Result<HistoricalTree> {
+    let (id, hash) = match root {
+        RootRef::Id(id) => (Some(*id), None),
+        RootRef::Hash(hash) => (None, Some(hash.trim_start_matches("0x").to_lowercase())),
+    };
+    let state = sqlx::query!(
+        "SELECT id, root_hash, is_synced_on_chain, tx_signature, created_at, tree_layout, sort_leaves,
+                leaf_count, chain_id, contract_address
+         FROM merkle_state
+         WHERE ($1::integer IS NULL OR id = $1)
+           AND ($2::text IS NULL OR lower(root_hash) IN ($2, '0x' || $2))
+         ORDER BY id DESC LIMIT 1",
+        id,
+        hash
+    )
+    .fetch_optional(pool)
+    .await?
+    .with_context(|| format!("No merkle_state row for {:?}", root))?;
+    let state_id = state.id;
+    let root_hash = state.root_hash;
+
+    let rows = sqlx::query!(
+        "SELECT wallet_address, expiration_ts FROM merkle_state_subscribers
+         WHERE state_id = $1 ORDER BY value_index",
+        state_id
+    )
+    .fetch_all(pool)
+    .await?;
+    let extension_rows = sqlx::query!(
+        "SELECT kind, wallet_address, expiration_ts, product_id, plan_id, entitlements,
+                epoch, credits
+         FROM merkle_state_extension_leaves
+         WHERE state_id = $1 ORDER BY value_index",
+        state_id
+    )
+    .fetch_all(pool)
+    .await?;
+    if rows.is_empty() && extension_rows.is_empty() && state.leaf_count != Some(0) {
+        return Err(anyhow::anyhow!(
+            "Root {} (merkle_state {}) has no stored subscriber snapshot",
+            root_hash,
+            state_id
+        ));
+    }
+
+    let subscribers = rows
+        .into_iter()
+        .map(|r| Ok((r.wallet_address, Expiration::from_unix(r.expiration_ts)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let extensions = extension_rows
+        .into_iter()
+        .map(|r| {
+            let context = || format!("Invalid {} leaf of {}", r.kind, r.wallet_address);
+            let expiration = Expiration::from_unix(r.expiration_ts).with_context(context)?;
+            match r.kind.as_str() {
+                "tiered" => Ok(ExtensionLeaf::Tiered(TieredSubscriber {
+                    plan_id: PlanId::try_from(r.plan_id.with_context(context)?)?,
+                    expiration,
+                    wallet_address: r.wallet_address.clone(),
+                })),
+                "product" => Ok(ExtensionLeaf::Product(ProductSubscription {
+                    product_id: ProductId::try_from(r.product_id.with_context(context)?)?,
+                    plan_id: PlanId::try_from(r.plan_id.with_context(context)?)?,
+                    expiration,
+                    wallet_address: r.wallet_address.clone(),
+                })),
+                "entitled" => Ok(ExtensionLeaf::Entitled(EntitledSubscriber {
+                    entitlements: Entitlements::from_be_slice(
+                        r.entitlements.as_deref().with_context(context)?,
+                    )?,
+                    expiration,
+                    wallet_address: r.wallet_address.clone(),
+                })),
+                "credit" => Ok(ExtensionLeaf::Credit(CreditSubscriber {
+                    epoch: Epoch::try_from(r.epoch.with_context(context)?)?,
+                    credits: u64::try_from(r.credits.with_context(context)?)?,
+                    expiration,
+                    wallet_address: r.wallet_address.clone(),
+                })),
+                other => Err(anyhow::anyhow!("Unknown extension leaf kind '{}'", other)),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mode = match (state.chain_id, state.contract_address) {
+        (Some(chain_id), Some(contract)) => {
+            LeafMode::Bound(Deployment::new(u64::try_from(chain_id)?, &contract)?)
+        }
+        _ => LeafMode::Unbound,
+    };
+
+    let mut options = TreeOptions {
+        layout: TreeLayout::from_name(&state.tree_layout)?,
+        sort_leaves: state.sort_leaves,
+        ..TreeOptions::default()
+    };
+    if subscribers.is_empty() && extensions.is_empty() {
+        let root_vec =
+            hex::decode(root_hash.trim_start_matches("0x")).context("Invalid root hex")?;
+        options.empty_root = root_vec
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Root must be 32 bytes"))?;
+    }
+    let PublishedTree {
+        root_hex,
+        tree,
+        subscribers,
+        extensions,
+        mode,
+    } = PublishedTree::build(subscribers, extensions, options, mode)?;
+    if root_hex != root_hash.trim_start_matches("0x").to_lowercase() {
+        return Err(anyhow::anyhow!(
+            "Snapshot of merkle_state {} rebuilds to root {}, not {}",
+            state_id,
+            root_hex,
+            root_hash
+        ));
+    }
+
+    Ok(HistoricalTree {
+        state_id,
+        root_hex,
+        is_synced_on_chain: state.is_synced_on_chain.unwrap_or(false),
+        tx_signature: state.tx_signature,
+        created_at: state.created_at,
+        tree,
+        subscribers,
+        extensions,
+        mode,
+    })
+}
+
+/// The most recent root that was synced on-chain, if it has a stored snapshot
+/// or was recorded for an empty set.
+pub async fn load_latest_synced_tree(pool: &PgPool) -> Result<Option<HistoricalTree>> {
+    let state_id = sqlx::query_scalar!(
+        "SELECT s.id FROM merkle_state s
+         WHERE s.is_synced_on_chain
+           AND (s.leaf_count = 0
+                OR EXISTS (SELECT 1 FROM merkle_state_subscribers m WHERE m.state_id = s.id)
+                OR EXISTS (SELECT 1 FROM merkle_state_extension_leaves e WHERE e.state_id = s.id))
+         ORDER BY s.id DESC LIMIT 1"
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    match state_id {
+        Some(id) => Ok(Some(load_historical_tree(pool, &RootRef::Id(id)).await?)),
+        None => Ok(None),
+    }
+}
+
+/// A subscriber's proof against a past root, with the expiration their leaf had then.
+/// Returns None if the subscriber was not in that root.
+/// Bound roots are rebuilt and proven in the mode they were recorded with.
+pub async fn get_historical_proof(
+    pool: &PgPool,
+    root: &RootRef,
+    user_pubkey: &str,
+) -> Result<Option<(Expiration, Proof)>> {
+    let historical = load_historical_tree(pool, root).await?;
+    Ok(historical.into_published().get_proof_for_user(user_pubkey))
+}
+
+/// A subscriber's tiered proof against a past root, with the plan and expiration their
+/// tiered leaf had then. Returns None if the subscriber was not in that root.
+pub async fn get_historical_tiered_proof(
+    pool: &PgPool,
+    root: &RootRef,
+    user_pubkey: &str,
+) -> Result<Option<(PlanId, Expiration, Proof)>> {
+    let historical = load_historical_tree(pool, root).await?;
+    let user = normalize_address(user_pubkey);
+    let published = historical.into_published();
+
+    Ok(published
+        .get_extension_proof(|extension| match extension {
+            ExtensionLeaf::Tiered(s) => normalize_address(&s.wallet_address) == user,
+            _ => false,
+        })
+        .and_then(|(extension, proof)| match extension {
+            ExtensionLeaf::Tiered(s) => Some((s.plan_id, s.expiration, proof)),
+            _ => None,
+        }))
+}
+
+/// A subscriber's proof for `product_id` against a past root, with the plan and expiration
+/// their product leaf had then. Returns None if that subscription was not in that root.
+pub async fn get_historical_product_proof(
+    pool: &PgPool,
+    root: &RootRef,
+    user_pubkey: &str,
+    product_id: ProductId,
+) -> Result<Option<(PlanId, Expiration, Proof)>> {
+    let historical = load_historical_tree(pool, root).await?;
+    let user = normalize_address(user_pubkey);
+    let published = historical.into_published();
+
+    Ok(published
+        .get_extension_proof(|extension| match extension {
+            ExtensionLeaf::Product(s) => {
+                s.product_id == product_id && normalize_address(&s.wallet_address) == user
+            }
+            _ => false,
+        })
+        .and_then(|(extension, proof)| match extension {
+            ExtensionLeaf::Product(s) => Some((s.plan_id, s.expiration, proof)),
+            _ => None,
+        }))
+}
+
+/// A subscriber's entitled proof against a past root, with the bitmap and expiration their
+/// entitled leaf had then. Returns None if they held no entitlements in that root.
+pub async fn get_historical_entitled_proof(
+    pool: &PgPool,
+    root: &RootRef,
+    user_pubkey: &str,
+) -> Result<Option<(Entitlements, Expiration, Proof)>> {
+    let historical = load_historical_tree(pool, root).await?;
+    let user = normalize_address(user_pubkey);
+    let published = historical.into_published();
+
+    Ok(published
+        .get_extension_proof(|extension| match extension {
+            ExtensionLeaf::Entitled(s) => normalize_address(&s.wallet_address) == user,
+            _ => false,
+        })
+        .and_then(|(extension, proof)| match extension {
+            ExtensionLeaf::Entitled(s) => Some((s.entitlements, s.expiration, proof)),
+            _ => None,
+        }))
+}
+
+/// A subscriber's credit proof against a past root, with the epoch, credits and expiration
+/// their credit leaf had then. Returns None if they had no credits in that root.
+pub async fn get_historical_credit_proof(
+    pool: &PgPool,
+    root: &RootRef,
+    user_pubkey: &str,
+) -> Result<Option<(Epoch, u64, Expiration, Proof)>> {
+    let historical = load_historical_tree(pool, root).await?;
+    let user = normalize_address(user_pubkey);
+    let published = historical.into_published();
+
+    Ok(published
+        .get_extension_proof(|extension| match extension {
+            ExtensionLeaf::Credit(s) => normalize_address(&s.wallet_address) == user,
+            _ => false,
+        })
+        .and_then(|(extension, proof)| match extension {
+            ExtensionLeaf::Credit(s) => Some((s.epoch, s.credits, s.expiration, proof)),
+            _ => None,
+        }))
+}
@@ -0,0 +1,481 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+
+use super::hasher::MerkleHasher;
+use super::tree::{build_standard, compute_leaf, MerkleTree, Nodes, OzMerkleTree};
+use super::validation::normalize_address;
+use crate::model::Expiration;
+
+// In-place tree updates. Every operation leaves the tree identical to a full rebuild
+// from the updated leaf list (in value order, with the same options), but only rehashes
+// the nodes whose subtree actually changed.
+//
+// Incremental updates are only cheap on trees built with `sort_leaves: false`:
+//
+// - unsorted trees: an update or a layered insert/remove rehashes one or two paths, O(log n)
+// - sorted trees: a leaf's position depends on its hash, so every leaf between the old and
+//   new sorted position shifts by one slot and has its path rehashed, O(n) in the worst case
+// - standard layout insert/remove: the OZ array shape depends on the leaf count,
+//   so these always fall back to a full rebuild, O(n)
+//
+// Trees that are meant to be updated in place should therefore use the layered layout
+// without sorting. main.rs publishes with the default (sorted) options and rebuilds from
+// the database instead. Sorted trees are still updated correctly, just not faster than a
+// rebuild.
+//
+// Removal follows `Vec::swap_remove` semantics for value indices: the last value
+// takes the removed value's index.
+
+impl<H: MerkleHasher> MerkleTree<H> {
+    /// Replace `old_leaf` with `new_leaf` and return the new root.
+    /// Returns None if `old_leaf` is not in the tree.
+    /// O(log n) once the leaf is found, unless the tree sorts its leaves.
+    pub fn update_leaf(&mut self, old_leaf: &[u8; 32], new_leaf: [u8; 32]) -> Option<[u8; 32]> {
+        let sort_leaves = self.sort_leaves;
+
+        match &mut self.nodes {
+            Nodes::Layered(layers) => {
+                let leaf_layer = &mut layers[0];
+                let len = leaf_layer.len();
+                let dirty = if sort_leaves {
+                    let p = leaf_layer.binary_search(old_leaf).ok()?;
+                    leaf_layer.remove(p);
+                    let q = leaf_layer.partition_point(|l| *l < new_leaf);
+                    leaf_layer.insert(q, new_leaf);
+                    (p.min(q)..=p.max(q)).collect()
+                } else {
+                    let p = leaf_layer.iter().position(|l| l == old_leaf)?;
+                    leaf_layer[p] = new_leaf;
+                    vec![p]
+                };
+                rehash_layered::<H>(layers, dirty, len);
+            }
+            Nodes::Standard {
+                tree,
+                tree_indices,
+                order,
+            } => {
+                let n = order.len();
+                let tree_len = tree.len();
+                let slot_index = |slot: usize| tree_len - 1 - slot;
+
+                if !sort_leaves {
+                    let p = (0..n).find(|&slot| tree[slot_index(slot)] == *old_leaf)?;
+                    tree[slot_index(p)] = new_leaf;
+                    rehash_standard::<H>(tree, [slot_index(p)]);
+                    return Some(tree[0]);
+                }
+
+                let p = partition_slots(tree, n, |l| l < old_leaf);
+                if p == n || tree[slot_index(p)] != *old_leaf {
+                    return None;
+                }
+
+                // Slot `q` the new leaf lands in once the old one is taken out
+                let below = partition_slots(tree, n, |l| *l < new_leaf);
+                let q = below - usize::from(*old_leaf < new_leaf);
+
+                let value_index = order.remove(p);
+                order.insert(q, value_index);
+                let range = p.min(q)..=p.max(q);
+                if q > p {
+                    for slot in p..q {
+                        tree[slot_index(slot)] = tree[slot_index(slot + 1)];
+                    }
+                } else {
+                    for slot in (q + 1..=p).rev() {
+                        tree[slot_index(slot)] = tree[slot_index(slot - 1)];
+                    }
+                }
+                tree[slot_index(q)] = new_leaf;
+                for slot in range.clone() {
+                    tree_indices[order[slot]] = slot_index(slot);
+                }
+
+                rehash_standard::<H>(tree, range.map(slot_index));
+            }
+        }
+
+        Some(self.root())
+    }
+
+    /// Add `leaf` to the tree and return the new root.
+    /// In the standard layout the new leaf gets the next value index.
+    /// O(log n) for unsorted layered trees, O(n) otherwise.
+    pub fn insert_leaf(&mut self, leaf: [u8; 32]) -> [u8; 32] {
+        let sort_leaves = self.sort_leaves;
+
+        match &mut self.nodes {
+            Nodes::Layered(layers) => {
+                let leaf_layer = &mut layers[0];
+                let len = leaf_layer.len();
+                let dirty = if sort_leaves {
+                    let q = leaf_layer.partition_point(|l| *l < leaf);
+                    leaf_layer.insert(q, leaf);
+                    (q..=len).collect()
+                } else {
+                    leaf_layer.push(leaf);
+                    vec![len]
+                };
+                rehash_layered::<H>(layers, dirty, len);
+            }
+            Nodes::Standard { .. } => {
+                let mut leaves = self.leaves_in_value_order();
+                leaves.push(leaf);
+                self.nodes = build_standard::<H>(&leaves, sort_leaves);
+            }
+        }
+
+        self.root()
+    }
+
+    /// Remove `leaf` from the tree and return the new root.
+    /// Removing the last leaf leaves an empty tree, whose root is the configured empty root.
+    /// Returns None if the leaf is not in the tree.
+    /// O(log n) for unsorted layered trees once the leaf is found, O(n) otherwise.
+    pub fn remove_leaf(&mut self, leaf: &[u8; 32]) -> Option<[u8; 32]> {
+        let sort_leaves = self.sort_leaves;
+
+        match &mut self.nodes {
+            Nodes::Layered(layers) => {
+                let leaf_layer = &mut layers[0];
+                let len = leaf_layer.len();
+                let dirty = if sort_leaves {
+                    let p = leaf_layer.binary_search(leaf).ok()?;
+                    leaf_layer.remove(p);
+                    (p..len).collect()
+                } else {
+                    let p = leaf_layer.iter().position(|l| l == leaf)?;
+                    leaf_layer.swap_remove(p);
+                    vec![p]
+                };
+                rehash_layered::<H>(layers, dirty, len);
+            }
+            Nodes::Standard { .. } => {
+                let mut leaves = self.leaves_in_value_order();
+                let value_index = leaves.iter().position(|l| l == leaf)?;
+                leaves.swap_remove(value_index);
+                self.nodes = build_standard::<H>(&leaves, sort_leaves);
+            }
+        }
+
+        Some(self.root())
+    }
+
+    fn leaves_in_value_order(&self) -> Vec<[u8; 32]> {
+        match &self.nodes {
+            Nodes::Layered(layers) => layers[0].clone(),
+            Nodes::Standard {
+                tree, tree_indices, ..
+            } => tree_indices.iter().map(|&i| tree[i]).collect(),
+        }
+    }
+}
+
+/// Number of leading slots (in sorted order) whose leaf satisfies `pred`
+fn partition_slots(tree: &[[u8; 32]], n: usize, pred: impl Fn(&[u8; 32]) -> bool) -> usize {
+    let (mut lo, mut hi) = (0, n);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if pred(&tree[tree.len() - 1 - mid]) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+/// Recompute the layers above the `dirty` leaf positions after layers[0] changed.
+/// `old_len` is the leaf count before the change; a different count may add or drop
+/// the last node of each layer, and layers at the top.
+fn rehash_layered<H: MerkleHasher>(
+    layers: &mut Vec<Vec<[u8; 32]>>,
+    mut dirty: Vec<usize>,
+    old_len: usize,
+) {
+    let new_len = layers[0].len();
+    if new_len != old_len {
+        dirty.push(old_len.max(new_len) - 1);
+    }
+
+    let mut level = 0;
+    while layers[level].len() > 1 {
+        let child_len = layers[level].len();
+        let parent_len = child_len.div_ceil(2);
+
+        for d in dirty.iter_mut() {
+            *d /= 2;
+        }
+        dirty.sort_unstable();
+        dirty.dedup();
+
+        if layers.len() == level + 1 {
+            layers.push(Vec::new());
+        }
+        let (children, parents) = layers.split_at_mut(level + 1);
+        let children = &children[level];
+        let parents = &mut parents[0];
+        parents.resize(parent_len, [0u8; 32]);
+
+        // Indices past the end stay in `dirty`: they still mark the last node of higher layers
+        for &j in dirty.iter().filter(|&&j| j < parent_len) {
+            parents[j] = if 2 * j + 1 < child_len {
+                H::hash_pair(children[2 * j], children[2 * j + 1])
+            } else {
+                // Odd node: promote it up
+                children[2 * j]
+            };
+        }
+
+        level += 1;
+    }
+
+    layers.truncate(level + 1);
+}
+
+/// Recompute every ancestor of the `dirty` tree indices in a standard-layout array.
+fn rehash_standard<H: MerkleHasher>(tree: &mut [[u8; 32]], dirty: impl IntoIterator<Item = usize>) {
+    let mut dirty: BTreeSet<usize> = dirty.into_iter().collect();
+
+    // Children always sit at higher indices than their parent, so draining from the top
+    // rehashes both children of a node before the node itself
+    while let Some(index) = dirty.pop_last() {
+        if index == 0 {
+            break;
+        }
+        let parent = (index - 1) / 2;
+        tree[parent] = H::hash_pair(tree[2 * parent + 1], tree[2 * parent + 2]);
+        dirty.insert(parent);
+    }
+}
+
+// ───────────────────────────────────────────────────
+// Subscriber-level helpers
+// ───────────────────────────────────────────────────
+
+/// Change a subscriber's expiration in place and return the new root.
+pub fn renew_subscriber(
+    tree: &mut OzMerkleTree,
+    subscribers: &mut [(String, Expiration)],
+    user_pubkey: &str,
+    new_expiration: Expiration,
+) -> Result<[u8; 32]> {
+    let entry = subscribers
+        .iter_mut()
+        .find(|(pk, _)| pk == user_pubkey)
+        .with_context(|| format!("Subscriber {} is not in the tree", user_pubkey))?;
+
+    let old_leaf = compute_leaf(user_pubkey, entry.1)?;
+    let new_leaf = compute_leaf(user_pubkey, new_expiration)?;
+    let root = tree
+        .update_leaf(&old_leaf, new_leaf)
+        .context("Subscriber leaf is missing from the tree")?;
+
+    entry.1 = new_expiration;
+    Ok(root)
+}
+
+/// Add a new subscriber and return the new root.
+/// The subscriber is appended, so `subscribers` keeps matching the tree's value order.
+/// On sorted trees the root then equals a rebuild from the database, which lists wallets
+/// in `wallet_address` order; unsorted trees only match a rebuild from `subscribers`.
+pub fn add_subscriber(
+    tree: &mut OzMerkleTree,
+    subscribers: &mut Vec<(String, Expiration)>,
+    user_pubkey: &str,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    // Compared case-insensitively: a case variant would otherwise be a second leaf
+    // for the same wallet, or the very same leaf if the expiration matches too
+    let user = normalize_address(user_pubkey);
+    if subscribers
+        .iter()
+        .any(|(pk, _)| normalize_address(pk) == user)
+    {
+        return Err(anyhow::anyhow!(
+            "Subscriber {} is already in the tree",
+            user_pubkey
+        ));
+    }
+
+    let leaf = compute_leaf(user_pubkey, expiration)?;
+    let root = tree.insert_leaf(leaf);
+
+    subscribers.push((user_pubkey.to_string(), expiration));
+    Ok(root)
+}
+
+/// Remove a subscriber and return the new root.
+/// The last subscriber takes the removed one's place, mirroring the tree's value order.
+pub fn remove_subscriber(
+    tree: &mut OzMerkleTree,
+    subscribers: &mut Vec<(String, Expiration)>,
+    user_pubkey: &str,
+) -> Result<[u8; 32]> {
+    let index = subscribers
+        .iter()
+        .position(|(pk, _)| pk == user_pubkey)
+        .with_context(|| format!("Subscriber {} is not in the tree", user_pubkey))?;
+
+    let leaf = compute_leaf(user_pubkey, subscribers[index].1)?;
+    let root = tree
+        .remove_leaf(&leaf)
+        .context("Subscriber leaf is missing from the tree")?;
+
+    subscribers.swap_remove(index);
+    Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::hasher::Keccak256Hasher;
+    use crate::merkle::tree::{TreeLayout, TreeOptions};
+
+    fn leaf(i: u32) -> [u8; 32] {
+        Keccak256Hasher::hash(&i.to_be_bytes())
+    }
+
+    fn all_options() -> Vec<TreeOptions> {
+        let mut options = Vec::new();
+        for layout in [TreeLayout::Layered, TreeLayout::Standard] {
+            for sort_leaves in [true, false] {
+                options.push(TreeOptions {
+                    layout,
+                    sort_leaves,
+                    ..TreeOptions::default()
+                });
+            }
+        }
+        options
+    }
+
+    /// Compare every node, not just the root, with a tree rebuilt from `leaves`
+    fn assert_rebuilt(tree: &OzMerkleTree, leaves: &[[u8; 32]], options: TreeOptions) {
+        let rebuilt = OzMerkleTree::from_leaves_with_options(leaves, options);
+        assert_eq!(
+            tree.root(),
+            rebuilt.root(),
+            "{:?}, {} leaves",
+            options,
+            leaves.len()
+        );
+        assert_eq!(tree.layers(), rebuilt.layers());
+        assert_eq!(tree.standard_tree(), rebuilt.standard_tree());
+        for value_index in 0..leaves.len() {
+            assert_eq!(
+                tree.tree_index(value_index),
+                rebuilt.tree_index(value_index)
+            );
+        }
+    }
+
+    #[test]
+    fn update_equals_rebuild() {
+        for options in all_options() {
+            for n in 1..=9 {
+                let mut leaves: Vec<_> = (0..n).map(leaf).collect();
+                let mut tree = OzMerkleTree::from_leaves_with_options(&leaves, options);
+                for (i, replacement) in (100..100 + n).enumerate() {
+                    let root = tree.update_leaf(&leaves[i], leaf(replacement)).unwrap();
+                    leaves[i] = leaf(replacement);
+                    assert_eq!(root, tree.root());
+                    assert_rebuilt(&tree, &leaves, options);
+                }
+                assert!(tree.update_leaf(&leaf(0), leaf(1)).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn insert_equals_rebuild() {
+        for options in all_options() {
+            let mut leaves = Vec::new();
+            let mut tree = OzMerkleTree::from_leaves_with_options(&leaves, options);
+            for i in 0..12 {
+                let root = tree.insert_leaf(leaf(i));
+                leaves.push(leaf(i));
+                assert_eq!(root, tree.root());
+                assert_rebuilt(&tree, &leaves, options);
+            }
+        }
+    }
+
+    #[test]
+    fn remove_equals_rebuild() {
+        for options in all_options() {
+            let mut leaves: Vec<_> = (0..12).map(leaf).collect();
+            let mut tree = OzMerkleTree::from_leaves_with_options(&leaves, options);
+            // Remove from the front, middle and back in turn
+            for step in 0..12 {
+                let index = [0, leaves.len() / 2, leaves.len() - 1][step % 3];
+                let removed = leaves.swap_remove(index);
+                let root = tree.remove_leaf(&removed).unwrap();
+                assert_eq!(root, tree.root());
+                assert_rebuilt(&tree, &leaves, options);
+            }
+            assert!(tree.is_empty());
+            assert!(tree.remove_leaf(&leaf(0)).is_none());
+        }
+    }
+
+    #[test]
+    fn subscriber_helpers_keep_value_order() {
+        let subscriber = |i: u8| {
+            let address = format!("0x{}", hex::encode([0xa0 + i; 20]));
+            (address, Expiration::new(1_700_000_000).unwrap())
+        };
+        let options = TreeOptions {
+            sort_leaves: false,
+            ..TreeOptions::default()
+        };
+        let leaves_of = |subscribers: &[(String, Expiration)]| {
+            subscribers
+                .iter()
+                .map(|(address, exp)| compute_leaf(address, *exp).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let mut subscribers: Vec<_> = (1..=4).map(subscriber).collect();
+        let mut tree = OzMerkleTree::from_leaves_with_options(&leaves_of(&subscribers), options);
+
+        let (renewed, _) = subscriber(2);
+        let later = Expiration::new(1_800_000_000).unwrap();
+        renew_subscriber(&mut tree, &mut subscribers, &renewed, later).unwrap();
+        assert_rebuilt(&tree, &leaves_of(&subscribers), options);
+
+        let (added, exp) = subscriber(5);
+        add_subscriber(&mut tree, &mut subscribers, &added, exp).unwrap();
+        let upper_case = format!("0x{}", added[2..].to_uppercase());
+        assert!(add_subscriber(&mut tree, &mut subscribers, &upper_case, exp).is_err());
+        assert_rebuilt(&tree, &leaves_of(&subscribers), options);
+
+        let (removed, _) = subscriber(1);
+        remove_subscriber(&mut tree, &mut subscribers, &removed).unwrap();
+        assert_eq!(subscribers[0].0, added);
+        assert_rebuilt(&tree, &leaves_of(&subscribers), options);
+    }
+
+    #[test]
+    fn added_subscriber_matches_a_database_rebuild_on_sorted_trees() {
+        // The database lists wallets in wallet_address order, not in insertion order
+        let exp = Expiration::new(1_700_000_000).unwrap();
+        let address = |i: u8| format!("0x{}", hex::encode([0xa0 + i; 20]));
+        let options = TreeOptions::default();
+
+        let mut subscribers: Vec<_> = [3, 1].iter().map(|&i| (address(i), exp)).collect();
+        let leaves: Vec<_> = subscribers
+            .iter()
+            .map(|(address, exp)| compute_leaf(address, *exp).unwrap())
+            .collect();
+        let mut tree = OzMerkleTree::from_leaves_with_options(&leaves, options);
+        let root = add_subscriber(&mut tree, &mut subscribers, &address(2), exp).unwrap();
+
+        let rebuilt: Vec<_> = (1..=3)
+            .map(|i| compute_leaf(&address(i), exp).unwrap())
+            .collect();
+        let rebuilt = OzMerkleTree::from_leaves_with_options(&rebuilt, options);
+        assert_eq!(root, rebuilt.root());
+    }
+}
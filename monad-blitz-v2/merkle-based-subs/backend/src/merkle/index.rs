@@ -0,0 +1,315 @@
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::tree::{compute_leaf_with, ensure_multi_proof_layout, MerkleTree, MultiProof, Proof};
+use super::validation::normalize_address;
+use crate::model::Expiration;
+
+/// A value behind a leaf that a `ProofIndex` can look up, e.g. a subscriber.
+pub trait IndexedValue {
+    /// What the value is looked up by, e.g. the normalized wallet address
+    type Key: Eq + Hash;
+
+    fn key(&self) -> Self::Key;
+
+    /// The leaf committing this value
+    fn leaf<H: MerkleHasher>(&self) -> Result<[u8; 32]>;
+}
+
+/// Plain subscriber leaves, looked up by normalized address
+impl IndexedValue for (String, Expiration) {
+    type Key = String;
+
+    fn key(&self) -> String {
+        normalize_address(&self.0)
+    }
+
+    fn leaf<H: MerkleHasher>(&self) -> Result<[u8; 32]> {
+        compute_leaf_with::<H>(&self.0, self.1)
+    }
+}
+
+/// A built tree with lookup maps from leaf hash and from value key to leaf position,
+/// so proofs are served in O(log n) without scanning the leaves or the values.
+///
+/// Works for every leaf shape: `V` is the value behind each leaf, e.g. `(String, Expiration)`
+/// for plain subscriber leaves. Positions are those of `MerkleTree::leaf_positions`.
+/// The index owns the tree and values, so it cannot go stale; rebuild it after an
+/// incremental update.
+pub struct ProofIndex<V: IndexedValue = (String, Expiration), H: MerkleHasher = Keccak256Hasher> {
+    root_hex: String,
+    tree: MerkleTree<H>,
+    values: Vec<V>,
+    by_leaf: HashMap<[u8; 32], usize>,
+    /// Value key -> (leaf position, index into `values`)
+    by_key: HashMap<V::Key, (usize, usize)>,
+}
+
+impl<V: IndexedValue + Sync, H: MerkleHasher> ProofIndex<V, H> {
+    /// Index a tree built from `values`, e.g. the output of `build_tree_from_db`.
+    pub fn new((root_hex, tree, values): (String, MerkleTree<H>, Vec<V>)) -> Result<Self> {
+        let leaves = values
+            .par_iter()
+            .map(|value| value.leaf::<H>())
+            .collect::<Result<Vec<_>>>()?;
+        Self::with_leaves((root_hex, tree, values), &leaves)
+    }
+}
+
+impl<V: IndexedValue, H: MerkleHasher> ProofIndex<V, H> {
+    /// Index a tree whose leaves were not computed with `IndexedValue::leaf`, e.g. leaves
+    /// bound to a deployment. `leaves[i]` must be the leaf behind `values[i]`.
+    pub fn with_leaves(
+        (root_hex, tree, values): (String, MerkleTree<H>, Vec<V>),
+        leaves: &[[u8; 32]],
+    ) -> Result<Self> {
+        if leaves.len() != values.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} leaves, got {}",
+                values.len(),
+                leaves.len()
+            ));
+        }
+
+        let mut by_leaf = HashMap::with_capacity(tree.leaf_count());
+        for (position, leaf) in tree.leaf_positions() {
+            // Equal leaves prove identically, so the first position is as good as any
+            by_leaf.entry(leaf).or_insert(position);
+        }
+
+        let mut by_key = HashMap::with_capacity(values.len());
+        for (value_index, (value, leaf)) in values.iter().zip(leaves).enumerate() {
+            let position = match tree.tree_index(value_index) {
+                Some(tree_index) => tree_index,
+                None => *by_leaf
+                    .get(leaf)
+                    .with_context(|| format!("Value {} is not in the tree", value_index))?,
+            };
+            by_key.entry(value.key()).or_insert((position, value_index));
+        }
+
+        Ok(ProofIndex {
+            root_hex,
+            tree,
+            values,
+            by_leaf,
+            by_key,
+        })
+    }
+
+    pub fn root_hex(&self) -> &str {
+        &self.root_hex
+    }
+
+    pub fn tree(&self) -> &MerkleTree<H> {
+        &self.tree
+    }
+
+    /// The values behind the leaves, in value order
+    pub fn values(&self) -> &[V] {
+        &self.values
+    }
+
+    /// Give back the tree and values, e.g. to apply incremental updates
+    pub fn into_parts(self) -> (String, MerkleTree<H>, Vec<V>) {
+        (self.root_hex, self.tree, self.values)
+    }
+
+    /// Leaf position of a leaf hash
+    pub fn position_of_leaf(&self, leaf: &[u8; 32]) -> Option<usize> {
+        self.by_leaf.get(leaf).copied()
+    }
+
+    /// Leaf position of the value with `key`
+    pub fn position_of(&self, key: &V::Key) -> Option<usize> {
+        self.by_key.get(key).map(|&(position, _)| position)
+    }
+
+    /// The value with `key`
+    pub fn value(&self, key: &V::Key) -> Option<&V> {
+        self.by_key
+            .get(key)
+            .map(|&(_, value_index)| &self.values[value_index])
+    }
+
+    /// Proof for the leaf at `position`
+    pub fn get_proof_at(&self, position: usize) -> Option<Proof<H>> {
+        self.tree.get_proof_at(position)
+    }
+
+    /// Proof for a leaf hash
+    pub fn get_proof(&self, leaf: &[u8; 32]) -> Option<Proof<H>> {
+        self.get_proof_at(self.position_of_leaf(leaf)?)
+    }
+
+    /// Proof for the value with `key`, together with the value, which the verifier needs
+    pub fn get_proof_for(&self, key: &V::Key) -> Option<(&V, Proof<H>)> {
+        let &(position, value_index) = self.by_key.get(key)?;
+        let proof = self.get_proof_at(position)?;
+        Some((&self.values[value_index], proof))
+    }
+
+    /// Multiproof for the values with `keys`; the tree must use `TreeLayout::Standard`.
+    pub fn get_multi_proof_for(&self, keys: &[V::Key]) -> Result<MultiProof<H>> {
+        ensure_multi_proof_layout(self.tree.layout())?;
+
+        let positions = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                self.position_of(key)
+                    .with_context(|| format!("Requested value {} is not in the tree", i))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.tree
+            .get_multi_proof_at(&positions)
+            .context("Every value of a multiproof must be requested once")
+    }
+}
+
+/// Lookups by wallet address, for values keyed by normalized address
+impl<V: IndexedValue<Key = String>, H: MerkleHasher> ProofIndex<V, H> {
+    /// Leaf position of a subscriber, matched case-insensitively with or without `0x`
+    pub fn position_of_user(&self, user_pubkey: &str) -> Option<usize> {
+        self.position_of(&normalize_address(user_pubkey))
+    }
+
+    /// The value behind an address
+    pub fn subscriber(&self, user_pubkey: &str) -> Option<&V> {
+        self.value(&normalize_address(user_pubkey))
+    }
+
+    /// Indexed counterpart of `get_multi_proof_for_users`, with the same errors:
+    /// the tree must use `TreeLayout::Standard`.
+    pub fn get_multi_proof_for_users(&self, user_pubkeys: &[&str]) -> Result<MultiProof<H>> {
+        ensure_multi_proof_layout(self.tree.layout())?;
+
+        let positions = user_pubkeys
+            .iter()
+            .map(|user_pubkey| {
+                self.position_of_user(user_pubkey)
+                    .with_context(|| format!("Subscriber {} is not in the tree", user_pubkey))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.tree
+            .get_multi_proof_at(&positions)
+            .context("Every subscriber of a multiproof must be requested once")
+    }
+}
+
+impl<H: MerkleHasher> ProofIndex<(String, Expiration), H> {
+    /// The plain subscribers behind the leaves
+    pub fn subscribers(&self) -> &[(String, Expiration)] {
+        &self.values
+    }
+
+    /// Indexed counterpart of `get_proof_for_user`.
+    /// Also returns the expiration the proof is for, which the verifier needs.
+    pub fn get_proof_for_user(&self, user_pubkey: &str) -> Option<(Expiration, Proof<H>)> {
+        let (&(_, expiration), proof) = self.get_proof_for(&normalize_address(user_pubkey))?;
+        Some((expiration, proof))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::encoding::LeafEncoding;
+    use crate::merkle::tree::{get_proof_for_user, TreeLayout, TreeOptions};
+    use ethers::abi::{Address, Token};
+    use ethers::types::U256;
+
+    fn address(i: u8) -> String {
+        format!("0x{}", hex::encode([0xa0 + i; 20]))
+    }
+
+    /// A wallet holding several products, as an extended leaf shape would commit it
+    #[derive(Debug, Clone, PartialEq)]
+    struct Holding {
+        wallet_address: String,
+        product: u32,
+    }
+
+    impl IndexedValue for Holding {
+        type Key = (String, u32);
+
+        fn key(&self) -> (String, u32) {
+            (normalize_address(&self.wallet_address), self.product)
+        }
+
+        fn leaf<H: MerkleHasher>(&self) -> Result<[u8; 32]> {
+            let address: Address = self.wallet_address.parse()?;
+            LeafEncoding::new(&["address", "uint256"])?.leaf_hash_with::<H>(&[
+                Token::Address(address),
+                Token::Uint(U256::from(self.product)),
+            ])
+        }
+    }
+
+    fn build<V: IndexedValue>(values: Vec<V>, layout: TreeLayout) -> (String, MerkleTree, Vec<V>) {
+        let leaves: Vec<_> = values
+            .iter()
+            .map(|v| v.leaf::<Keccak256Hasher>().unwrap())
+            .collect();
+        let options = TreeOptions {
+            layout,
+            ..TreeOptions::default()
+        };
+        let tree = MerkleTree::from_leaves_with_options(&leaves, options);
+        (hex::encode(tree.root()), tree, values)
+    }
+
+    #[test]
+    fn indexed_proofs_equal_scanned_proofs() {
+        let subscribers: Vec<(String, Expiration)> = (0..7)
+            .map(|i| (address(i), Expiration::new(u64::from(i)).unwrap()))
+            .collect();
+
+        for layout in [TreeLayout::Layered, TreeLayout::Standard] {
+            let index = ProofIndex::new(build(subscribers.clone(), layout)).unwrap();
+            for (user, expiration) in &subscribers {
+                let scanned = get_proof_for_user(index.tree(), &subscribers, user).unwrap();
+                let upper_case = format!("0x{}", user[2..].to_uppercase());
+                assert_eq!(
+                    index.get_proof_for_user(&upper_case),
+                    Some((*expiration, scanned))
+                );
+            }
+            assert_eq!(index.get_proof_for_user(&address(9)), None);
+        }
+    }
+
+    #[test]
+    fn indexes_any_leaf_shape() {
+        // Two products per wallet: the key tells them apart
+        let holdings: Vec<Holding> = (0..4)
+            .flat_map(|i| {
+                (1..=2).map(move |product| Holding {
+                    wallet_address: address(i),
+                    product,
+                })
+            })
+            .collect();
+
+        let index = ProofIndex::new(build(holdings.clone(), TreeLayout::Standard)).unwrap();
+        for h in &holdings {
+            let (value, proof) = index.get_proof_for(&h.key()).unwrap();
+            assert_eq!(value, h);
+            let scanned = index
+                .tree()
+                .get_proof(&h.leaf::<Keccak256Hasher>().unwrap())
+                .unwrap();
+            assert_eq!(proof, scanned);
+        }
+
+        let keys: Vec<_> = holdings[..3].iter().map(IndexedValue::key).collect();
+        let multi = index.get_multi_proof_for(&keys).unwrap();
+        assert!(MerkleTree::<Keccak256Hasher>::verify_multi(
+            &index.tree().root(),
+            &multi
+        ));
+    }
+}
@@ -1,4 +1,25 @@
+pub mod credits;
+pub mod deployment;
+pub mod diff;
+pub mod dump;
+pub mod export;
+pub mod encoding;
+pub mod entitlements;
 pub mod generator;
+pub mod hasher;
+pub mod history;
 pub mod ethereum_client;
+pub mod incremental;
+pub mod index;
+pub mod parallel;
+pub mod positional;
+pub mod products;
+pub mod published;
+pub mod render;
+pub mod sparse;
+pub mod stream;
+pub mod tiers;
 pub mod tree;
 pub mod updatestate;
+pub mod validation;
+pub mod vectors;
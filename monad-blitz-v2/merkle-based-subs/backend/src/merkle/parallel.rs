@@ -0,0 +1,190 @@
+use anyhow::Result;
+use rayon::prelude::*;
+use sqlx::PgPool;
+
+use super::hasher::MerkleHasher;
+use super::tree::{
+    build_standard, check_unique_leaves, compute_leaf, load_subscribers, BuiltTree, MerkleTree,
+    Nodes, OzMerkleTree, TreeLayout, TreeOptions,
+};
+use crate::model::Expiration;
+
+// Multi-threaded tree construction. Leaves are hashed, sorted and combined across all cores
+// with rayon; the output is identical to the serial builders in `tree`.
+
+impl<H: MerkleHasher> MerkleTree<H> {
+    /// Same as `from_leaves_with_options`, but sorts the leaves and hashes every layer in parallel.
+    pub fn from_leaves_parallel(leaves: &[[u8; 32]], options: TreeOptions) -> Self {
+        let nodes = match options.layout {
+            TreeLayout::Layered => {
+                Nodes::Layered(build_layers_parallel::<H>(leaves, options.sort_leaves))
+            }
+            TreeLayout::Standard => build_standard_parallel::<H>(leaves, options.sort_leaves),
+        };
+
+        Self::from_nodes(nodes, options)
+    }
+}
+
+fn build_layers_parallel<H: MerkleHasher>(
+    leaves: &[[u8; 32]],
+    sort_leaves: bool,
+) -> Vec<Vec<[u8; 32]>> {
+    let mut leaf_layer = leaves.to_vec();
+    if sort_leaves {
+        // Equal leaves are identical bytes, so an unstable sort gives the same layer
+        leaf_layer.par_sort_unstable();
+    }
+
+    let mut layers = vec![leaf_layer];
+    while layers.last().unwrap().len() > 1 {
+        let next_layer = layers
+            .last()
+            .unwrap()
+            .par_chunks(2)
+            .map(|chunk| {
+                if chunk.len() == 2 {
+                    H::hash_pair(chunk[0], chunk[1])
+                } else {
+                    // Odd node: promote it up
+                    chunk[0]
+                }
+            })
+            .collect();
+        layers.push(next_layer);
+    }
+
+    layers
+}
+
+/// Parallel `build_standard`: all internal nodes at one depth of the OZ array are independent,
+/// so each depth is hashed in parallel, deepest first.
+fn build_standard_parallel<H: MerkleHasher>(leaves: &[[u8; 32]], sort_leaves: bool) -> Nodes {
+    let n = leaves.len();
+    if n == 0 {
+        return build_standard::<H>(leaves, sort_leaves);
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    if sort_leaves {
+        // Stable, like the serial build, so equal leaves keep their value order
+        order.par_sort_by(|&a, &b| leaves[a].cmp(&leaves[b]));
+    }
+
+    let tree_len = 2 * n - 1;
+    let internal = n - 1;
+    let mut tree = vec![[0u8; 32]; tree_len];
+    let mut tree_indices = vec![0usize; n];
+    for (i, &value_index) in order.iter().enumerate() {
+        tree[tree_len - 1 - i] = leaves[value_index];
+        tree_indices[value_index] = tree_len - 1 - i;
+    }
+
+    if internal > 0 {
+        // Node i sits at depth floor(log2(i + 1))
+        let max_depth = (internal as u64).ilog2();
+        for depth in (0..=max_depth).rev() {
+            let start = (1usize << depth) - 1;
+            let end = ((1usize << (depth + 1)) - 1).min(internal);
+
+            // Children of this depth all sit at or after `end`
+            let (upper, lower) = tree.split_at_mut(end);
+            upper[start..end]
+                .par_iter_mut()
+                .enumerate()
+                .for_each(|(k, node)| {
+                    let i = start + k;
+                    *node = H::hash_pair(lower[2 * i + 1 - end], lower[2 * i + 2 - end]);
+                });
+        }
+    }
+
+    Nodes::Standard {
+        tree,
+        tree_indices,
+        order,
+    }
+}
+
+/// Compute subscriber leaves in parallel, in the same order as `subscribers`.
+pub fn compute_leaves_parallel(subscribers: &[(String, Expiration)]) -> Result<Vec<[u8; 32]>> {
+    subscribers
+        .par_iter()
+        .map(|(address, exp)| compute_leaf(address, *exp))
+        .collect()
+}
+
+/// Parallel counterpart of `build_tree_from_db_with_options`, with identical output.
+/// Hashing runs on rayon's pool via `spawn_blocking`, off the async runtime's workers.
+pub async fn build_tree_from_db_parallel(pool: &PgPool, options: TreeOptions) -> Result<BuiltTree> {
+    let subscribers = load_subscribers(pool).await?;
+
+    tokio::task::spawn_blocking(move || {
+        let leaves = compute_leaves_parallel(&subscribers)?;
+        check_unique_leaves(&leaves, &subscribers)?;
+        let tree = OzMerkleTree::from_leaves_parallel(&leaves, options);
+        Ok((hex::encode(tree.root()), tree, subscribers))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::hasher::{Keccak256Hasher, Sha256Hasher};
+    use crate::merkle::tree::compute_leaf_with;
+
+    fn assert_parallel_equals_serial<H: MerkleHasher>(leaves: &[[u8; 32]]) {
+        for layout in [TreeLayout::Layered, TreeLayout::Standard] {
+            for sort_leaves in [true, false] {
+                let options = TreeOptions {
+                    layout,
+                    sort_leaves,
+                    ..TreeOptions::default()
+                };
+                let serial = MerkleTree::<H>::from_leaves_with_options(leaves, options);
+                let parallel = MerkleTree::<H>::from_leaves_parallel(leaves, options);
+                assert_eq!(parallel.root(), serial.root(), "{:?}", options);
+                assert_eq!(parallel.layers(), serial.layers());
+                assert_eq!(parallel.standard_tree(), serial.standard_tree());
+                for value_index in 0..leaves.len() {
+                    assert_eq!(
+                        parallel.tree_index(value_index),
+                        serial.tree_index(value_index)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_equals_serial() {
+        // Sizes around powers of two, where the shape of both layouts changes
+        for n in [0u32, 1, 2, 3, 4, 5, 7, 8, 9, 31, 32, 33, 1000] {
+            let leaves: Vec<_> = (0..n)
+                .map(|i| Keccak256Hasher::hash(&i.to_be_bytes()))
+                .collect();
+            assert_parallel_equals_serial::<Keccak256Hasher>(&leaves);
+            assert_parallel_equals_serial::<Sha256Hasher>(&leaves);
+        }
+
+        // Duplicate leaves keep their value order in both builds
+        let leaf = Keccak256Hasher::hash(b"duplicate");
+        assert_parallel_equals_serial::<Keccak256Hasher>(&[leaf, [0u8; 32], leaf]);
+    }
+
+    #[test]
+    fn parallel_leaves_equal_serial_leaves() {
+        let subscribers: Vec<(String, Expiration)> = (0..100u8)
+            .map(|i| {
+                let address = format!("0x{}", hex::encode([i; 20]));
+                (address, Expiration::new(u64::from(i) * 86_400).unwrap())
+            })
+            .collect();
+        let serial = subscribers
+            .iter()
+            .map(|(address, exp)| compute_leaf_with::<Keccak256Hasher>(address, *exp))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(compute_leaves_parallel(&subscribers).unwrap(), serial);
+    }
+}
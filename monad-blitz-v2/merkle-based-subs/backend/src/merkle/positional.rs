@@ -0,0 +1,310 @@
+use anyhow::{Context, Result};
+use sqlx::PgPool;
+use std::marker::PhantomData;
+
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::tree::{compute_leaf_with, load_subscribers};
+use crate::model::Expiration;
+
+// Positional Merkle tree: children are hashed in left/right order (`H::hash_ordered`),
+// never sorted, so a proof pins the leaf to one slot. The leaf count is padded with
+// `EMPTY_SLOT` up to a power of two, which gives every proof the same length and lets a
+// verifier read the path straight from the bits of the leaf index:
+//
+//   bit i of leaf_index == 0  ->  node at height i is a left child:  parent = H(node, sibling)
+//   bit i of leaf_index == 1  ->  node at height i is a right child: parent = H(sibling, node)
+//
+// This is the shape most non-OZ verifiers expect, e.g. a Solidity loop over `proof` that
+// shifts `index` right by one per level.
+
+/// Leaf value of the padding slots past the last real leaf
+pub const EMPTY_SLOT: [u8; 32] = [0u8; 32];
+
+/// Proof that a leaf sits at `leaf_index` of a `PositionalTree`.
+/// `siblings[0]` is the sibling of the leaf, the last one a child of the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PositionalProof<H: MerkleHasher = Keccak256Hasher> {
+    pub leaf_index: u64,
+    pub siblings: Vec<[u8; 32]>,
+    _hasher: PhantomData<H>,
+}
+
+impl<H: MerkleHasher> PositionalProof<H> {
+    /// Wrap a proof received from elsewhere, asserting it was produced with `H`
+    pub fn new(leaf_index: u64, siblings: Vec<[u8; 32]>) -> Self {
+        PositionalProof {
+            leaf_index,
+            siblings,
+            _hasher: PhantomData,
+        }
+    }
+
+    /// Direction bitmap: bit i is set when the path node at height i is a right child.
+    /// For a padded tree this is the leaf index itself.
+    pub fn directions(&self) -> u64 {
+        self.leaf_index
+    }
+
+    /// Per-level directions, leaf first: true when the path node is a right child.
+    /// Levels past the 64 bits of the index are left children.
+    pub fn direction_bits(&self) -> Vec<bool> {
+        (0..self.siblings.len())
+            .map(|height| {
+                let shift = u32::try_from(height).unwrap_or(u32::MAX);
+                self.leaf_index.checked_shr(shift).unwrap_or(0) & 1 == 1
+            })
+            .collect()
+    }
+}
+
+/// Ordered (unsorted-pair) Merkle tree over leaves in input order.
+pub struct PositionalTree<H: MerkleHasher = Keccak256Hasher> {
+    /// layers[0] = leaves padded to a power of two, layers[last] = [root]
+    layers: Vec<Vec<[u8; 32]>>,
+    leaf_count: usize,
+    _hasher: PhantomData<H>,
+}
+
+impl<H: MerkleHasher> PositionalTree<H> {
+    /// Build the tree with `leaves[i]` at slot `i`.
+    /// Without leaves the tree is a single `EMPTY_SLOT`, which is also its root.
+    pub fn from_leaves(leaves: &[[u8; 32]]) -> Self {
+        let mut leaf_layer = leaves.to_vec();
+        leaf_layer.resize(leaves.len().next_power_of_two(), EMPTY_SLOT);
+
+        let mut layers = vec![leaf_layer];
+        while layers.last().unwrap().len() > 1 {
+            let next_layer = layers
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|chunk| H::hash_ordered(chunk[0], chunk[1]))
+                .collect();
+            layers.push(next_layer);
+        }
+
+        PositionalTree {
+            layers,
+            leaf_count: leaves.len(),
+            _hasher: PhantomData,
+        }
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.layers.last().unwrap()[0]
+    }
+
+    /// Number of real leaves, not counting padding
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    /// Proof length: log2 of the padded leaf count
+    pub fn depth(&self) -> usize {
+        self.layers.len() - 1
+    }
+
+    pub fn leaf(&self, index: usize) -> Option<[u8; 32]> {
+        if index >= self.leaf_count {
+            return None;
+        }
+        Some(self.layers[0][index])
+    }
+
+    /// Proof for the leaf at slot `index`. Returns None past the last real leaf.
+    pub fn get_proof(&self, index: usize) -> Option<PositionalProof<H>> {
+        self.leaf(index)?;
+
+        let siblings = self.layers[..self.depth()]
+            .iter()
+            .enumerate()
+            .map(|(height, layer)| layer[(index >> height) ^ 1])
+            .collect();
+
+        Some(PositionalProof::new(index as u64, siblings))
+    }
+
+    /// Recompute the root from a leaf and its proof.
+    /// Returns None if the index does not fit in a tree of the proof's depth, or if the proof
+    /// is deeper than the 64 levels a `u64` index can address.
+    pub fn compute_root(proof: &PositionalProof<H>, leaf: &[u8; 32]) -> Option<[u8; 32]> {
+        let depth = proof.siblings.len();
+        if depth > 64 || (depth < 64 && proof.leaf_index >> depth != 0) {
+            return None;
+        }
+
+        let mut computed = *leaf;
+        for (sibling, is_right) in proof.siblings.iter().zip(proof.direction_bits()) {
+            computed = if is_right {
+                H::hash_ordered(*sibling, computed)
+            } else {
+                H::hash_ordered(computed, *sibling)
+            };
+        }
+        Some(computed)
+    }
+
+    /// Off-chain verification that `leaf` sits at `proof.leaf_index` under `root`.
+    pub fn verify(root: &[u8; 32], proof: &PositionalProof<H>, leaf: &[u8; 32]) -> bool {
+        Self::compute_root(proof, leaf) == Some(*root)
+    }
+
+    /// Like `verify`, but also require the leaf to sit at `expected_index`,
+    /// as a contract enforcing per-slot data would.
+    pub fn verify_at(
+        root: &[u8; 32],
+        proof: &PositionalProof<H>,
+        leaf: &[u8; 32],
+        expected_index: u64,
+    ) -> bool {
+        proof.leaf_index == expected_index && Self::verify(root, proof, leaf)
+    }
+}
+
+// ───────────────────────────────────────────────────
+// Subscriber-level API
+// ───────────────────────────────────────────────────
+
+/// Build a positional tree from `subscriber_storage`. Subscriber `i` (sorted by
+/// wallet_address, as `load_subscribers` returns them) occupies slot `i`.
+pub async fn build_positional_tree_from_db<H: MerkleHasher>(
+    pool: &PgPool,
+) -> Result<(String, PositionalTree<H>, Vec<(String, Expiration)>)> {
+    let subscribers = load_subscribers(pool).await?;
+
+    let leaves = subscribers
+        .iter()
+        .map(|(address, exp)| {
+            compute_leaf_with::<H>(address, *exp)
+                .with_context(|| format!("Invalid wallet address for subscriber {}", address))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let tree = PositionalTree::<H>::from_leaves(&leaves);
+    Ok((hex::encode(tree.root()), tree, subscribers))
+}
+
+/// Get a positional proof for a user, carrying the user's slot.
+pub fn get_positional_proof_for_user<H: MerkleHasher>(
+    tree: &PositionalTree<H>,
+    subscribers: &[(String, Expiration)],
+    user_pubkey: &str,
+) -> Option<PositionalProof<H>> {
+    let index = subscribers.iter().position(|(pk, _)| pk == user_pubkey)?;
+    tree.get_proof(index)
+}
+
+/// Off-chain verification of a positional subscription proof.
+/// Pass `expected_index` to also check the subscriber's slot.
+pub fn verify_positional_subscription<H: MerkleHasher>(
+    root_hex: &str,
+    proof: &PositionalProof<H>,
+    user_pubkey: &str,
+    expiration_ts: Expiration,
+    expected_index: Option<u64>,
+) -> Result<bool> {
+    let root_vec = hex::decode(root_hex).context("Invalid root hex")?;
+    let root: [u8; 32] = root_vec
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Root must be 32 bytes"))?;
+
+    let leaf = compute_leaf_with::<H>(user_pubkey, expiration_ts)?;
+
+    Ok(match expected_index {
+        Some(index) => PositionalTree::<H>::verify_at(&root, proof, &leaf, index),
+        None => PositionalTree::<H>::verify(&root, proof, &leaf),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Tree = PositionalTree<Keccak256Hasher>;
+
+    fn leaves(n: u8) -> Vec<[u8; 32]> {
+        (0..n).map(|i| Keccak256Hasher::hash(&[i])).collect()
+    }
+
+    #[test]
+    fn proofs_round_trip_at_their_slot() {
+        for n in 1..=9 {
+            let leaves = leaves(n);
+            let tree = Tree::from_leaves(&leaves);
+            assert_eq!(
+                tree.depth(),
+                usize::from(n).next_power_of_two().trailing_zeros() as usize
+            );
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.get_proof(index).unwrap();
+                assert_eq!(proof.siblings.len(), tree.depth());
+                assert!(Tree::verify(&tree.root(), &proof, leaf));
+                assert!(Tree::verify_at(&tree.root(), &proof, leaf, index as u64));
+                assert!(!Tree::verify_at(
+                    &tree.root(),
+                    &proof,
+                    leaf,
+                    index as u64 + 1
+                ));
+            }
+            assert!(tree.get_proof(leaves.len()).is_none());
+        }
+    }
+
+    #[test]
+    fn tampered_proofs_fail() {
+        let leaves = leaves(5);
+        let tree = Tree::from_leaves(&leaves);
+        let root = tree.root();
+        let proof = tree.get_proof(2).unwrap();
+
+        // Another slot of the same depth
+        let moved = PositionalProof::new(3, proof.siblings.clone());
+        assert!(!Tree::verify(&root, &moved, &leaves[2]));
+
+        // An index past the proof's depth
+        let outside = PositionalProof::new(1 << tree.depth(), proof.siblings.clone());
+        assert_eq!(Tree::compute_root(&outside, &leaves[2]), None);
+
+        // A changed sibling
+        let mut siblings = proof.siblings.clone();
+        siblings[1][0] ^= 1;
+        assert!(!Tree::verify(
+            &root,
+            &PositionalProof::new(2, siblings),
+            &leaves[2]
+        ));
+
+        // A dropped or an extra level
+        let mut shorter = proof.siblings.clone();
+        shorter.pop();
+        assert!(!Tree::verify(
+            &root,
+            &PositionalProof::new(2, shorter),
+            &leaves[2]
+        ));
+        let mut longer = proof.siblings.clone();
+        longer.push(EMPTY_SLOT);
+        assert!(!Tree::verify(
+            &root,
+            &PositionalProof::new(2, longer),
+            &leaves[2]
+        ));
+    }
+
+    #[test]
+    fn proofs_deeper_than_the_index_are_rejected() {
+        let leaf = leaves(1)[0];
+        let full = PositionalProof::<Keccak256Hasher>::new(u64::MAX, vec![EMPTY_SLOT; 64]);
+        assert!(Tree::compute_root(&full, &leaf).is_some());
+        assert!(full.direction_bits().iter().all(|&is_right| is_right));
+
+        for depth in [65, 80] {
+            let proof = PositionalProof::<Keccak256Hasher>::new(u64::MAX, vec![EMPTY_SLOT; depth]);
+            assert_eq!(Tree::compute_root(&proof, &leaf), None);
+            let bits = proof.direction_bits();
+            assert_eq!(bits.len(), depth);
+            assert!(bits[64..].iter().all(|&is_right| !is_right));
+        }
+    }
+}
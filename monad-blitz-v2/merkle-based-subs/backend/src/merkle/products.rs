@@ -0,0 +1,269 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use ethers::abi::Token;
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use super::encoding::{LeafEncoding, LeafKind};
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::index::IndexedValue;
+use super::tree::{
+    check_unique_leaves, get_proof_for_value, subscriber_values, verify_value, MerkleTree, Proof,
+    TreeOptions,
+};
+use super::validation::normalize_address;
+use crate::model::{Expiration, PlanId, Product, ProductId, DEFAULT_PRODUCT};
+
+// Product leaves commit which product a subscription is for, so a single root covers every
+// (wallet, product) pair in `subscriber_storage`:
+//
+//   leaf = keccak256(bytes.concat(keccak256(abi.encode(
+//              uint8(1), address, uint256 productId, uint256 planId, uint256 expiration))))
+//
+// The leading `LeafKind::Product` keeps product leaves apart from credit leaves, which
+// have the same types after it.
+// A proof for one product never verifies for another, even for the same wallet and plan.
+
+/// Leaf encoding of product leaves: `abi.encode(uint8, address, uint256, uint256, uint256)`
+pub const PRODUCT_LEAF_ENCODING: [&str; 5] = ["uint8", "address", "uint256", "uint256", "uint256"];
+
+/// One (wallet, product) subscription, as committed in its leaf
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProductSubscription {
+    pub wallet_address: String,
+    pub product_id: ProductId,
+    pub plan_id: PlanId,
+    pub expiration: Expiration,
+}
+
+/// Product leaves are looked up by (normalized address, product)
+impl IndexedValue for ProductSubscription {
+    type Key = (String, ProductId);
+
+    fn key(&self) -> (String, ProductId) {
+        (normalize_address(&self.wallet_address), self.product_id)
+    }
+
+    fn leaf<H: MerkleHasher>(&self) -> Result<[u8; 32]> {
+        compute_product_leaf_with::<H>(
+            &self.wallet_address,
+            self.product_id,
+            self.plan_id,
+            self.expiration,
+        )
+    }
+}
+
+/// The `(uint8, address, uint256, uint256, uint256)` encoding of product leaves
+pub fn product_encoding() -> LeafEncoding {
+    LeafEncoding::new(&PRODUCT_LEAF_ENCODING).expect("valid Solidity types")
+}
+
+/// Leaf values `(LeafKind::Product, address, productId, planId, expiration)`,
+/// in `product_encoding()` order
+pub fn product_values(
+    address_hex: &str,
+    product_id: ProductId,
+    plan_id: PlanId,
+    expiration: Expiration,
+) -> Result<Vec<Token>> {
+    let mut values = subscriber_values(address_hex, expiration)?;
+    values.insert(0, LeafKind::Product.token());
+    values.insert(2, Token::Uint(U256::from(product_id)));
+    values.insert(3, Token::Uint(U256::from(plan_id)));
+    Ok(values)
+}
+
+/// Product leaf matching the contract's `keccak256(bytes.concat(keccak256(abi.encode(
+/// uint8(1), address, productId, planId, expiration))))`
+pub fn compute_product_leaf(
+    address_hex: &str,
+    product_id: ProductId,
+    plan_id: PlanId,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    compute_product_leaf_with::<Keccak256Hasher>(address_hex, product_id, plan_id, expiration)
+}
+
+/// `compute_product_leaf` with any hasher
+pub fn compute_product_leaf_with<H: MerkleHasher>(
+    address_hex: &str,
+    product_id: ProductId,
+    plan_id: PlanId,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    let values = product_values(address_hex, product_id, plan_id, expiration)?;
+    product_encoding().leaf_hash_with::<H>(&values)
+}
+
+// ───────────────────────────────────────────────────
+// Products and subscriptions in the database
+// ───────────────────────────────────────────────────
+
+/// Add a product and return its id
+pub async fn create_product(pool: &PgPool, name: &str) -> Result<ProductId> {
+    let id = sqlx::query_scalar!("INSERT INTO products (name) VALUES ($1) RETURNING id", name)
+        .fetch_one(pool)
+        .await
+        .with_context(|| format!("Failed to create product '{}'", name))?;
+    Ok(ProductId::try_from(id)?)
+}
+
+pub async fn load_products(pool: &PgPool) -> Result<Vec<Product>> {
+    let products = sqlx::query_as!(
+        Product,
+        "SELECT id, name, created_at FROM products ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(products)
+}
+
+/// Create or replace the subscription of a wallet to a product
+pub async fn upsert_product_subscription(
+    pool: &PgPool,
+    subscription: &ProductSubscription,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO subscriber_storage (wallet_address, product_id, plan_id, expiration_ts, last_updated_at)
+         VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (wallet_address, product_id)
+         DO UPDATE SET plan_id = $3, expiration_ts = $4, last_updated_at = $5",
+        subscription.wallet_address,
+        i32::try_from(subscription.product_id)?,
+        i32::try_from(subscription.plan_id)?,
+        subscription.expiration.as_i64(),
+        Utc::now().naive_utc()
+    )
+    .execute(pool)
+    .await
+    .with_context(|| {
+        format!(
+            "Failed to store subscription of {} to product {}",
+            subscription.wallet_address, subscription.product_id
+        )
+    })?;
+    Ok(())
+}
+
+/// Load every subscription of every product, sorted by (wallet_address, product_id).
+pub async fn load_product_subscriptions(pool: &PgPool) -> Result<Vec<ProductSubscription>> {
+    query_product_subscriptions(pool, None).await
+}
+
+/// Load the `DEFAULT_PRODUCT` subscriptions of `default_wallets`, e.g. the rows validation
+/// accepted, sorted by wallet_address. Subscriptions to other products are validated with
+/// the rest of `subscriber_storage` instead (see `validation::validate_product_rows`).
+pub async fn load_default_subscriptions_for(
+    pool: &PgPool,
+    default_wallets: &[String],
+) -> Result<Vec<ProductSubscription>> {
+    query_product_subscriptions(pool, Some(default_wallets)).await
+}
+
+async fn query_product_subscriptions(
+    pool: &PgPool,
+    default_wallets: Option<&[String]>,
+) -> Result<Vec<ProductSubscription>> {
+    let rows = sqlx::query!(
+        "SELECT wallet_address, product_id, plan_id, expiration_ts FROM subscriber_storage
+         WHERE $1::varchar[] IS NULL OR (product_id = $2 AND wallet_address = ANY($1))",
+        default_wallets as Option<&[String]>,
+        DEFAULT_PRODUCT as i32
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut subscriptions = rows
+        .into_iter()
+        .map(|row| {
+            let context = || {
+                format!(
+                    "Invalid subscription of {} to product {}",
+                    row.wallet_address, row.product_id
+                )
+            };
+            Ok(ProductSubscription {
+                product_id: ProductId::try_from(row.product_id).with_context(context)?,
+                plan_id: PlanId::try_from(row.plan_id).with_context(context)?,
+                expiration: Expiration::from_unix(row.expiration_ts).with_context(context)?,
+                wallet_address: row.wallet_address,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    subscriptions
+        .sort_by(|a, b| (&a.wallet_address, a.product_id).cmp(&(&b.wallet_address, b.product_id)));
+    Ok(subscriptions)
+}
+
+/// Build one tree over the subscriptions of all products.
+/// `subscriptions[i]` is the value behind the i-th leaf, as for `build_tree_from_db`.
+pub async fn build_product_tree_from_db<H: MerkleHasher>(
+    pool: &PgPool,
+    options: TreeOptions,
+) -> Result<(String, MerkleTree<H>, Vec<ProductSubscription>)> {
+    let subscriptions = load_product_subscriptions(pool).await?;
+
+    let leaves = subscriptions
+        .iter()
+        .map(|s| {
+            compute_product_leaf_with::<H>(&s.wallet_address, s.product_id, s.plan_id, s.expiration)
+                .with_context(|| {
+                    format!("Invalid wallet address for subscriber {}", s.wallet_address)
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let labels: Vec<(String, Expiration)> = subscriptions
+        .iter()
+        .map(|s| {
+            let label = format!("{} (product {})", s.wallet_address, s.product_id);
+            (label, s.expiration)
+        })
+        .collect();
+    check_unique_leaves(&leaves, &labels)?;
+
+    let tree = MerkleTree::<H>::from_leaves_with_options(&leaves, options);
+    Ok((hex::encode(tree.root()), tree, subscriptions))
+}
+
+/// Get the proof of a user's subscription to `product_id`, with the plan and expiration
+/// it proves. Returns None if the user has no subscription to that product.
+/// Scans `subscriptions` and the leaves; a `ProofIndex<ProductSubscription, H>` serves
+/// repeated lookups without scanning.
+pub fn get_product_proof_for_user<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscriptions: &[ProductSubscription],
+    user_pubkey: &str,
+    product_id: ProductId,
+) -> Option<(PlanId, Expiration, Proof<H>)> {
+    let user = normalize_address(user_pubkey);
+    let subscription = subscriptions
+        .iter()
+        .find(|s| s.product_id == product_id && normalize_address(&s.wallet_address) == user)?;
+
+    let values = product_values(
+        &subscription.wallet_address,
+        subscription.product_id,
+        subscription.plan_id,
+        subscription.expiration,
+    )
+    .ok()?;
+    let proof = get_proof_for_value(tree, &product_encoding(), &values)?;
+    Some((subscription.plan_id, subscription.expiration, proof))
+}
+
+/// Off-chain verification that the user holds `plan_id` of `product_id` until `expiration_ts`.
+pub fn verify_product_subscription<H: MerkleHasher>(
+    root_hex: &str,
+    proof: &Proof<H>,
+    user_pubkey: &str,
+    product_id: ProductId,
+    plan_id: PlanId,
+    expiration_ts: Expiration,
+) -> Result<bool> {
+    let values = product_values(user_pubkey, product_id, plan_id, expiration_ts)?;
+    verify_value(root_hex, proof, &product_encoding(), &values)
+}
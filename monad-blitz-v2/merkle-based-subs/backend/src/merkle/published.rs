@@ -0,0 +1,570 @@
+use anyhow::{Context, Result};
+use ethers::abi::Token;
+use sqlx::PgPool;
+
+use super::credits::{
+    credit_encoding, credit_values, current_credit_epoch, load_credit_subscribers_for,
+    CreditSubscriber,
+};
+use super::deployment::{compute_leaf_in_mode, LeafMode};
+use super::encoding::LeafEncoding;
+use super::entitlements::{
+    entitled_encoding, entitled_values, load_entitled_subscribers_for, EntitledSubscriber,
+};
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::products::{
+    load_default_subscriptions_for, product_encoding, product_values, ProductSubscription,
+};
+use super::tiers::{tiered_encoding, tiered_values, TieredSubscriber};
+use super::tree::{check_unique_leaves, MerkleTree, Proof, TreeOptions};
+use super::validation::{
+    normalize_address, validate_and_quarantine, ValidationPolicy, ValidationReport,
+};
+use crate::model::{Entitlements, Epoch, Expiration};
+
+// The root main publishes commits every leaf shape at once, so one on-chain root serves
+// every kind of proof:
+//
+//   value indices 0..n   plain leaves (address, expiration) of the DEFAULT_PRODUCT
+//                        subscribers, which the deployed verifySubscription(proof, expiration)
+//                        checks
+//   value indices n..    extension leaves, in `extensions` order:
+//                        - a tiered leaf (address, planId, expiration) for every
+//                          DEFAULT_PRODUCT subscriber, which verifySubscription(proof, planId,
+//                          expiration) checks
+//                        - a product leaf (uint8(1), address, productId, planId, expiration)
+//                          for every subscription of every product
+//                        - an entitled leaf (uint8(2), address, entitlements, expiration) for
+//                          every subscriber holding at least one entitlement
+//                        - a credit leaf (uint8(3), address, epoch, credits, expiration) for
+//                          every subscriber with credits in the open credit epoch
+//
+// Keeping the plain leaves first means value index i is still `subscribers[i]`, so
+// `export_proofs`, `get_proof_for_user` and `MerkleTree::tree_index` work unchanged.
+// Plain and tiered leaves never equal another shape: their encodings differ in length. The
+// other extension leaves start with their `LeafKind`, so no two shapes share an encoding.
+// Every leaf is built in the tree's `LeafMode`, so a bound root binds all of its shapes.
+
+/// A leaf of the published root other than a plain subscriber leaf
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtensionLeaf {
+    Tiered(TieredSubscriber),
+    Product(ProductSubscription),
+    Entitled(EntitledSubscriber),
+    Credit(CreditSubscriber),
+}
+
+impl ExtensionLeaf {
+    /// Name stored in `merkle_state_extension_leaves.kind`
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            ExtensionLeaf::Tiered(_) => "tiered",
+            ExtensionLeaf::Product(_) => "product",
+            ExtensionLeaf::Entitled(_) => "entitled",
+            ExtensionLeaf::Credit(_) => "credit",
+        }
+    }
+
+    pub fn wallet_address(&self) -> &str {
+        match self {
+            ExtensionLeaf::Tiered(s) => &s.wallet_address,
+            ExtensionLeaf::Product(s) => &s.wallet_address,
+            ExtensionLeaf::Entitled(s) => &s.wallet_address,
+            ExtensionLeaf::Credit(s) => &s.wallet_address,
+        }
+    }
+
+    pub fn expiration(&self) -> Expiration {
+        match self {
+            ExtensionLeaf::Tiered(s) => s.expiration,
+            ExtensionLeaf::Product(s) => s.expiration,
+            ExtensionLeaf::Entitled(s) => s.expiration,
+            ExtensionLeaf::Credit(s) => s.expiration,
+        }
+    }
+
+    /// The encoding of the unbound leaf
+    pub fn encoding(&self) -> LeafEncoding {
+        match self {
+            ExtensionLeaf::Tiered(_) => tiered_encoding(),
+            ExtensionLeaf::Product(_) => product_encoding(),
+            ExtensionLeaf::Entitled(_) => entitled_encoding(),
+            ExtensionLeaf::Credit(_) => credit_encoding(),
+        }
+    }
+
+    /// The values of the unbound leaf, in `encoding()` order
+    pub fn values(&self) -> Result<Vec<Token>> {
+        match self {
+            ExtensionLeaf::Tiered(s) => tiered_values(&s.wallet_address, s.plan_id, s.expiration),
+            ExtensionLeaf::Product(s) => {
+                product_values(&s.wallet_address, s.product_id, s.plan_id, s.expiration)
+            }
+            ExtensionLeaf::Entitled(s) => {
+                entitled_values(&s.wallet_address, s.entitlements, s.expiration)
+            }
+            ExtensionLeaf::Credit(s) => {
+                credit_values(&s.wallet_address, s.epoch, s.credits, s.expiration)
+            }
+        }
+    }
+
+    /// The leaf committing this value in `mode`
+    pub fn leaf_in_mode<H: MerkleHasher>(&self, mode: &LeafMode) -> Result<[u8; 32]> {
+        mode.leaf_hash_with::<H>(&self.encoding(), self.values()?)
+    }
+
+    /// How duplicate-leaf errors name this value
+    fn label(&self) -> String {
+        match self {
+            ExtensionLeaf::Tiered(s) => format!("{} (plan {})", s.wallet_address, s.plan_id),
+            ExtensionLeaf::Product(s) => format!("{} (product {})", s.wallet_address, s.product_id),
+            ExtensionLeaf::Entitled(s) => format!("{} (entitlements)", s.wallet_address),
+            ExtensionLeaf::Credit(s) => {
+                format!("{} (credits of epoch {})", s.wallet_address, s.epoch)
+            }
+        }
+    }
+}
+
+/// The tree behind a published root, with the values behind its leaves
+pub struct PublishedTree<H: MerkleHasher = Keccak256Hasher> {
+    pub root_hex: String,
+    pub tree: MerkleTree<H>,
+    /// Plain subscribers, behind value indices `0..subscribers.len()`
+    pub subscribers: Vec<(String, Expiration)>,
+    /// Behind the value indices after the subscribers, in order
+    pub extensions: Vec<ExtensionLeaf>,
+    /// How every leaf was built
+    pub mode: LeafMode,
+}
+
+impl<H: MerkleHasher> PublishedTree<H> {
+    /// Build the published tree over `subscribers` followed by `extensions`, with every
+    /// leaf built in `mode`. Fails if two values produce the same leaf.
+    pub fn build(
+        subscribers: Vec<(String, Expiration)>,
+        extensions: Vec<ExtensionLeaf>,
+        options: TreeOptions,
+        mode: LeafMode,
+    ) -> Result<Self> {
+        let mut leaves = subscribers
+            .iter()
+            .map(|(address, exp)| compute_leaf_in_mode::<H>(&mode, address, *exp))
+            .collect::<Result<Vec<_>>>()?;
+        for extension in &extensions {
+            let leaf = extension
+                .leaf_in_mode::<H>(&mode)
+                .with_context(|| format!("Invalid extension leaf {}", extension.label()))?;
+            leaves.push(leaf);
+        }
+
+        let labels: Vec<(String, Expiration)> = subscribers
+            .iter()
+            .cloned()
+            .chain(extensions.iter().map(|e| (e.label(), e.expiration())))
+            .collect();
+        check_unique_leaves(&leaves, &labels)?;
+
+        let tree = MerkleTree::<H>::from_leaves_with_options(&leaves, options);
+        Ok(PublishedTree {
+            root_hex: hex::encode(tree.root()),
+            tree,
+            subscribers,
+            extensions,
+            mode,
+        })
+    }
+
+    /// Value index of `extensions[i]`
+    pub fn extension_value_index(&self, i: usize) -> usize {
+        self.subscribers.len() + i
+    }
+
+    /// Proof of a subscriber's plain leaf, with the expiration it proves.
+    /// Returns None if the user is not in `subscribers`.
+    pub fn get_proof_for_user(&self, user_pubkey: &str) -> Option<(Expiration, Proof<H>)> {
+        let user = normalize_address(user_pubkey);
+        let (address, exp) = self
+            .subscribers
+            .iter()
+            .find(|(address, _)| normalize_address(address) == user)?;
+        let leaf = compute_leaf_in_mode::<H>(&self.mode, address, *exp).ok()?;
+        Some((*exp, self.tree.get_proof(&leaf)?))
+    }
+
+    /// Proof of the first extension leaf matching `predicate`, with that leaf's value
+    pub fn get_extension_proof(
+        &self,
+        predicate: impl Fn(&ExtensionLeaf) -> bool,
+    ) -> Option<(&ExtensionLeaf, Proof<H>)> {
+        let extension = self.extensions.iter().find(|e| predicate(e))?;
+        let leaf = extension.leaf_in_mode::<H>(&self.mode).ok()?;
+        Some((extension, self.tree.get_proof(&leaf)?))
+    }
+
+    /// Tiered subscribers committed in this root
+    pub fn tiered_subscribers(&self) -> Vec<TieredSubscriber> {
+        self.extensions
+            .iter()
+            .filter_map(|extension| match extension {
+                ExtensionLeaf::Tiered(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Product subscriptions committed in this root
+    pub fn product_subscriptions(&self) -> Vec<ProductSubscription> {
+        self.extensions
+            .iter()
+            .filter_map(|extension| match extension {
+                ExtensionLeaf::Product(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Subscribers whose credits are committed in this root
+    pub fn credit_subscribers(&self) -> Vec<CreditSubscriber> {
+        self.extensions
+            .iter()
+            .filter_map(|extension| match extension {
+                ExtensionLeaf::Credit(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Subscribers whose entitlements are committed in this root
+    pub fn entitled_subscribers(&self) -> Vec<EntitledSubscriber> {
+        self.extensions
+            .iter()
+            .filter_map(|extension| match extension {
+                ExtensionLeaf::Entitled(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Every extension leaf to publish next to `subscribers`: the tiered leaves of the
+/// `DEFAULT_PRODUCT` subscriptions, product leaves, entitled leaves, then the credit leaves of
+/// the open credit epoch, if one was ever started.
+/// Only rows `report` accepted become leaves: `DEFAULT_PRODUCT` rows of the accepted
+/// wallets and the validated subscriptions to other products, so rows that validation
+/// quarantined stay out of every leaf shape.
+pub async fn load_extension_leaves(
+    pool: &PgPool,
+    report: &ValidationReport,
+) -> Result<Vec<ExtensionLeaf>> {
+    let accepted: Vec<String> = report.accepted.iter().map(|(a, _)| a.clone()).collect();
+
+    // Default subscriptions are both tiered and product leaves; other products come from
+    // the validated report, so a malformed row is quarantined instead of failing the build
+    let mut subscriptions = load_default_subscriptions_for(pool, &accepted).await?;
+    let mut extensions: Vec<ExtensionLeaf> = subscriptions
+        .iter()
+        .map(|s| {
+            ExtensionLeaf::Tiered(TieredSubscriber {
+                wallet_address: s.wallet_address.clone(),
+                plan_id: s.plan_id,
+                expiration: s.expiration,
+            })
+        })
+        .collect();
+    subscriptions.extend(report.products.iter().cloned());
+    subscriptions
+        .sort_by(|a, b| (&a.wallet_address, a.product_id).cmp(&(&b.wallet_address, b.product_id)));
+    extensions.extend(subscriptions.into_iter().map(ExtensionLeaf::Product));
+    extensions.extend(
+        load_entitled_subscribers_for(pool, &accepted)
+            .await?
+            .into_iter()
+            .filter(|s| s.entitlements != Entitlements::NONE)
+            .map(ExtensionLeaf::Entitled),
+    );
+    if let Some(epoch) = current_credit_epoch(pool).await? {
+        let epoch = Epoch::try_from(epoch.epoch)?;
+        extensions.extend(
+            load_credit_subscribers_for(pool, epoch, &accepted)
+                .await?
+                .into_iter()
+                .map(ExtensionLeaf::Credit),
+        );
+    }
+    Ok(extensions)
+}
+
+/// Validate `subscriber_storage` like `build_tree_from_db_validated`, then build the tree
+/// main publishes over the accepted subscribers and every extension leaf, in `mode`.
+pub async fn build_published_tree_from_db(
+    pool: &PgPool,
+    options: TreeOptions,
+    mode: LeafMode,
+    policy: ValidationPolicy,
+) -> Result<(PublishedTree, ValidationReport)> {
+    let report = validate_and_quarantine(pool, policy).await?;
+    let extensions = load_extension_leaves(pool, &report).await?;
+    let published = PublishedTree::build(report.accepted.clone(), extensions, options, mode)?;
+    Ok((published, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::credits::{
+        compute_credit_leaf, get_credit_proof_for_user, verify_credit_subscription,
+    };
+    use crate::merkle::deployment::{
+        verify_subscription_in_mode, verify_value_in_mode, Deployment,
+    };
+    use crate::merkle::entitlements::compute_entitled_leaf;
+    use crate::merkle::entitlements::{get_entitled_proof_for_user, verify_entitled_subscription};
+    use crate::merkle::products::{
+        compute_product_leaf, get_product_proof_for_user, verify_product_subscription,
+    };
+    use crate::merkle::tiers::{
+        compute_tiered_leaf, get_tiered_proof_for_user, verify_tiered_subscription,
+    };
+    use crate::merkle::tree::{
+        compute_leaf_with, get_proof_for_user, verify_subscription, TreeLayout,
+    };
+    use crate::model::DEFAULT_PRODUCT;
+
+    fn address(i: u8) -> String {
+        format!("0x{}", hex::encode([0xa0 + i; 20]))
+    }
+
+    fn published(layout: TreeLayout) -> PublishedTree {
+        let expiration = Expiration::new(1_700_000_000).unwrap();
+        let subscribers: Vec<_> = (0..3).map(|i| (address(i), expiration)).collect();
+        let mut extensions: Vec<_> = (0..3)
+            .map(|i| {
+                ExtensionLeaf::Tiered(TieredSubscriber {
+                    wallet_address: address(i),
+                    plan_id: u32::from(i),
+                    expiration,
+                })
+            })
+            .collect();
+        extensions.extend((0..3).flat_map(|i| {
+            [DEFAULT_PRODUCT, 7].map(|product_id| {
+                ExtensionLeaf::Product(ProductSubscription {
+                    wallet_address: address(i),
+                    product_id,
+                    plan_id: u32::from(i),
+                    expiration,
+                })
+            })
+        }));
+        // Bitmaps 1 and 2 equal plan ids 1 and 2 as uint256s
+        extensions.extend((1..3).map(|i| {
+            ExtensionLeaf::Entitled(EntitledSubscriber {
+                wallet_address: address(i),
+                entitlements: Entitlements::NONE.with(i - 1),
+                expiration,
+            })
+        }));
+        // Epoch 7 and credits i match product 7 and plan i
+        extensions.extend((0..3).map(|i| {
+            ExtensionLeaf::Credit(CreditSubscriber {
+                wallet_address: address(i),
+                epoch: 7,
+                credits: u64::from(i),
+                expiration,
+            })
+        }));
+        let options = TreeOptions {
+            layout,
+            sort_leaves: false,
+            ..TreeOptions::default()
+        };
+        PublishedTree::build(subscribers, extensions, options, LeafMode::Unbound).unwrap()
+    }
+
+    #[test]
+    fn every_leaf_shape_proves_against_the_published_root() {
+        for layout in [TreeLayout::Layered, TreeLayout::Standard] {
+            let published = published(layout);
+            assert_eq!(published.tree.leaf_count(), 17);
+
+            for (user, expiration) in &published.subscribers {
+                let proof =
+                    get_proof_for_user(&published.tree, &published.subscribers, user).unwrap();
+                assert!(
+                    verify_subscription(&published.root_hex, &proof, user, *expiration).unwrap()
+                );
+            }
+
+            let tiered = published.tiered_subscribers();
+            assert_eq!(tiered.len(), 3);
+            for s in &tiered {
+                let (plan_id, expiration, proof) =
+                    get_tiered_proof_for_user(&published.tree, &tiered, &s.wallet_address).unwrap();
+                assert!(verify_tiered_subscription(
+                    &published.root_hex,
+                    &proof,
+                    &s.wallet_address,
+                    plan_id,
+                    expiration,
+                )
+                .unwrap());
+            }
+
+            let subscriptions = published.product_subscriptions();
+            assert_eq!(subscriptions.len(), 6);
+            for s in &subscriptions {
+                let (plan_id, expiration, proof) = get_product_proof_for_user(
+                    &published.tree,
+                    &subscriptions,
+                    &s.wallet_address,
+                    s.product_id,
+                )
+                .unwrap();
+                assert!(verify_product_subscription(
+                    &published.root_hex,
+                    &proof,
+                    &s.wallet_address,
+                    s.product_id,
+                    plan_id,
+                    expiration,
+                )
+                .unwrap());
+            }
+
+            let entitled = published.entitled_subscribers();
+            assert_eq!(entitled.len(), 2);
+            for s in &entitled {
+                let (entitlements, expiration, proof) =
+                    get_entitled_proof_for_user(&published.tree, &entitled, &s.wallet_address)
+                        .unwrap();
+                assert!(verify_entitled_subscription(
+                    &published.root_hex,
+                    &proof,
+                    &s.wallet_address,
+                    entitlements,
+                    expiration,
+                    Entitlements::NONE,
+                )
+                .unwrap());
+            }
+
+            let credited = published.credit_subscribers();
+            assert_eq!(credited.len(), 3);
+            for s in &credited {
+                let (epoch, credits, expiration, proof) =
+                    get_credit_proof_for_user(&published.tree, &credited, &s.wallet_address)
+                        .unwrap();
+                assert!(verify_credit_subscription(
+                    &published.root_hex,
+                    &proof,
+                    &s.wallet_address,
+                    epoch,
+                    credits,
+                    expiration,
+                )
+                .unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn leaf_kinds_never_collide() {
+        // Same types and values after the kind: only the kind tells the leaves apart
+        let expiration = Expiration::new(1_700_000_000).unwrap();
+        assert_ne!(
+            compute_product_leaf(&address(0), 4, 5, expiration).unwrap(),
+            compute_credit_leaf(&address(0), 4, 5, expiration).unwrap()
+        );
+        // A tiered leaf and an entitled leaf with the same number differ in length
+        assert_ne!(
+            compute_tiered_leaf(&address(0), 1, expiration).unwrap(),
+            compute_entitled_leaf(&address(0), Entitlements::NONE.with(0), expiration).unwrap()
+        );
+    }
+
+    #[test]
+    fn plain_leaves_keep_their_value_indices() {
+        let published = published(TreeLayout::Standard);
+        let plain_only = PublishedTree::<Keccak256Hasher>::build(
+            published.subscribers.clone(),
+            Vec::new(),
+            published.tree.options(),
+            LeafMode::Unbound,
+        )
+        .unwrap();
+
+        assert_eq!(published.extension_value_index(0), 3);
+        for (value_index, (address, expiration)) in published.subscribers.iter().enumerate() {
+            let leaf = compute_leaf_with::<Keccak256Hasher>(address, *expiration).unwrap();
+            let tree_index = published.tree.tree_index(value_index).unwrap();
+            assert_eq!(published.tree.leaf_at(tree_index), Some(leaf));
+        }
+        assert_ne!(published.root_hex, plain_only.root_hex);
+    }
+
+    #[test]
+    fn duplicate_extension_leaves_are_rejected() {
+        let s = ProductSubscription {
+            wallet_address: address(0),
+            product_id: 1,
+            plan_id: 1,
+            expiration: Expiration::new(1).unwrap(),
+        };
+        let extensions = vec![ExtensionLeaf::Product(s.clone()), ExtensionLeaf::Product(s)];
+        let result = PublishedTree::<Keccak256Hasher>::build(
+            Vec::new(),
+            extensions,
+            TreeOptions::default(),
+            LeafMode::Unbound,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn bound_roots_bind_every_leaf_shape() {
+        let unbound = published(TreeLayout::Standard);
+        let deployment =
+            Deployment::new(10_143, "0x1111111111111111111111111111111111111111").unwrap();
+        let mode = LeafMode::Bound(deployment);
+        let bound = PublishedTree::<Keccak256Hasher>::build(
+            unbound.subscribers.clone(),
+            unbound.extensions.clone(),
+            unbound.tree.options(),
+            mode,
+        )
+        .unwrap();
+        assert_ne!(bound.root_hex, unbound.root_hex);
+
+        let (user, _) = &bound.subscribers[0];
+        let (expiration, proof) = bound.get_proof_for_user(user).unwrap();
+        assert!(
+            verify_subscription_in_mode(&bound.root_hex, &proof, user, expiration, &mode).unwrap()
+        );
+        assert!(!verify_subscription_in_mode(
+            &bound.root_hex,
+            &proof,
+            user,
+            expiration,
+            &LeafMode::Unbound
+        )
+        .unwrap());
+
+        for extension in &bound.extensions {
+            let (found, proof) = bound.get_extension_proof(|e| e == extension).unwrap();
+            let verify = |mode: &LeafMode| {
+                verify_value_in_mode(
+                    &bound.root_hex,
+                    &proof,
+                    mode,
+                    &found.encoding(),
+                    found.values().unwrap(),
+                )
+                .unwrap()
+            };
+            assert!(verify(&mode));
+            assert!(!verify(&LeafMode::Unbound));
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use super::hasher::MerkleHasher;
+use super::tree::{compute_leaf_with, MerkleTree, Nodes};
+use crate::model::Expiration;
+
+// Debug rendering of a `MerkleTree` as Graphviz DOT or an indented ASCII tree.
+// Both renderers walk the same node graph: in the layered layout a node at (level, j)
+// has children (level - 1, 2j) and (level - 1, 2j + 1), where a promoted odd node has
+// only the first; in the standard layout node i has children 2i + 1 and 2i + 2.
+//
+// With a highlighted leaf, the nodes on its path to the root are marked `path` and the
+// proof siblings (exactly the hashes `get_proof` returns) are marked `proof`.
+// The highlighted path is always drawn in full; everything else can be collapsed.
+
+/// What to draw and how much of it
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    /// Leaf whose proof path is highlighted
+    pub highlight: Option<[u8; 32]>,
+    /// Levels below the root to draw. Deeper subtrees off the highlighted path are
+    /// collapsed into one node. None draws every level.
+    pub max_depth: Option<usize>,
+    /// Collapse every subtree that does not contain the highlighted leaf
+    pub collapse_off_path: bool,
+    /// Hex digits shown per hash; None shows all 64
+    pub hash_digits: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum NodeId {
+    Layered { level: usize, index: usize },
+    Standard(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    None,
+    Path,
+    Proof,
+}
+
+enum Line {
+    Node {
+        id: NodeId,
+        depth: usize,
+        mark: Mark,
+        collapsed: bool,
+    },
+    Edge(NodeId, NodeId),
+}
+
+struct Renderer<'a, H: MerkleHasher> {
+    tree: &'a MerkleTree<H>,
+    options: &'a RenderOptions,
+    labels: HashMap<[u8; 32], &'a (String, Expiration)>,
+    path: HashSet<NodeId>,
+    proof: HashSet<NodeId>,
+}
+
+impl<'a, H: MerkleHasher> Renderer<'a, H> {
+    fn new(
+        tree: &'a MerkleTree<H>,
+        subscribers: &'a [(String, Expiration)],
+        options: &'a RenderOptions,
+    ) -> Result<Self> {
+        let labels = subscribers
+            .iter()
+            .map(|subscriber| {
+                Ok((
+                    compute_leaf_with::<H>(&subscriber.0, subscriber.1)?,
+                    subscriber,
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut renderer = Renderer {
+            tree,
+            options,
+            labels,
+            path: HashSet::new(),
+            proof: HashSet::new(),
+        };
+
+        let position = options.highlight.and_then(|leaf| {
+            tree.leaf_positions()
+                .into_iter()
+                .find(|(_, l)| *l == leaf)
+                .map(|(position, _)| position)
+        });
+        if let Some(position) = position {
+            renderer.path = renderer.path_to_root(position).into_iter().collect();
+            renderer.proof = renderer
+                .path
+                .iter()
+                .flat_map(|&id| renderer.children(id))
+                .filter(|id| !renderer.path.contains(id))
+                .collect();
+        }
+
+        Ok(renderer)
+    }
+
+    fn root(&self) -> Option<NodeId> {
+        if self.tree.is_empty() {
+            return None;
+        }
+        Some(match &self.tree.nodes {
+            Nodes::Layered(layers) => NodeId::Layered {
+                level: layers.len() - 1,
+                index: 0,
+            },
+            Nodes::Standard { .. } => NodeId::Standard(0),
+        })
+    }
+
+    fn hash(&self, id: NodeId) -> [u8; 32] {
+        match (&self.tree.nodes, id) {
+            (Nodes::Layered(layers), NodeId::Layered { level, index }) => layers[level][index],
+            (Nodes::Standard { tree, .. }, NodeId::Standard(i)) => tree[i],
+            _ => unreachable!("node id matches the tree layout"),
+        }
+    }
+
+    fn children(&self, id: NodeId) -> Vec<NodeId> {
+        match (&self.tree.nodes, id) {
+            (Nodes::Layered(_), NodeId::Layered { level: 0, .. }) => Vec::new(),
+            (Nodes::Layered(layers), NodeId::Layered { level, index }) => (2 * index
+                ..(2 * index + 2).min(layers[level - 1].len()))
+                .map(|index| NodeId::Layered {
+                    level: level - 1,
+                    index,
+                })
+                .collect(),
+            (Nodes::Standard { tree, .. }, NodeId::Standard(i)) => [2 * i + 1, 2 * i + 2]
+                .into_iter()
+                .filter(|&child| child < tree.len())
+                .map(NodeId::Standard)
+                .collect(),
+            _ => unreachable!("node id matches the tree layout"),
+        }
+    }
+
+    /// Nodes from the leaf at `position` (see `MerkleTree::leaf_positions`) up to the root
+    fn path_to_root(&self, position: usize) -> Vec<NodeId> {
+        match &self.tree.nodes {
+            Nodes::Layered(layers) => (0..layers.len())
+                .map(|level| NodeId::Layered {
+                    level,
+                    index: position >> level,
+                })
+                .collect(),
+            Nodes::Standard { .. } => {
+                let mut index = position;
+                let mut path = vec![NodeId::Standard(index)];
+                while index > 0 {
+                    index = (index - 1) / 2;
+                    path.push(NodeId::Standard(index));
+                }
+                path
+            }
+        }
+    }
+
+    fn leaf_count_under(&self, id: NodeId) -> usize {
+        let children = self.children(id);
+        if children.is_empty() {
+            return 1;
+        }
+        children.into_iter().map(|c| self.leaf_count_under(c)).sum()
+    }
+
+    fn mark(&self, id: NodeId) -> Mark {
+        if self.path.contains(&id) {
+            Mark::Path
+        } else if self.proof.contains(&id) {
+            Mark::Proof
+        } else {
+            Mark::None
+        }
+    }
+
+    fn is_collapsed(&self, id: NodeId, depth: usize) -> bool {
+        if self.path.contains(&id) || self.children(id).is_empty() {
+            return false;
+        }
+        let too_deep = self.options.max_depth.is_some_and(|max| depth >= max);
+        let off_path = self.options.collapse_off_path && !self.path.is_empty();
+        too_deep || off_path
+    }
+
+    /// Pre-order walk: each node, then the edges to and subtrees of its children
+    fn lines(&self) -> Vec<Line> {
+        let mut lines = Vec::new();
+        if let Some(root) = self.root() {
+            self.walk(root, 0, &mut lines);
+        }
+        lines
+    }
+
+    fn walk(&self, id: NodeId, depth: usize, lines: &mut Vec<Line>) {
+        let collapsed = self.is_collapsed(id, depth);
+        lines.push(Line::Node {
+            id,
+            depth,
+            mark: self.mark(id),
+            collapsed,
+        });
+        if collapsed {
+            return;
+        }
+        for child in self.children(id) {
+            lines.push(Line::Edge(id, child));
+            self.walk(child, depth + 1, lines);
+        }
+    }
+
+    fn format_hash(&self, hash: &[u8; 32]) -> String {
+        let hex = hex::encode(hash);
+        match self.options.hash_digits {
+            Some(digits) if digits < hex.len() => format!("0x{}…", &hex[..digits]),
+            _ => format!("0x{}", hex),
+        }
+    }
+
+    /// Label parts: the hash, then the subscriber for leaves or the leaf count for collapsed nodes
+    fn label(&self, id: NodeId, collapsed: bool) -> Vec<String> {
+        let hash = self.hash(id);
+        let mut parts = vec![self.format_hash(&hash)];
+        if collapsed {
+            let count = self.leaf_count_under(id);
+            parts.push(format!(
+                "… {} {}",
+                count,
+                if count == 1 { "leaf" } else { "leaves" }
+            ));
+        } else if self.children(id).is_empty() {
+            if let Some((address, expiration)) = self.labels.get(&hash) {
+                parts.push(format!("{} (expiration {})", address, expiration));
+            }
+        }
+        parts
+    }
+}
+
+fn dot_name(id: NodeId) -> String {
+    match id {
+        NodeId::Layered { level, index } => format!("l{}_{}", level, index),
+        NodeId::Standard(i) => format!("n{}", i),
+    }
+}
+
+/// Render the tree as a Graphviz `digraph`, root at the top. Leaves found in `subscribers`
+/// are labelled with the address and expiration; pass an empty slice to show hashes only.
+/// The highlighted path is filled gold and its proof siblings light blue.
+pub fn render_dot<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscribers: &[(String, Expiration)],
+    options: &RenderOptions,
+) -> Result<String> {
+    let renderer = Renderer::new(tree, subscribers, options)?;
+    let mut out = String::new();
+
+    writeln!(out, "digraph merkle {{")?;
+    writeln!(out, "  node [shape=box, fontname=\"monospace\"];")?;
+    if renderer.root().is_none() {
+        writeln!(
+            out,
+            "  empty [label=\"{}\\nempty tree\"];",
+            renderer.format_hash(&tree.root())
+        )?;
+    }
+
+    for line in renderer.lines() {
+        match line {
+            Line::Node {
+                id,
+                mark,
+                collapsed,
+                ..
+            } => {
+                let mut attributes = vec![format!(
+                    "label=\"{}\"",
+                    renderer.label(id, collapsed).join("\\n")
+                )];
+                match mark {
+                    Mark::Path => attributes.push("style=filled, fillcolor=gold".to_string()),
+                    Mark::Proof => attributes.push("style=filled, fillcolor=lightblue".to_string()),
+                    Mark::None => {}
+                }
+                if collapsed {
+                    attributes.push("shape=folder".to_string());
+                }
+                writeln!(out, "  {} [{}];", dot_name(id), attributes.join(", "))?;
+            }
+            Line::Edge(parent, child) => {
+                let on_path = renderer.path.contains(&child);
+                writeln!(
+                    out,
+                    "  {} -> {}{};",
+                    dot_name(parent),
+                    dot_name(child),
+                    if on_path { " [penwidth=2]" } else { "" }
+                )?;
+            }
+        }
+    }
+
+    writeln!(out, "}}")?;
+    Ok(out)
+}
+
+/// Render the tree as an indented ASCII tree, one node per line, root first.
+/// Path nodes end in `[path]` and proof siblings in `[proof]`.
+pub fn render_ascii<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscribers: &[(String, Expiration)],
+    options: &RenderOptions,
+) -> Result<String> {
+    let renderer = Renderer::new(tree, subscribers, options)?;
+    let mut out = String::new();
+
+    if renderer.root().is_none() {
+        writeln!(out, "{} (empty tree)", renderer.format_hash(&tree.root()))?;
+        return Ok(out);
+    }
+
+    // Whether the node at each depth on the way down is its parent's last child,
+    // which decides between "│" and blank in the indentation below it
+    let mut last_at_depth: Vec<bool> = Vec::new();
+    let lines = renderer.lines();
+    for (i, line) in lines.iter().enumerate() {
+        let Line::Node {
+            id,
+            depth,
+            mark,
+            collapsed,
+        } = *line
+        else {
+            continue;
+        };
+
+        let is_last = lines[i + 1..]
+            .iter()
+            .find_map(|next| match next {
+                Line::Node { depth: d, .. } if *d <= depth => Some(*d < depth),
+                _ => None,
+            })
+            .unwrap_or(true);
+        last_at_depth.truncate(depth);
+        last_at_depth.push(is_last);
+
+        let mut prefix = String::new();
+        if depth > 0 {
+            for &last in &last_at_depth[1..depth] {
+                prefix.push_str(if last { "    " } else { "│   " });
+            }
+            prefix.push_str(if is_last { "└── " } else { "├── " });
+        }
+
+        let mut text = renderer.label(id, collapsed).join("  ");
+        match mark {
+            Mark::Path => text.push_str("  [path]"),
+            Mark::Proof => text.push_str("  [proof]"),
+            Mark::None => {}
+        }
+        writeln!(out, "{}{}", prefix, text)?;
+    }
+
+    Ok(out)
+}
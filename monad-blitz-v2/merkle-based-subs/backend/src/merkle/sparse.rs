@@ -0,0 +1,436 @@
+use anyhow::{Context, Result};
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashMap};
+
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::tree::compute_leaf;
+use super::validation::{
+    normalize_address, validate_and_quarantine, ValidationPolicy, ValidationReport,
+};
+use crate::model::Expiration;
+
+/// One level per address bit: leaves live at depth 160, the root at depth 0.
+pub const SMT_DEPTH: usize = 160;
+
+/// Hash of an empty leaf. An address whose leaf is empty is not subscribed.
+pub const EMPTY_LEAF: [u8; 32] = [0u8; 32];
+
+/// How internal nodes of the sparse tree are hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SmtHashing {
+    /// Empty subtrees hash to zero at every level: `node(0, 0) = 0`,
+    /// otherwise `keccak256(left ++ right)`. Cheapest to compute off-chain.
+    #[default]
+    Compact,
+    /// Plain `keccak256(abi.encodePacked(left, right))` at every level, with empty subtrees
+    /// hashing to `zeros[h + 1] = keccak256(zeros[h], zeros[h])`. A contract can verify
+    /// proofs with a branch-free loop over the 160 siblings.
+    Solidity,
+}
+
+/// Membership or non-membership proof for one address.
+/// `siblings[0]` is the sibling of the leaf, `siblings[159]` the child of the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseProof {
+    pub key: [u8; 20],
+    /// The address's leaf, `EMPTY_LEAF` for a non-membership proof
+    pub leaf: [u8; 32],
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl SparseProof {
+    pub fn is_membership(&self) -> bool {
+        self.leaf != EMPTY_LEAF
+    }
+}
+
+/// Sparse Merkle tree with one fixed leaf position per 160-bit wallet address.
+/// Only non-empty nodes are stored, so memory grows with the number of subscribers.
+pub struct SparseMerkleTree {
+    hashing: SmtHashing,
+    leaves: BTreeMap<[u8; 20], [u8; 32]>,
+    /// Non-empty nodes keyed by (height above the leaves, key with the low `height` bits cleared)
+    nodes: HashMap<(usize, [u8; 20]), [u8; 32]>,
+    /// Hash of an empty subtree at each height
+    empty: Vec<[u8; 32]>,
+}
+
+impl SparseMerkleTree {
+    pub fn new(hashing: SmtHashing) -> Self {
+        SparseMerkleTree {
+            hashing,
+            leaves: BTreeMap::new(),
+            nodes: HashMap::new(),
+            empty: empty_hashes(hashing),
+        }
+    }
+
+    pub fn hashing(&self) -> SmtHashing {
+        self.hashing
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.node(SMT_DEPTH, &[0u8; 20])
+    }
+
+    /// The leaf stored for `key`, if any
+    pub fn get(&self, key: &[u8; 20]) -> Option<[u8; 32]> {
+        self.leaves.get(key).copied()
+    }
+
+    /// Set the leaf for `key` and return the new root.
+    /// Setting `EMPTY_LEAF` is the same as removing the key.
+    pub fn insert(&mut self, key: [u8; 20], leaf: [u8; 32]) -> [u8; 32] {
+        if leaf == EMPTY_LEAF {
+            self.leaves.remove(&key);
+        } else {
+            self.leaves.insert(key, leaf);
+        }
+        self.set_node(0, key, leaf);
+
+        // Rehash the 160 ancestors of the leaf
+        let mut current = leaf;
+        for height in 0..SMT_DEPTH {
+            let sibling = self.node(height, &sibling_prefix(&key, height));
+            current = if bit(&key, height) {
+                self.hash_node(sibling, current, height)
+            } else {
+                self.hash_node(current, sibling, height)
+            };
+            self.set_node(height + 1, prefix(&key, height + 1), current);
+        }
+
+        current
+    }
+
+    /// Remove `key` (revoke the address) and return the new root.
+    pub fn remove(&mut self, key: &[u8; 20]) -> [u8; 32] {
+        self.insert(*key, EMPTY_LEAF)
+    }
+
+    /// Prove the current state of `key`: membership if it has a leaf, non-membership otherwise.
+    pub fn prove(&self, key: &[u8; 20]) -> SparseProof {
+        let siblings = (0..SMT_DEPTH)
+            .map(|height| self.node(height, &sibling_prefix(key, height)))
+            .collect();
+
+        SparseProof {
+            key: *key,
+            leaf: self.get(key).unwrap_or(EMPTY_LEAF),
+            siblings,
+        }
+    }
+
+    /// Recompute the root from a proof (off-chain verification).
+    pub fn compute_root(hashing: SmtHashing, proof: &SparseProof) -> Option<[u8; 32]> {
+        if proof.siblings.len() != SMT_DEPTH {
+            return None;
+        }
+
+        let empty = empty_hashes(hashing);
+        let mut current = proof.leaf;
+        for (height, sibling) in proof.siblings.iter().enumerate() {
+            let (left, right) = if bit(&proof.key, height) {
+                (*sibling, current)
+            } else {
+                (current, *sibling)
+            };
+            current = hash_node(hashing, &empty, left, right, height);
+        }
+
+        Some(current)
+    }
+
+    /// Verify that `leaf` is stored under `key` in the tree with the given root.
+    pub fn verify_membership(
+        hashing: SmtHashing,
+        root: &[u8; 32],
+        key: &[u8; 20],
+        leaf: &[u8; 32],
+        proof: &SparseProof,
+    ) -> bool {
+        *leaf != EMPTY_LEAF
+            && proof.key == *key
+            && proof.leaf == *leaf
+            && Self::compute_root(hashing, proof) == Some(*root)
+    }
+
+    /// Verify that nothing is stored under `key` in the tree with the given root.
+    pub fn verify_non_membership(
+        hashing: SmtHashing,
+        root: &[u8; 32],
+        key: &[u8; 20],
+        proof: &SparseProof,
+    ) -> bool {
+        proof.key == *key
+            && proof.leaf == EMPTY_LEAF
+            && Self::compute_root(hashing, proof) == Some(*root)
+    }
+
+    fn node(&self, height: usize, prefix: &[u8; 20]) -> [u8; 32] {
+        self.nodes
+            .get(&(height, *prefix))
+            .copied()
+            .unwrap_or(self.empty[height])
+    }
+
+    fn set_node(&mut self, height: usize, prefix: [u8; 20], hash: [u8; 32]) {
+        if hash == self.empty[height] {
+            self.nodes.remove(&(height, prefix));
+        } else {
+            self.nodes.insert((height, prefix), hash);
+        }
+    }
+
+    fn hash_node(&self, left: [u8; 32], right: [u8; 32], height: usize) -> [u8; 32] {
+        hash_node(self.hashing, &self.empty, left, right, height)
+    }
+}
+
+/// Hash two children at `height` into their parent
+fn hash_node(
+    hashing: SmtHashing,
+    empty: &[[u8; 32]],
+    left: [u8; 32],
+    right: [u8; 32],
+    height: usize,
+) -> [u8; 32] {
+    match hashing {
+        SmtHashing::Compact if left == empty[height] && right == empty[height] => empty[height + 1],
+        _ => Keccak256Hasher::hash_ordered(left, right),
+    }
+}
+
+fn empty_hashes(hashing: SmtHashing) -> Vec<[u8; 32]> {
+    let mut empty = vec![EMPTY_LEAF; SMT_DEPTH + 1];
+    if hashing == SmtHashing::Solidity {
+        for height in 0..SMT_DEPTH {
+            empty[height + 1] = Keccak256Hasher::hash_ordered(empty[height], empty[height]);
+        }
+    }
+    empty
+}
+
+/// Bit `height` of the key, counted from the least significant bit of the address.
+/// A set bit means the node at that height is a right child.
+fn bit(key: &[u8; 20], height: usize) -> bool {
+    (key[19 - height / 8] >> (height % 8)) & 1 == 1
+}
+
+/// The key with its low `height` bits cleared: identifies the ancestor at that height
+fn prefix(key: &[u8; 20], height: usize) -> [u8; 20] {
+    let mut out = *key;
+    let full_bytes = height / 8;
+    for byte in out[20 - full_bytes..].iter_mut() {
+        *byte = 0;
+    }
+    if !height.is_multiple_of(8) {
+        out[19 - full_bytes] &= 0xff << (height % 8);
+    }
+    out
+}
+
+fn sibling_prefix(key: &[u8; 20], height: usize) -> [u8; 20] {
+    let mut out = prefix(key, height);
+    out[19 - height / 8] ^= 1 << (height % 8);
+    out
+}
+
+/// Parse a hex wallet address into a sparse tree key.
+/// Case variants of an address map to the same key.
+pub fn address_key(address_hex: &str) -> Result<[u8; 20]> {
+    let normalized = normalize_address(address_hex);
+    let bytes = hex::decode(&normalized[2..]).context("Invalid hex address")?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Ethereum address must be 20 bytes"))
+}
+
+// ───────────────────────────────────────────────────
+// Subscriber-level API
+// ───────────────────────────────────────────────────
+
+/// Build a sparse tree from the `DEFAULT_PRODUCT` subscribers in `subscriber_storage`.
+/// Each subscriber's leaf is the same `compute_leaf(address, expiration)` hash the sorted tree uses.
+///
+/// Rows go through the same validation pass as the published tree: malformed rows and case
+/// variants of an accepted address, which would share its key, are written to
+/// `subscriber_quarantine` instead of overwriting it (see `validation`).
+pub async fn build_sparse_tree_from_db(
+    pool: &PgPool,
+    hashing: SmtHashing,
+    policy: ValidationPolicy,
+) -> Result<(String, SparseMerkleTree, ValidationReport)> {
+    let report = validate_and_quarantine(pool, policy).await?;
+
+    let mut tree = SparseMerkleTree::new(hashing);
+    for (address, expiration) in &report.accepted {
+        tree.insert(address_key(address)?, compute_leaf(address, *expiration)?);
+    }
+
+    Ok((hex::encode(tree.root()), tree, report))
+}
+
+/// Off-chain check that `user_pubkey` is subscribed with `expiration_ts` under `root_hex`.
+pub fn verify_subscribed(
+    hashing: SmtHashing,
+    root_hex: &str,
+    proof: &SparseProof,
+    user_pubkey: &str,
+    expiration_ts: Expiration,
+) -> Result<bool> {
+    let root = parse_root(root_hex)?;
+    let key = address_key(user_pubkey)?;
+    let leaf = compute_leaf(user_pubkey, expiration_ts)?;
+    Ok(SparseMerkleTree::verify_membership(
+        hashing, &root, &key, &leaf, proof,
+    ))
+}
+
+/// Off-chain check that `user_pubkey` is not subscribed (or was revoked) under `root_hex`.
+pub fn verify_not_subscribed(
+    hashing: SmtHashing,
+    root_hex: &str,
+    proof: &SparseProof,
+    user_pubkey: &str,
+) -> Result<bool> {
+    let root = parse_root(root_hex)?;
+    let key = address_key(user_pubkey)?;
+    Ok(SparseMerkleTree::verify_non_membership(
+        hashing, &root, &key, proof,
+    ))
+}
+
+fn parse_root(root_hex: &str) -> Result<[u8; 32]> {
+    let root_vec = hex::decode(root_hex.trim_start_matches("0x")).context("Invalid root hex")?;
+    root_vec
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Root must be 32 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "0xAbCdEfAbCdEfAbCdEfAbCdEfAbCdEfAbCdEfAbCd";
+    const BOB: &str = "0x1111111111111111111111111111111111111111";
+    const CAROL: &str = "0x1111111111111111111111111111111111111110";
+
+    fn expiration() -> Expiration {
+        Expiration::new(1_700_000_000).unwrap()
+    }
+
+    fn tree(hashing: SmtHashing) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new(hashing);
+        for address in [ALICE, BOB] {
+            tree.insert(
+                address_key(address).unwrap(),
+                compute_leaf(address, expiration()).unwrap(),
+            );
+        }
+        tree
+    }
+
+    #[test]
+    fn proves_membership_and_non_membership() {
+        for hashing in [SmtHashing::Compact, SmtHashing::Solidity] {
+            let tree = tree(hashing);
+            let root = hex::encode(tree.root());
+
+            let proof = tree.prove(&address_key(ALICE).unwrap());
+            assert!(proof.is_membership());
+            assert!(verify_subscribed(hashing, &root, &proof, ALICE, expiration()).unwrap());
+            assert!(!verify_not_subscribed(hashing, &root, &proof, ALICE).unwrap());
+            let later = Expiration::new(1_800_000_000).unwrap();
+            assert!(!verify_subscribed(hashing, &root, &proof, ALICE, later).unwrap());
+            assert!(!verify_subscribed(hashing, &root, &proof, BOB, expiration()).unwrap());
+
+            // CAROL shares all but the last bit with BOB
+            let proof = tree.prove(&address_key(CAROL).unwrap());
+            assert!(!proof.is_membership());
+            assert!(verify_not_subscribed(hashing, &root, &proof, CAROL).unwrap());
+            assert!(!verify_subscribed(hashing, &root, &proof, CAROL, expiration()).unwrap());
+
+            let mut tampered = proof.clone();
+            tampered.siblings[0][0] ^= 1;
+            assert!(!verify_not_subscribed(hashing, &root, &tampered, CAROL).unwrap());
+            tampered.siblings.pop();
+            assert_eq!(SparseMerkleTree::compute_root(hashing, &tampered), None);
+        }
+    }
+
+    #[test]
+    fn removal_restores_the_previous_root() {
+        for hashing in [SmtHashing::Compact, SmtHashing::Solidity] {
+            let mut tree = tree(hashing);
+            let before = tree.root();
+            let carol = address_key(CAROL).unwrap();
+            tree.insert(carol, compute_leaf(CAROL, expiration()).unwrap());
+            assert_ne!(tree.root(), before);
+            assert_eq!(tree.remove(&carol), before);
+            assert_eq!(tree.len(), 2);
+
+            let root = hex::encode(tree.root());
+            let proof = tree.prove(&carol);
+            assert!(verify_not_subscribed(hashing, &root, &proof, CAROL).unwrap());
+
+            for address in [ALICE, BOB] {
+                tree.remove(&address_key(address).unwrap());
+            }
+            assert!(tree.is_empty());
+            assert_eq!(tree.root(), SparseMerkleTree::new(hashing).root());
+        }
+    }
+
+    #[test]
+    fn compact_mode_hashes_empty_subtrees_to_zero() {
+        assert_eq!(SparseMerkleTree::new(SmtHashing::Compact).root(), [0u8; 32]);
+
+        // A single leaf folds with zero siblings all the way up
+        let tree = tree(SmtHashing::Compact);
+        let proof = tree.prove(&address_key(BOB).unwrap());
+        let empty_siblings = proof.siblings.iter().filter(|s| **s == EMPTY_LEAF).count();
+        assert_eq!(empty_siblings, SMT_DEPTH - 1);
+    }
+
+    #[test]
+    fn solidity_mode_folds_plain_keccak_pairs() {
+        // Empty root: keccak256(zeros[h] ++ zeros[h]) up to the root
+        let mut expected = EMPTY_LEAF;
+        for _ in 0..SMT_DEPTH {
+            expected = Keccak256Hasher::hash_ordered(expected, expected);
+        }
+        assert_eq!(SparseMerkleTree::new(SmtHashing::Solidity).root(), expected);
+
+        // The branch-free loop a contract would run over the 160 siblings
+        let tree = tree(SmtHashing::Solidity);
+        let key = address_key(ALICE).unwrap();
+        let proof = tree.prove(&key);
+        let mut current = proof.leaf;
+        for (height, sibling) in proof.siblings.iter().enumerate() {
+            current = if bit(&key, height) {
+                Keccak256Hasher::hash_ordered(*sibling, current)
+            } else {
+                Keccak256Hasher::hash_ordered(current, *sibling)
+            };
+        }
+        assert_eq!(current, tree.root());
+    }
+
+    #[test]
+    fn case_variants_share_a_key() {
+        let upper = format!("0X{}", ALICE[2..].to_uppercase());
+        let lower = ALICE.to_lowercase();
+        assert_eq!(address_key(&upper).unwrap(), address_key(ALICE).unwrap());
+        assert_eq!(address_key(&lower).unwrap(), address_key(ALICE).unwrap());
+        assert!(address_key("0x1234").is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use futures::TryStreamExt;
+use sqlx::PgPool;
+use std::collections::HashSet;
+
+use super::tree::{compute_leaf, OzMerkleTree, TreeOptions};
+use crate::model::{Expiration, DEFAULT_PRODUCT};
+
+/// How often `build_tree_streaming` reports progress, in rows
+pub const PROGRESS_INTERVAL: usize = 10_000;
+
+/// A row that could not be turned into a leaf
+#[derive(Debug, Clone)]
+pub struct RowFailure {
+    pub wallet_address: String,
+    pub reason: String,
+}
+
+/// Progress of a streaming build, passed to the progress callback
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamProgress {
+    pub rows_read: usize,
+    pub leaves: usize,
+    pub failed: usize,
+}
+
+/// Outcome of a streaming build
+#[derive(Debug, Clone, Default)]
+pub struct StreamReport {
+    pub progress: StreamProgress,
+    pub failures: Vec<RowFailure>,
+}
+
+/// Build the tree while streaming `subscriber_storage` through a cursor.
+///
+/// Unlike `build_tree_from_db`, rows are hashed as they arrive and only the leaf hashes
+/// are kept, so peak memory is bounded by the leaf array rather than the row strings.
+/// Rows with a malformed address or expiration, or with the same leaf as an earlier row,
+/// are skipped and listed in the report instead of aborting the build. Rows are read in wallet_address order, so a build
+/// without failures is identical to `build_tree_from_db_with_options`.
+pub async fn build_tree_streaming(
+    pool: &PgPool,
+    options: TreeOptions,
+    mut on_progress: impl FnMut(&StreamProgress),
+) -> Result<(String, OzMerkleTree, StreamReport)> {
+    let mut rows = sqlx::query_as::<_, (String, i64)>(
+        // Byte-wise order, matching the `String` sort in `load_subscribers`
+        r#"SELECT wallet_address, expiration_ts FROM subscriber_storage
+           WHERE product_id = $1
+           ORDER BY wallet_address COLLATE "C""#,
+    )
+    .bind(DEFAULT_PRODUCT as i32)
+    .fetch(pool);
+
+    let mut leaves: Vec<[u8; 32]> = Vec::new();
+    let mut seen: HashSet<[u8; 32]> = HashSet::new();
+    let mut report = StreamReport::default();
+
+    while let Some((address, exp)) = rows.try_next().await? {
+        report.progress.rows_read += 1;
+
+        let leaf = Expiration::from_unix(exp)
+            .and_then(|exp| compute_leaf(&address, exp))
+            .and_then(|leaf| {
+                if !seen.insert(leaf) {
+                    return Err(anyhow::anyhow!("Same leaf as an earlier row"));
+                }
+                Ok(leaf)
+            });
+        match leaf {
+            Ok(leaf) => {
+                leaves.push(leaf);
+                report.progress.leaves += 1;
+            }
+            Err(e) => {
+                report.progress.failed += 1;
+                report.failures.push(RowFailure {
+                    wallet_address: address,
+                    reason: e.to_string(),
+                });
+            }
+        }
+
+        if report.progress.rows_read % PROGRESS_INTERVAL == 0 {
+            on_progress(&report.progress);
+        }
+    }
+    on_progress(&report.progress);
+
+    // An empty table publishes the empty root; rows that all failed are an error
+    if leaves.is_empty() && report.progress.failed > 0 {
+        return Err(anyhow::anyhow!(
+            "No valid subscribers found in database ({} rows failed)",
+            report.progress.failed
+        ));
+    }
+
+    let tree = OzMerkleTree::from_leaves_with_options(&leaves, options);
+    Ok((hex::encode(tree.root()), tree, report))
+}
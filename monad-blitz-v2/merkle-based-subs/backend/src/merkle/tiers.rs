@@ -0,0 +1,253 @@
+use anyhow::{Context, Result};
+use ethers::abi::Token;
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use super::encoding::LeafEncoding;
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::index::IndexedValue;
+use super::tree::{
+    check_unique_leaves, get_proof_for_value, subscriber_values, verify_value, MerkleTree, Proof,
+    TreeOptions,
+};
+use super::validation::normalize_address;
+use crate::model::{Expiration, PlanId, DEFAULT_PRODUCT};
+
+// Tiered leaves commit the subscriber's plan next to the expiration:
+//
+//   leaf = keccak256(bytes.concat(keccak256(abi.encode(address, uint256 planId, uint256 expiration))))
+//
+// so one contract can serve every tier, calling `verifySubscription(proof, planId, expiration)`
+// (see smart-contracts/contracts/MerkleUpdater.sol) and deciding itself what each plan unlocks.
+// Tiered and plain leaves never collide (the encodings have different lengths), but the
+// overload only passes against a root that commits tiered leaves.
+
+/// Leaf encoding of tiered subscriber leaves: `abi.encode(address, uint256, uint256)`
+pub const TIERED_LEAF_ENCODING: [&str; 3] = ["address", "uint256", "uint256"];
+
+/// A subscriber with the plan committed in its leaf
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TieredSubscriber {
+    pub wallet_address: String,
+    pub plan_id: PlanId,
+    pub expiration: Expiration,
+}
+
+impl IndexedValue for TieredSubscriber {
+    type Key = String;
+
+    fn key(&self) -> String {
+        normalize_address(&self.wallet_address)
+    }
+
+    fn leaf<H: MerkleHasher>(&self) -> Result<[u8; 32]> {
+        compute_tiered_leaf_with::<H>(&self.wallet_address, self.plan_id, self.expiration)
+    }
+}
+
+/// The `(address, uint256, uint256)` encoding of tiered leaves
+pub fn tiered_encoding() -> LeafEncoding {
+    LeafEncoding::new(&TIERED_LEAF_ENCODING).expect("valid Solidity types")
+}
+
+/// Leaf values `(address, planId, expiration)`, in `tiered_encoding()` order
+pub fn tiered_values(
+    address_hex: &str,
+    plan_id: PlanId,
+    expiration: Expiration,
+) -> Result<Vec<Token>> {
+    let mut values = subscriber_values(address_hex, expiration)?;
+    values.insert(1, Token::Uint(U256::from(plan_id)));
+    Ok(values)
+}
+
+/// Tiered leaf matching the contract's
+/// `keccak256(bytes.concat(keccak256(abi.encode(address, planId, expiration))))`
+pub fn compute_tiered_leaf(
+    address_hex: &str,
+    plan_id: PlanId,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    compute_tiered_leaf_with::<Keccak256Hasher>(address_hex, plan_id, expiration)
+}
+
+/// `compute_tiered_leaf` with any hasher
+pub fn compute_tiered_leaf_with<H: MerkleHasher>(
+    address_hex: &str,
+    plan_id: PlanId,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    tiered_encoding().leaf_hash_with::<H>(&tiered_values(address_hex, plan_id, expiration)?)
+}
+
+/// Load every `DEFAULT_PRODUCT` subscriber with its plan, sorted by wallet_address
+/// like `load_subscribers`.
+pub async fn load_tiered_subscribers(pool: &PgPool) -> Result<Vec<TieredSubscriber>> {
+    let rows = sqlx::query!(
+        "SELECT wallet_address, plan_id, expiration_ts FROM subscriber_storage
+             WHERE product_id = $1",
+        DEFAULT_PRODUCT as i32
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut subscribers = rows
+        .into_iter()
+        .map(|row| {
+            let expiration = Expiration::from_unix(row.expiration_ts).with_context(|| {
+                format!("Invalid expiration for subscriber {}", row.wallet_address)
+            })?;
+            Ok(TieredSubscriber {
+                plan_id: PlanId::try_from(row.plan_id).with_context(|| {
+                    format!("Invalid plan for subscriber {}", row.wallet_address)
+                })?,
+                wallet_address: row.wallet_address,
+                expiration,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    subscribers.sort_by(|a, b| a.wallet_address.cmp(&b.wallet_address));
+    Ok(subscribers)
+}
+
+/// Build a tree of tiered leaves from `subscriber_storage`.
+/// `subscribers[i]` is the value behind the i-th leaf, as for `build_tree_from_db`.
+pub async fn build_tiered_tree_from_db<H: MerkleHasher>(
+    pool: &PgPool,
+    options: TreeOptions,
+) -> Result<(String, MerkleTree<H>, Vec<TieredSubscriber>)> {
+    let subscribers = load_tiered_subscribers(pool).await?;
+
+    let leaves = subscribers
+        .iter()
+        .map(|s| {
+            compute_tiered_leaf_with::<H>(&s.wallet_address, s.plan_id, s.expiration).with_context(
+                || format!("Invalid wallet address for subscriber {}", s.wallet_address),
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let plain: Vec<(String, Expiration)> = subscribers
+        .iter()
+        .map(|s| (s.wallet_address.clone(), s.expiration))
+        .collect();
+    check_unique_leaves(&leaves, &plain)?;
+
+    let tree = MerkleTree::<H>::from_leaves_with_options(&leaves, options);
+    Ok((hex::encode(tree.root()), tree, subscribers))
+}
+
+/// Get a tiered proof for a user, with the plan and expiration it proves.
+/// Returns None if the user is not in `subscribers`.
+/// Scans `subscribers` and the leaves; a `ProofIndex<TieredSubscriber, H>` serves repeated
+/// lookups without scanning.
+pub fn get_tiered_proof_for_user<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscribers: &[TieredSubscriber],
+    user_pubkey: &str,
+) -> Option<(PlanId, Expiration, Proof<H>)> {
+    let user = normalize_address(user_pubkey);
+    let subscriber = subscribers
+        .iter()
+        .find(|s| normalize_address(&s.wallet_address) == user)?;
+
+    let values = tiered_values(
+        &subscriber.wallet_address,
+        subscriber.plan_id,
+        subscriber.expiration,
+    )
+    .ok()?;
+    let proof = get_proof_for_value(tree, &tiered_encoding(), &values)?;
+    Some((subscriber.plan_id, subscriber.expiration, proof))
+}
+
+/// Off-chain verification of a tiered subscription proof: the user holds `plan_id` until
+/// `expiration_ts`. If this passes for a Keccak256 proof of an unexpired subscription,
+/// the contract's `verifySubscription(proof, planId, expiration)` passes too.
+pub fn verify_tiered_subscription<H: MerkleHasher>(
+    root_hex: &str,
+    proof: &Proof<H>,
+    user_pubkey: &str,
+    plan_id: PlanId,
+    expiration_ts: Expiration,
+) -> Result<bool> {
+    let values = tiered_values(user_pubkey, plan_id, expiration_ts)?;
+    verify_value(root_hex, proof, &tiered_encoding(), &values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::tree::compute_leaf;
+    use ethers::abi::{encode, Address};
+    use ethers::utils::keccak256;
+
+    const ADDRESS: &str = "0xabcdefabcdefabcdefabcdefabcdefabcdefabcd";
+
+    #[test]
+    fn tiered_leaf_matches_the_contract() {
+        let expiration = Expiration::new(1_700_000_000).unwrap();
+        // keccak256(bytes.concat(keccak256(abi.encode(msg.sender, planId, expiration))))
+        let encoded = encode(&[
+            Token::Address(ADDRESS.parse::<Address>().unwrap()),
+            Token::Uint(U256::from(2)),
+            Token::Uint(U256::from(1_700_000_000u64)),
+        ]);
+        let expected = keccak256(keccak256(encoded));
+
+        assert_eq!(
+            compute_tiered_leaf(ADDRESS, 2, expiration).unwrap(),
+            expected
+        );
+        assert_ne!(
+            compute_tiered_leaf(ADDRESS, 2, expiration).unwrap(),
+            compute_tiered_leaf(ADDRESS, 3, expiration).unwrap()
+        );
+        assert_ne!(
+            compute_tiered_leaf(ADDRESS, 0, expiration).unwrap(),
+            compute_leaf(ADDRESS, expiration).unwrap()
+        );
+    }
+
+    #[test]
+    fn tiered_proofs_bind_the_plan() {
+        let expiration = Expiration::new(1_700_000_000).unwrap();
+        let subscribers: Vec<TieredSubscriber> = (0..5u8)
+            .map(|i| TieredSubscriber {
+                wallet_address: format!("0x{}", hex::encode([0xb0 + i; 20])),
+                plan_id: PlanId::from(i % 3),
+                expiration,
+            })
+            .collect();
+        let leaves: Vec<_> = subscribers
+            .iter()
+            .map(|s| s.leaf::<Keccak256Hasher>().unwrap())
+            .collect();
+        let tree = MerkleTree::<Keccak256Hasher>::from_leaves(&leaves);
+        let root_hex = hex::encode(tree.root());
+
+        for s in &subscribers {
+            let (plan_id, expiration, proof) =
+                get_tiered_proof_for_user(&tree, &subscribers, &s.wallet_address).unwrap();
+            assert_eq!(plan_id, s.plan_id);
+            assert!(verify_tiered_subscription(
+                &root_hex,
+                &proof,
+                &s.wallet_address,
+                plan_id,
+                expiration
+            )
+            .unwrap());
+            assert!(!verify_tiered_subscription(
+                &root_hex,
+                &proof,
+                &s.wallet_address,
+                plan_id + 1,
+                expiration
+            )
+            .unwrap());
+        }
+    }
+}
@@ -1,192 +1,1119 @@
 use anyhow::{Context, Result};
-use sha3::{Digest, Keccak256};
+use ethers::abi::Token;
+use ethers::types::Address;
 use sqlx::PgPool;
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::ops::Deref;
 
-/// Keccak256 hash helper
-fn keccak256(data: &[u8]) -> [u8; 32] {
-    let mut hasher = Keccak256::new();
-    hasher.update(data);
-    hasher.finalize().into()
-}
-
-/// OpenZeppelin-compatible sorted-pair hash for internal nodes.
-/// MerkleProof.verify uses: keccak256(abi.encodePacked(min(a,b), max(a,b)))
-fn hash_pair(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
-    let mut combined = [0u8; 64];
-    if a <= b {
-        combined[..32].copy_from_slice(&a);
-        combined[32..].copy_from_slice(&b);
-    } else {
-        combined[..32].copy_from_slice(&b);
-        combined[32..].copy_from_slice(&a);
-    }
-    keccak256(&combined)
-}
+use super::encoding::LeafEncoding;
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::validation::normalize_address;
+use crate::model::{Expiration, DEFAULT_PRODUCT};
 
 /// Compute a leaf hash matching the Solidity contract:
 /// `keccak256(bytes.concat(keccak256(abi.encode(address, expiration))))`
 /// This is OpenZeppelin's StandardMerkleTree double-hash format.
-pub fn compute_leaf(address_hex: &str, expiration: i64) -> Result<[u8; 32]> {
-    let clean_addr = address_hex.trim_start_matches("0x");
-    let pubkey_bytes = hex::decode(clean_addr).context("Invalid hex address")?;
+pub fn compute_leaf(address_hex: &str, expiration: Expiration) -> Result<[u8; 32]> {
+    compute_leaf_with::<Keccak256Hasher>(address_hex, expiration)
+}
+
+/// `compute_leaf` with any hasher: `H::hash_leaf(abi.encode(address, expiration))`
+pub fn compute_leaf_with<H: MerkleHasher>(
+    address_hex: &str,
+    expiration: Expiration,
+) -> Result<[u8; 32]> {
+    LeafEncoding::subscriber().leaf_hash_with::<H>(&subscriber_values(address_hex, expiration)?)
+}
+
+/// Leaf values `(address, uint256)` of a subscriber, in `LeafEncoding::subscriber()` order
+pub fn subscriber_values(address_hex: &str, expiration: Expiration) -> Result<Vec<Token>> {
+    let normalized = normalize_address(address_hex);
+    let pubkey_bytes = hex::decode(&normalized[2..]).context("Invalid hex address")?;
 
     if pubkey_bytes.len() != 20 {
         return Err(anyhow::anyhow!("Ethereum address must be 20 bytes"));
     }
 
-    // abi.encode(address, uint256): address left-padded to 32 bytes + uint256 left-padded to 32 bytes
-    let mut encoded = vec![0u8; 64];
-    encoded[12..32].copy_from_slice(&pubkey_bytes);
+    Ok(vec![
+        Token::Address(Address::from_slice(&pubkey_bytes)),
+        Token::Uint(expiration.to_u256()),
+    ])
+}
+
+/// Sibling hashes from a leaf up to the root, for a tree committed with `H`.
+/// Derefs to the sibling slice, e.g. to pass it on as Solidity's `bytes32[]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proof<H: MerkleHasher = Keccak256Hasher> {
+    siblings: Vec<[u8; 32]>,
+    _hasher: PhantomData<H>,
+}
 
-    let exp_bytes = expiration.to_be_bytes(); // i64 = 8 bytes
-    encoded[56..64].copy_from_slice(&exp_bytes);
+impl<H: MerkleHasher> Proof<H> {
+    /// Wrap siblings received from elsewhere, asserting they were produced with `H`
+    pub fn new(siblings: Vec<[u8; 32]>) -> Self {
+        Proof {
+            siblings,
+            _hasher: PhantomData,
+        }
+    }
 
-    // Double hash: keccak256(bytes.concat(keccak256(abi.encode(user, expiration))))
-    let inner_hash = keccak256(&encoded);
-    Ok(keccak256(&inner_hash))
+    pub fn into_siblings(self) -> Vec<[u8; 32]> {
+        self.siblings
+    }
 }
 
-/// An OpenZeppelin-compatible Merkle tree.
-/// Uses sorted-pair hashing so proofs work with `MerkleProof.verify`.
-pub struct OzMerkleTree {
-    /// All layers of the tree. layers[0] = leaves, layers[last] = root
-    layers: Vec<Vec<[u8; 32]>>,
+impl<H: MerkleHasher> Deref for Proof<H> {
+    type Target = [[u8; 32]];
+
+    fn deref(&self) -> &Self::Target {
+        &self.siblings
+    }
+}
+
+/// A multiproof in the shape expected by `MerkleProof.multiProofVerify(proof, proofFlags, root, leaves)`.
+/// `leaves` are ordered as OZ orders them, which is not necessarily the order they were requested in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiProof<H: MerkleHasher = Keccak256Hasher> {
+    pub leaves: Vec<[u8; 32]>,
+    pub proof: Vec<[u8; 32]>,
+    pub proof_flags: Vec<bool>,
+    _hasher: PhantomData<H>,
+}
+
+impl<H: MerkleHasher> MultiProof<H> {
+    /// Wrap a multiproof received from elsewhere, asserting it was produced with `H`
+    pub fn new(leaves: Vec<[u8; 32]>, proof: Vec<[u8; 32]>, proof_flags: Vec<bool>) -> Self {
+        MultiProof {
+            leaves,
+            proof,
+            proof_flags,
+            _hasher: PhantomData,
+        }
+    }
+}
+
+/// Root of a tree without leaves, unless `TreeOptions::empty_root` says otherwise.
+/// Matches an uninitialized `bytes32` root on-chain, so no proof verifies against it.
+pub const EMPTY_ROOT: [u8; 32] = [0u8; 32];
+
+/// Node arrangement used by `OzMerkleTree`.
+/// Both layouts use sorted-pair hashing, so proofs from either verify with `MerkleProof.verify`,
+/// but only `Standard` yields the same root as `@openzeppelin/merkle-tree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TreeLayout {
+    /// Built layer by layer; an odd trailing node is promoted to the next layer unchanged.
+    #[default]
+    Layered,
+    /// Array-backed complete binary tree, byte-for-byte identical to OZ's StandardMerkleTree.
+    Standard,
+}
+
+impl TreeLayout {
+    /// Name used in `merkle_state.tree_layout` and in exported files
+    pub fn name(self) -> &'static str {
+        match self {
+            TreeLayout::Layered => "layered",
+            TreeLayout::Standard => "standard",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "layered" => Ok(TreeLayout::Layered),
+            "standard" => Ok(TreeLayout::Standard),
+            _ => Err(anyhow::anyhow!("Unknown tree layout '{}'", name)),
+        }
+    }
+}
+
+/// Options for building a `MerkleTree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeOptions {
+    pub layout: TreeLayout,
+    /// Sort leaves by hash before building (OZ's `sortLeaves`, on by default).
+    /// Unsorted trees keep leaves in input order, so a leaf's position never depends on its hash,
+    /// which lets incremental updates touch a single path.
+    pub sort_leaves: bool,
+    /// Root reported when the tree has no leaves (`EMPTY_ROOT` by default).
+    /// A sentinel must never equal a leaf hash, or a leaf would verify with an empty proof.
+    pub empty_root: [u8; 32],
+}
+
+impl Default for TreeOptions {
+    fn default() -> Self {
+        TreeOptions {
+            layout: TreeLayout::Layered,
+            sort_leaves: true,
+            empty_root: EMPTY_ROOT,
+        }
+    }
+}
+
+/// Node arrangement of a `MerkleTree`; independent of the hasher.
+pub(super) enum Nodes {
+    /// layers[0] = leaves, layers[last] = root. An empty tree is a single empty layer.
+    Layered(Vec<Vec<[u8; 32]>>),
+    /// Flat OZ array: tree[0] = root, leaf slot `i` lives at tree index `tree.len() - 1 - i`.
+    /// All three vectors are empty for an empty tree.
+    /// `tree_indices[v]` is the tree index of the v-th input leaf (OZ's `values[v].treeIndex`)
+    /// and `order[i]` is the input index of the leaf in slot `i`.
+    Standard {
+        tree: Vec<[u8; 32]>,
+        tree_indices: Vec<usize>,
+        order: Vec<usize>,
+    },
 }
 
-impl OzMerkleTree {
+/// A Merkle tree with OpenZeppelin's shape and sorted-pair hashing, generic over the hasher.
+pub struct MerkleTree<H: MerkleHasher = Keccak256Hasher> {
+    pub(super) nodes: Nodes,
+    pub(super) sort_leaves: bool,
+    pub(super) empty_root: [u8; 32],
+    _hasher: PhantomData<H>,
+}
+
+/// An OpenZeppelin-compatible Merkle tree.
+/// Uses Keccak256 sorted-pair hashing so proofs work with `MerkleProof.verify`.
+pub type OzMerkleTree = MerkleTree<Keccak256Hasher>;
+
+impl<H: MerkleHasher> MerkleTree<H> {
+    pub(super) fn from_nodes(nodes: Nodes, options: TreeOptions) -> Self {
+        MerkleTree {
+            nodes,
+            sort_leaves: options.sort_leaves,
+            empty_root: options.empty_root,
+            _hasher: PhantomData,
+        }
+    }
+
     /// Build the tree from a set of leaf hashes.
     /// Leaves should already be double-hashed via `compute_leaf`.
     pub fn from_leaves(leaves: &[[u8; 32]]) -> Self {
-        assert!(!leaves.is_empty(), "Cannot build tree from empty leaves");
+        Self::from_leaves_with_options(leaves, TreeOptions::default())
+    }
 
-        // Sort leaves for deterministic ordering (matches OZ StandardMerkleTree)
-        let mut sorted_leaves = leaves.to_vec();
-        sorted_leaves.sort();
+    /// Build the tree from a set of leaf hashes using the given layout.
+    pub fn from_leaves_with_layout(leaves: &[[u8; 32]], layout: TreeLayout) -> Self {
+        Self::from_leaves_with_options(
+            leaves,
+            TreeOptions {
+                layout,
+                ..TreeOptions::default()
+            },
+        )
+    }
 
-        let mut layers: Vec<Vec<[u8; 32]>> = vec![sorted_leaves];
+    /// Build the tree from a set of leaf hashes using the given options.
+    /// Without leaves, the tree's root is `options.empty_root`.
+    pub fn from_leaves_with_options(leaves: &[[u8; 32]], options: TreeOptions) -> Self {
+        let nodes = match options.layout {
+            TreeLayout::Layered => Nodes::Layered(build_layers::<H>(leaves, options.sort_leaves)),
+            TreeLayout::Standard => build_standard::<H>(leaves, options.sort_leaves),
+        };
 
-        // Build layers bottom-up
-        while layers.last().unwrap().len() > 1 {
-            let current = layers.last().unwrap();
-            let mut next_layer = Vec::new();
+        Self::from_nodes(nodes, options)
+    }
 
-            for chunk in current.chunks(2) {
-                if chunk.len() == 2 {
-                    next_layer.push(hash_pair(chunk[0], chunk[1]));
-                } else {
-                    // Odd node: promote it up (OZ behavior)
-                    next_layer.push(chunk[0]);
-                }
+    /// Like `from_leaves_with_options`, but fails if two leaves are equal.
+    /// A tree keeps duplicate leaves, but `get_proof` only ever finds the first of them.
+    pub fn try_from_leaves_with_options(leaves: &[[u8; 32]], options: TreeOptions) -> Result<Self> {
+        if let Some(&(index, first)) = find_duplicate_leaves(leaves).first() {
+            return Err(anyhow::anyhow!(
+                "Leaf {} duplicates leaf {} (0x{})",
+                index,
+                first,
+                hex::encode(leaves[index])
+            ));
+        }
+        Ok(Self::from_leaves_with_options(leaves, options))
+    }
+
+    /// Rebuild a standard-layout tree from leaves and their OZ tree indices,
+    /// e.g. as recorded in a `standard-v1` dump. The indices may come from an
+    /// unsorted OZ tree, but must cover every leaf slot exactly once.
+    /// Without leaves this is the empty standard tree, whose root is `EMPTY_ROOT`.
+    pub fn from_standard_parts(leaves: &[[u8; 32]], tree_indices: &[usize]) -> Result<Self> {
+        if leaves.len() != tree_indices.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} tree indices, got {}",
+                leaves.len(),
+                tree_indices.len()
+            ));
+        }
+
+        if leaves.is_empty() {
+            return Ok(Self::from_leaves_with_layout(&[], TreeLayout::Standard));
+        }
+
+        let tree_len = 2 * leaves.len() - 1;
+        let first_leaf = tree_len - leaves.len();
+        let mut order = vec![usize::MAX; leaves.len()];
+        for (value_index, &tree_index) in tree_indices.iter().enumerate() {
+            if tree_index < first_leaf || tree_index >= tree_len {
+                return Err(anyhow::anyhow!(
+                    "Tree index {} is not a leaf slot",
+                    tree_index
+                ));
+            }
+            let slot = &mut order[tree_len - 1 - tree_index];
+            if *slot != usize::MAX {
+                return Err(anyhow::anyhow!("Tree index {} is used twice", tree_index));
             }
+            *slot = value_index;
+        }
+
+        let sort_leaves = order.windows(2).all(|w| leaves[w[0]] <= leaves[w[1]]);
+        let tree = place_standard::<H>(leaves, tree_indices);
+        let nodes = Nodes::Standard {
+            tree,
+            tree_indices: tree_indices.to_vec(),
+            order,
+        };
+        let options = TreeOptions {
+            layout: TreeLayout::Standard,
+            sort_leaves,
+            ..TreeOptions::default()
+        };
+        Ok(Self::from_nodes(nodes, options))
+    }
+
+    /// Whether leaves are kept sorted by hash
+    pub fn sorts_leaves(&self) -> bool {
+        self.sort_leaves
+    }
 
-            layers.push(next_layer);
+    /// The options this tree was built with
+    pub fn options(&self) -> TreeOptions {
+        TreeOptions {
+            layout: self.layout(),
+            sort_leaves: self.sort_leaves,
+            empty_root: self.empty_root,
         }
+    }
 
-        OzMerkleTree { layers }
+    /// The layout this tree was built with
+    pub fn layout(&self) -> TreeLayout {
+        match self.nodes {
+            Nodes::Layered(_) => TreeLayout::Layered,
+            Nodes::Standard { .. } => TreeLayout::Standard,
+        }
     }
 
-    /// Get the root hash
+    /// Number of leaves in the tree
+    pub fn leaf_count(&self) -> usize {
+        match &self.nodes {
+            Nodes::Layered(layers) => layers[0].len(),
+            Nodes::Standard { tree_indices, .. } => tree_indices.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaf_count() == 0
+    }
+
+    /// Get the root hash, or the configured empty root if the tree has no leaves
     pub fn root(&self) -> [u8; 32] {
-        *self.layers.last().unwrap().first().unwrap()
+        let root = match &self.nodes {
+            Nodes::Layered(layers) => layers.last().unwrap().first(),
+            Nodes::Standard { tree, .. } => tree.first(),
+        };
+        root.copied().unwrap_or(self.empty_root)
     }
 
-    /// Generate a Merkle proof for a given leaf hash.
-    /// Returns None if the leaf is not in the tree.
-    pub fn get_proof(&self, leaf: &[u8; 32]) -> Option<Vec<[u8; 32]>> {
-        let leaves = &self.layers[0];
-        let mut index = leaves.iter().position(|l| l == leaf)?;
+    /// The flat OZ node array (`StandardMerkleTree.tree`), or None for the layered layout.
+    pub fn standard_tree(&self) -> Option<&[[u8; 32]]> {
+        match &self.nodes {
+            Nodes::Layered(_) => None,
+            Nodes::Standard { tree, .. } => Some(tree),
+        }
+    }
 
-        let mut proof = Vec::new();
+    /// The node layers, leaves first and root last, or None for the standard layout.
+    pub fn layers(&self) -> Option<&[Vec<[u8; 32]>]> {
+        match &self.nodes {
+            Nodes::Layered(layers) => Some(layers),
+            Nodes::Standard { .. } => None,
+        }
+    }
 
-        for layer in &self.layers[..self.layers.len() - 1] {
-            let sibling_index = if index % 2 == 0 {
-                index + 1
-            } else {
-                index - 1
-            };
+    /// OZ tree index of the `value_index`-th input leaf (standard layout only).
+    pub fn tree_index(&self, value_index: usize) -> Option<usize> {
+        match &self.nodes {
+            Nodes::Layered(_) => None,
+            Nodes::Standard { tree_indices, .. } => tree_indices.get(value_index).copied(),
+        }
+    }
 
-            if sibling_index < layer.len() {
-                proof.push(layer[sibling_index]);
+    /// Every leaf with its position: the index in the leaf layer for `TreeLayout::Layered`,
+    /// the OZ tree index for `TreeLayout::Standard`.
+    pub fn leaf_positions(&self) -> Vec<(usize, [u8; 32])> {
+        match &self.nodes {
+            Nodes::Layered(layers) => layers[0].iter().copied().enumerate().collect(),
+            Nodes::Standard {
+                tree, tree_indices, ..
+            } => {
+                let first_leaf = tree.len() - tree_indices.len();
+                (first_leaf..tree.len()).map(|i| (i, tree[i])).collect()
             }
+        }
+    }
+
+    /// The leaf at `position` (see `leaf_positions`), if it is a leaf position.
+    pub fn leaf_at(&self, position: usize) -> Option<[u8; 32]> {
+        match &self.nodes {
+            Nodes::Layered(layers) => layers[0].get(position).copied(),
+            Nodes::Standard {
+                tree, tree_indices, ..
+            } => {
+                if position < tree.len() - tree_indices.len() {
+                    return None;
+                }
+                tree.get(position).copied()
+            }
+        }
+    }
+
+    /// Generate a Merkle proof for a given leaf hash.
+    /// Returns None if the leaf is not in the tree.
+    /// This scans the leaves; use a `ProofIndex` and `get_proof_at` for repeated lookups.
+    pub fn get_proof(&self, leaf: &[u8; 32]) -> Option<Proof<H>> {
+        self.get_proof_at(self.position_of(leaf)?)
+    }
 
-            // Move to parent index
-            index /= 2;
+    /// Position of the first occurrence of `leaf` (see `leaf_positions`), scanning the leaves
+    /// in place.
+    fn position_of(&self, leaf: &[u8; 32]) -> Option<usize> {
+        match &self.nodes {
+            Nodes::Layered(layers) => layers[0].iter().position(|l| l == leaf),
+            Nodes::Standard {
+                tree, tree_indices, ..
+            } => {
+                let first_leaf = tree.len() - tree_indices.len();
+                let index = tree[first_leaf..].iter().position(|l| l == leaf)?;
+                Some(first_leaf + index)
+            }
         }
+    }
+
+    /// Generate a proof for the leaf at `position` (see `leaf_positions`) without searching.
+    pub fn get_proof_at(&self, position: usize) -> Option<Proof<H>> {
+        self.leaf_at(position)?;
+        let siblings = match &self.nodes {
+            Nodes::Layered(layers) => layered_proof(layers, position),
+            Nodes::Standard { tree, .. } => standard_proof(tree, position),
+        };
+        Some(Proof::new(siblings))
+    }
 
-        Some(proof)
+    /// Generate a proof for the leaf at `tree_index` in the OZ array (standard layout only).
+    /// Matches `StandardMerkleTree.getProof(index)` once the value index is mapped via `tree_index`.
+    pub fn get_proof_at_tree_index(&self, tree_index: usize) -> Option<Proof<H>> {
+        match &self.nodes {
+            Nodes::Layered(_) => None,
+            Nodes::Standard { .. } => self.get_proof_at(tree_index),
+        }
     }
 
     /// Verify a proof against a root (off-chain verification).
     /// This mirrors OpenZeppelin's MerkleProof.verify logic.
-    pub fn verify(root: &[u8; 32], proof: &[[u8; 32]], leaf: &[u8; 32]) -> bool {
+    pub fn verify(root: &[u8; 32], proof: &Proof<H>, leaf: &[u8; 32]) -> bool {
         let mut computed = *leaf;
-        for sibling in proof {
-            computed = hash_pair(computed, *sibling);
+        for sibling in proof.iter() {
+            computed = H::hash_pair(computed, *sibling);
         }
         computed == *root
     }
+
+    /// Generate a multiproof for several leaves at once (standard layout only).
+    /// Mirrors `getMultiProof` from `@openzeppelin/merkle-tree`, so the result can be passed
+    /// straight to `MerkleProof.multiProofVerify`.
+    /// Returns None if the tree is layered, or a leaf is missing or requested twice.
+    pub fn get_multi_proof(&self, leaves: &[[u8; 32]]) -> Option<MultiProof<H>> {
+        if self.layout() != TreeLayout::Standard {
+            return None;
+        }
+
+        let indices = leaves
+            .iter()
+            .map(|leaf| self.position_of(leaf))
+            .collect::<Option<Vec<_>>>()?;
+        self.get_multi_proof_at(&indices)
+    }
+
+    /// Generate a multiproof for the leaves at the given OZ tree indices (standard layout only),
+    /// without searching for them.
+    /// Returns None if the tree is layered or empty, or an index is not a leaf or is requested twice.
+    pub fn get_multi_proof_at(&self, tree_indices: &[usize]) -> Option<MultiProof<H>> {
+        let Nodes::Standard { tree, .. } = &self.nodes else {
+            return None;
+        };
+        if tree.is_empty() {
+            return None;
+        }
+        if tree_indices.iter().any(|&i| self.leaf_at(i).is_none()) {
+            return None;
+        }
+        let mut indices = tree_indices.to_vec();
+
+        // OZ processes leaves from the highest tree index down
+        indices.sort_unstable_by(|a, b| b.cmp(a));
+        if indices.windows(2).any(|w| w[0] == w[1]) {
+            return None;
+        }
+
+        let mut queue: VecDeque<usize> = indices.iter().copied().collect();
+        let mut proof = Vec::new();
+        let mut proof_flags = Vec::new();
+
+        while let Some(&index) = queue.front() {
+            if index == 0 {
+                break;
+            }
+            queue.pop_front();
+
+            let sibling_index = if index % 2 == 1 { index + 1 } else { index - 1 };
+            if queue.front() == Some(&sibling_index) {
+                // Sibling is already known: hash two queued nodes together
+                proof_flags.push(true);
+                queue.pop_front();
+            } else {
+                proof_flags.push(false);
+                proof.push(tree[sibling_index]);
+            }
+            queue.push_back((index - 1) / 2);
+        }
+
+        if indices.is_empty() {
+            proof.push(tree[0]);
+        }
+
+        Some(MultiProof::new(
+            indices.iter().map(|&i| tree[i]).collect(),
+            proof,
+            proof_flags,
+        ))
+    }
+
+    /// Recompute the root from a multiproof.
+    /// This mirrors OpenZeppelin's MerkleProof.processMultiProof, including its
+    /// rejection of proofs whose lengths do not add up.
+    pub fn process_multi_proof(multi_proof: &MultiProof<H>) -> Option<[u8; 32]> {
+        let MultiProof {
+            leaves,
+            proof,
+            proof_flags,
+            ..
+        } = multi_proof;
+
+        if leaves.len() + proof.len() != proof_flags.len() + 1 {
+            return None;
+        }
+
+        let mut queue: VecDeque<[u8; 32]> = leaves.iter().copied().collect();
+        let mut proof_iter = proof.iter().copied();
+
+        for &flag in proof_flags {
+            let a = queue.pop_front()?;
+            let b = if flag {
+                queue.pop_front()?
+            } else {
+                proof_iter.next()?
+            };
+            queue.push_back(H::hash_pair(a, b));
+        }
+
+        if proof_flags.is_empty() {
+            return leaves.first().or(proof.first()).copied();
+        }
+
+        // Every proof element must have been consumed, as the Solidity version requires
+        if proof_iter.next().is_some() {
+            return None;
+        }
+        queue.pop_back()
+    }
+
+    /// Verify a multiproof against a root (off-chain verification).
+    /// This mirrors OpenZeppelin's MerkleProof.multiProofVerify logic.
+    pub fn verify_multi(root: &[u8; 32], multi_proof: &MultiProof<H>) -> bool {
+        Self::process_multi_proof(multi_proof) == Some(*root)
+    }
+}
+
+fn build_layers<H: MerkleHasher>(leaves: &[[u8; 32]], sort_leaves: bool) -> Vec<Vec<[u8; 32]>> {
+    // Sort leaves for deterministic ordering
+    let mut leaf_layer = leaves.to_vec();
+    if sort_leaves {
+        leaf_layer.sort();
+    }
+
+    let mut layers: Vec<Vec<[u8; 32]>> = vec![leaf_layer];
+
+    // Build layers bottom-up
+    while layers.last().unwrap().len() > 1 {
+        let current = layers.last().unwrap();
+        let mut next_layer = Vec::new();
+
+        for chunk in current.chunks(2) {
+            if chunk.len() == 2 {
+                next_layer.push(H::hash_pair(chunk[0], chunk[1]));
+            } else {
+                // Odd node: promote it up
+                next_layer.push(chunk[0]);
+            }
+        }
+
+        layers.push(next_layer);
+    }
+
+    layers
+}
+
+fn layered_proof(layers: &[Vec<[u8; 32]>], mut index: usize) -> Vec<[u8; 32]> {
+    let mut proof = Vec::new();
+
+    for layer in &layers[..layers.len() - 1] {
+        let sibling_index = if index.is_multiple_of(2) {
+            index + 1
+        } else {
+            index - 1
+        };
+
+        if sibling_index < layer.len() {
+            proof.push(layer[sibling_index]);
+        }
+
+        // Move to parent index
+        index /= 2;
+    }
+
+    proof
+}
+
+/// Mirrors `makeMerkleTree` from `@openzeppelin/merkle-tree`: leaves (sorted by hash unless
+/// `sort_leaves` is off) are written to the end of a `2n - 1` array in reverse,
+/// then parents are filled right to left.
+pub(super) fn build_standard<H: MerkleHasher>(leaves: &[[u8; 32]], sort_leaves: bool) -> Nodes {
+    if leaves.is_empty() {
+        return Nodes::Standard {
+            tree: Vec::new(),
+            tree_indices: Vec::new(),
+            order: Vec::new(),
+        };
+    }
+
+    let mut order: Vec<usize> = (0..leaves.len()).collect();
+    if sort_leaves {
+        order.sort_by(|&a, &b| leaves[a].cmp(&leaves[b]));
+    }
+
+    let tree_len = 2 * leaves.len() - 1;
+    let mut tree_indices = vec![0usize; leaves.len()];
+    for (i, &value_index) in order.iter().enumerate() {
+        tree_indices[value_index] = tree_len - 1 - i;
+    }
+
+    Nodes::Standard {
+        tree: place_standard::<H>(leaves, &tree_indices),
+        tree_indices,
+        order,
+    }
+}
+
+/// Write each leaf at its tree index and hash the internal nodes right to left.
+/// `tree_indices` must be a permutation of the leaf slots `n - 1..2n - 1`.
+pub(super) fn place_standard<H: MerkleHasher>(
+    leaves: &[[u8; 32]],
+    tree_indices: &[usize],
+) -> Vec<[u8; 32]> {
+    let tree_len = 2 * leaves.len() - 1;
+    let mut tree = vec![[0u8; 32]; tree_len];
+
+    for (leaf, &tree_index) in leaves.iter().zip(tree_indices) {
+        tree[tree_index] = *leaf;
+    }
+
+    for i in (0..tree_len - leaves.len()).rev() {
+        tree[i] = H::hash_pair(tree[2 * i + 1], tree[2 * i + 2]);
+    }
+
+    tree
+}
+
+/// Every leaf equal to an earlier one, as `(index, index of its first occurrence)`,
+/// in input order.
+pub fn find_duplicate_leaves(leaves: &[[u8; 32]]) -> Vec<(usize, usize)> {
+    let mut first_seen: HashMap<&[u8; 32], usize> = HashMap::with_capacity(leaves.len());
+    leaves
+        .iter()
+        .enumerate()
+        .filter_map(|(index, leaf)| match first_seen.get(leaf) {
+            Some(&first) => Some((index, first)),
+            None => {
+                first_seen.insert(leaf, index);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Fail if two subscribers produce the same leaf, e.g. the same address in different case.
+/// `subscribers[i]` must be the value behind `leaves[i]`.
+pub fn check_unique_leaves(
+    leaves: &[[u8; 32]],
+    subscribers: &[(String, Expiration)],
+) -> Result<()> {
+    match find_duplicate_leaves(leaves).first() {
+        Some(&(index, first)) => Err(anyhow::anyhow!(
+            "Subscriber {} has the same leaf as subscriber {}",
+            subscribers[index].0,
+            subscribers[first].0
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Mirrors `getProof` from `@openzeppelin/merkle-tree`: collect siblings up to the root.
+fn standard_proof(tree: &[[u8; 32]], mut index: usize) -> Vec<[u8; 32]> {
+    let mut proof = Vec::new();
+
+    while index > 0 {
+        // Odd indices are left children, even indices right children
+        let sibling_index = if index % 2 == 1 { index + 1 } else { index - 1 };
+        proof.push(tree[sibling_index]);
+        index = (index - 1) / 2;
+    }
+
+    proof
 }
 
 // ───────────────────────────────────────────────────
 // Public API used by main.rs
 // ───────────────────────────────────────────────────
 
-pub async fn build_tree_from_db(
+/// `(root_hex, tree, subscribers)` where `subscribers[i]` is the value behind the i-th leaf
+pub type BuiltTree<H = Keccak256Hasher> = (String, MerkleTree<H>, Vec<(String, Expiration)>);
+
+pub async fn build_tree_from_db(pool: &PgPool) -> Result<BuiltTree> {
+    build_tree_from_db_with_layout(pool, TreeLayout::Layered).await
+}
+
+/// Same as `build_tree_from_db`, but with an explicit tree layout.
+/// Use `TreeLayout::Standard` when the root must match `@openzeppelin/merkle-tree`.
+pub async fn build_tree_from_db_with_layout(
     pool: &PgPool,
-) -> Result<(String, OzMerkleTree, Vec<(String, i64)>)> {
-    let rows = sqlx::query_as::<_, (String, i64)>(
-        "SELECT wallet_address, expiration_ts FROM subscriber_storage",
-    )
-    .fetch_all(pool)
-    .await?;
+    layout: TreeLayout,
+) -> Result<BuiltTree> {
+    let options = TreeOptions {
+        layout,
+        ..TreeOptions::default()
+    };
+    build_tree_from_db_with_options(pool, options).await
+}
 
-    let mut subscribers = rows;
-    if subscribers.is_empty() {
-        return Err(anyhow::anyhow!("No subscribers found in database"));
-    }
+/// Same as `build_tree_from_db`, but with explicit tree options.
+pub async fn build_tree_from_db_with_options(
+    pool: &PgPool,
+    options: TreeOptions,
+) -> Result<BuiltTree> {
+    build_tree_from_db_with_hasher::<Keccak256Hasher>(pool, options).await
+}
 
-    // Sort by wallet_address to keep the tree deterministic
-    subscribers.sort_by(|a, b| a.0.cmp(&b.0));
+/// Same as `build_tree_from_db_with_options`, but committing with hasher `H`,
+/// e.g. `PoseidonHasher` for a ZK verifier. Only Keccak256 roots verify on EVM chains.
+pub async fn build_tree_from_db_with_hasher<H: MerkleHasher>(
+    pool: &PgPool,
+    options: TreeOptions,
+) -> Result<BuiltTree<H>> {
+    let subscribers = load_subscribers(pool).await?;
 
     // Build leaves using the OZ-compatible double hash
-    let leaves: Vec<[u8; 32]> = subscribers
+    let leaves = subscribers
         .iter()
-        .map(|(address, exp)| compute_leaf(address, *exp).expect("Failed to compute leaf"))
-        .collect();
+        .map(|(address, exp)| {
+            compute_leaf_with::<H>(address, *exp)
+                .with_context(|| format!("Invalid wallet address for subscriber {}", address))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    check_unique_leaves(&leaves, &subscribers)?;
 
-    let tree = OzMerkleTree::from_leaves(&leaves);
+    let tree = MerkleTree::<H>::from_leaves_with_options(&leaves, options);
     let root = tree.root();
 
     Ok((hex::encode(root), tree, subscribers))
 }
 
+/// Load every subscriber of `DEFAULT_PRODUCT`, sorted by wallet_address to keep the tree
+/// deterministic.
+pub async fn load_subscribers(pool: &PgPool) -> Result<Vec<(String, Expiration)>> {
+    let rows = sqlx::query_as::<_, (String, i64)>(
+        "SELECT wallet_address, expiration_ts FROM subscriber_storage WHERE product_id = $1",
+    )
+    .bind(DEFAULT_PRODUCT as i32)
+    .fetch_all(pool)
+    .await?;
+
+    let mut subscribers = rows
+        .into_iter()
+        .map(|(address, exp)| {
+            let exp = Expiration::from_unix(exp)
+                .with_context(|| format!("Invalid expiration for subscriber {}", address))?;
+            Ok((address, exp))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    subscribers.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(subscribers)
+}
+
 /// Get a Merkle proof for a specific user.
 /// Returns the proof as Vec<[u8; 32]> compatible with Solidity's bytes32[].
-pub fn get_proof_for_user(
-    tree: &OzMerkleTree,
-    subscribers: &[(String, i64)],
+/// Scans `subscribers` and the leaves; `ProofIndex` serves the same proof without scanning.
+pub fn get_proof_for_user<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscribers: &[(String, Expiration)],
     user_pubkey: &str,
-) -> Option<Vec<[u8; 32]>> {
+) -> Option<Proof<H>> {
     let (_, exp) = subscribers.iter().find(|(pk, _)| pk == user_pubkey)?;
-    let leaf = compute_leaf(user_pubkey, *exp).ok()?;
+    let leaf = compute_leaf_with::<H>(user_pubkey, *exp).ok()?;
     tree.get_proof(&leaf)
 }
 
-/// Off-chain verification of a subscription proof.
-/// Uses the same sorted-pair hashing as OpenZeppelin, so if this passes,
-/// the on-chain verification will pass too.
-pub fn verify_subscription(
+/// Get a multiproof covering several users at once, e.g. for a relayer batch.
+/// Multiproofs are only defined for `TreeLayout::Standard`; trees built with the default
+/// `TreeLayout::Layered` are rejected with an error naming the layout.
+/// Also fails if a user is unknown or requested twice.
+pub fn get_multi_proof_for_users<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    subscribers: &[(String, Expiration)],
+    user_pubkeys: &[&str],
+) -> Result<MultiProof<H>> {
+    ensure_multi_proof_layout(tree.layout())?;
+
+    let mut leaves = Vec::with_capacity(user_pubkeys.len());
+    for user_pubkey in user_pubkeys {
+        let (_, exp) = subscribers
+            .iter()
+            .find(|(pk, _)| pk == user_pubkey)
+            .with_context(|| format!("Subscriber {} is not in the tree", user_pubkey))?;
+        leaves.push(compute_leaf_with::<H>(user_pubkey, *exp)?);
+    }
+    tree.get_multi_proof(&leaves)
+        .context("Every subscriber of a multiproof must be requested once")
+}
+
+/// Fail unless multiproofs can be generated for trees of `layout`
+pub(super) fn ensure_multi_proof_layout(layout: TreeLayout) -> Result<()> {
+    match layout {
+        TreeLayout::Standard => Ok(()),
+        TreeLayout::Layered => Err(anyhow::anyhow!(
+            "Multiproofs need the standard tree layout, but the tree uses the {} layout",
+            layout.name()
+        )),
+    }
+}
+
+/// Get a Merkle proof for a leaf of any shape, given its values and encoding.
+pub fn get_proof_for_value<H: MerkleHasher>(
+    tree: &MerkleTree<H>,
+    encoding: &LeafEncoding,
+    values: &[Token],
+) -> Option<Proof<H>> {
+    let leaf = encoding.leaf_hash_with::<H>(values).ok()?;
+    tree.get_proof(&leaf)
+}
+
+/// Off-chain verification of a proof for a leaf of any shape.
+pub fn verify_value<H: MerkleHasher>(
     root_hex: &str,
-    proof: &[[u8; 32]],
-    user_pubkey: &str,
-    expiration_ts: i64,
+    proof: &Proof<H>,
+    encoding: &LeafEncoding,
+    values: &[Token],
 ) -> Result<bool> {
     let root_vec = hex::decode(root_hex).context("Invalid root hex")?;
     let root: [u8; 32] = root_vec
         .try_into()
         .map_err(|_| anyhow::anyhow!("Root must be 32 bytes"))?;
 
-    let leaf = compute_leaf(user_pubkey, expiration_ts)?;
+    let leaf = encoding.leaf_hash_with::<H>(values)?;
+
+    Ok(MerkleTree::<H>::verify(&root, proof, &leaf))
+}
+
+/// Off-chain verification of a subscription proof.
+/// Uses the same sorted-pair hashing as OpenZeppelin, so if this passes for a Keccak256 proof,
+/// the on-chain verification will pass too.
+pub fn verify_subscription<H: MerkleHasher>(
+    root_hex: &str,
+    proof: &Proof<H>,
+    user_pubkey: &str,
+    expiration_ts: Expiration,
+) -> Result<bool> {
+    let values = subscriber_values(user_pubkey, expiration_ts)?;
+    verify_value(root_hex, proof, &LeafEncoding::subscriber(), &values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U256;
+    use std::str::FromStr;
+
+    #[test]
+    fn subscriber_values_take_one_prefix_in_either_case() {
+        let exp = Expiration::new(1_700_000_000).unwrap();
+        let address = "0xabcdefabcdefabcdefabcdefabcdefabcdefabcd";
+        let expected = subscriber_values(address, exp).unwrap();
+        for variant in [
+            "0XABCDEFABCDEFABCDEFABCDEFABCDEFABCDEFABCD",
+            "abcdefabcdefabcdefabcdefabcdefabcdefabcd",
+        ] {
+            assert_eq!(subscriber_values(variant, exp).unwrap(), expected);
+        }
+        assert!(subscriber_values("0x0xabcdefabcdefabcdefabcdefabcdefabcdefabcd", exp).is_err());
+        assert!(subscriber_values("0xabcd", exp).is_err());
+    }
+
+    /// The values of the `@openzeppelin/merkle-tree` README example
+    fn oz_readme_leaves() -> Vec<[u8; 32]> {
+        [
+            (
+                "0x1111111111111111111111111111111111111111",
+                "5000000000000000000",
+            ),
+            (
+                "0x2222222222222222222222222222222222222222",
+                "2500000000000000000",
+            ),
+        ]
+        .iter()
+        .map(|(address, amount)| {
+            let values = [
+                Token::Address(Address::from_str(address).unwrap()),
+                Token::Uint(U256::from_dec_str(amount).unwrap()),
+            ];
+            LeafEncoding::subscriber().leaf_hash(&values).unwrap()
+        })
+        .collect()
+    }
+
+    /// Root printed by `StandardMerkleTree.of(values, ["address", "uint256"])` in the README
+    const OZ_README_ROOT: &str = "d4dee0beab2d53f2cc83e567171bd2820e49898130a22622b10ead383e90bd77";
+
+    fn leaves(n: u8) -> Vec<[u8; 32]> {
+        (0..n)
+            .map(|i| Keccak256Hasher::hash(&[i]))
+            .collect::<Vec<_>>()
+    }
+
+    fn standard(leaves: &[[u8; 32]], sort_leaves: bool) -> OzMerkleTree {
+        let options = TreeOptions {
+            layout: TreeLayout::Standard,
+            sort_leaves,
+            ..TreeOptions::default()
+        };
+        OzMerkleTree::from_leaves_with_options(leaves, options)
+    }
+
+    #[test]
+    fn standard_root_matches_openzeppelin() {
+        let tree = standard(&oz_readme_leaves(), true);
+        assert_eq!(hex::encode(tree.root()), OZ_README_ROOT);
+    }
+
+    #[test]
+    fn standard_array_layout_matches_openzeppelin() {
+        for n in 1..=9 {
+            let leaves = leaves(n);
+            let tree = standard(&leaves, true);
+            let array = tree.standard_tree().unwrap();
+            let n = leaves.len();
+            assert_eq!(array.len(), 2 * n - 1);
 
-    Ok(OzMerkleTree::verify(&root, proof, &leaf))
+            // Sorted leaves fill the array from the end
+            let mut sorted = leaves.clone();
+            sorted.sort();
+            for (slot, leaf) in sorted.iter().enumerate() {
+                assert_eq!(array[array.len() - 1 - slot], *leaf);
+            }
+            for i in 0..n - 1 {
+                assert_eq!(
+                    array[i],
+                    Keccak256Hasher::hash_pair(array[2 * i + 1], array[2 * i + 2])
+                );
+            }
+            assert_eq!(tree.root(), array[0]);
+        }
+    }
+
+    #[test]
+    fn tree_indices_point_at_each_value() {
+        for sort_leaves in [true, false] {
+            let leaves = leaves(7);
+            let tree = standard(&leaves, sort_leaves);
+            let array = tree.standard_tree().unwrap();
+            for (value_index, leaf) in leaves.iter().enumerate() {
+                let tree_index = tree.tree_index(value_index).unwrap();
+                assert_eq!(array[tree_index], *leaf);
+                let proof = tree.get_proof_at_tree_index(tree_index).unwrap();
+                assert!(OzMerkleTree::verify(&tree.root(), &proof, leaf));
+            }
+            assert_eq!(tree.tree_index(leaves.len()), None);
+        }
+
+        // Unsorted trees keep input order: value i sits in slot i
+        let tree = standard(&leaves(5), false);
+        assert_eq!(
+            (0..5)
+                .map(|v| tree.tree_index(v).unwrap())
+                .collect::<Vec<_>>(),
+            vec![8, 7, 6, 5, 4]
+        );
+    }
+
+    #[test]
+    fn layouts_agree_on_full_trees() {
+        for n in [1, 2, 4, 8] {
+            let leaves = leaves(n);
+            let layered = OzMerkleTree::from_leaves(&leaves);
+            assert_eq!(layered.root(), standard(&leaves, true).root());
+        }
+    }
+
+    #[test]
+    fn empty_tree_reports_the_empty_root() {
+        let tree = standard(&[], true);
+        assert_eq!(tree.root(), EMPTY_ROOT);
+        assert!(tree.get_proof_at(0).is_none());
+    }
+
+    /// Direct port of OpenZeppelin's `MerkleProof.processMultiProof`, kept independent of
+    /// `process_multi_proof` so the two can be checked against each other.
+    fn oz_process_multi_proof(
+        proof: &[[u8; 32]],
+        proof_flags: &[bool],
+        leaves: &[[u8; 32]],
+    ) -> Option<[u8; 32]> {
+        let leaves_len = leaves.len();
+        let proof_flags_len = proof_flags.len();
+        if leaves_len + proof.len() != proof_flags_len + 1 {
+            return None;
+        }
+
+        let mut hashes = vec![[0u8; 32]; proof_flags_len];
+        let (mut leaf_pos, mut hash_pos, mut proof_pos) = (0, 0, 0);
+        for i in 0..proof_flags_len {
+            let a = if leaf_pos < leaves_len {
+                leaf_pos += 1;
+                leaves[leaf_pos - 1]
+            } else {
+                hash_pos += 1;
+                hashes[hash_pos - 1]
+            };
+            let b = if proof_flags[i] {
+                if leaf_pos < leaves_len {
+                    leaf_pos += 1;
+                    leaves[leaf_pos - 1]
+                } else {
+                    hash_pos += 1;
+                    hashes[hash_pos - 1]
+                }
+            } else {
+                proof_pos += 1;
+                proof[proof_pos - 1]
+            };
+            hashes[i] = Keccak256Hasher::hash_pair(a, b);
+        }
+
+        if proof_flags_len > 0 {
+            if proof_pos != proof.len() {
+                return None;
+            }
+            Some(hashes[proof_flags_len - 1])
+        } else if leaves_len > 0 {
+            Some(leaves[0])
+        } else {
+            Some(proof[0])
+        }
+    }
+
+    #[test]
+    fn multi_proof_flags_and_order_match_openzeppelin() {
+        let tree = standard(&leaves(4), true);
+        let array = tree.standard_tree().unwrap();
+
+        // Sibling leaves: hashed together first, then with the other half of the tree
+        let multi = tree.get_multi_proof_at(&[3, 4]).unwrap();
+        assert_eq!(multi.leaves, vec![array[4], array[3]]);
+        assert_eq!(multi.proof, vec![array[2]]);
+        assert_eq!(multi.proof_flags, vec![true, false]);
+
+        // Leaves are ordered by descending tree index, whatever the request order
+        let multi = tree.get_multi_proof_at(&[3, 6]).unwrap();
+        assert_eq!(multi, tree.get_multi_proof_at(&[6, 3]).unwrap());
+        assert_eq!(multi.leaves, vec![array[6], array[3]]);
+        assert_eq!(multi.proof, vec![array[5], array[4]]);
+        assert_eq!(multi.proof_flags, vec![false, false, true]);
+        assert!(OzMerkleTree::verify_multi(&tree.root(), &multi));
+
+        // The same leaves in request order no longer verify
+        let mut swapped = multi.clone();
+        swapped.leaves.swap(0, 1);
+        assert!(!OzMerkleTree::verify_multi(&tree.root(), &swapped));
+    }
+
+    #[test]
+    fn multi_proofs_verify_like_openzeppelin() {
+        let readme = standard(&oz_readme_leaves(), true);
+        let multi = readme.get_multi_proof(&oz_readme_leaves()).unwrap();
+        assert_eq!(multi.proof_flags, vec![true]);
+        let root = oz_process_multi_proof(&multi.proof, &multi.proof_flags, &multi.leaves);
+        assert_eq!(hex::encode(root.unwrap()), OZ_README_ROOT);
+
+        let leaves = leaves(9);
+        let tree = standard(&leaves, true);
+        for selection in [vec![0], vec![1, 7], vec![8, 2, 5], (0..9).collect()] {
+            let requested: Vec<_> = selection.iter().map(|&i| leaves[i]).collect();
+            let multi = tree.get_multi_proof(&requested).unwrap();
+            let oz_root = oz_process_multi_proof(&multi.proof, &multi.proof_flags, &multi.leaves);
+            assert_eq!(oz_root, Some(tree.root()));
+            assert_eq!(OzMerkleTree::process_multi_proof(&multi), oz_root);
+        }
+
+        // Lengths that do not add up are rejected, as in Solidity
+        let mut multi = tree.get_multi_proof(&leaves[..1]).unwrap();
+        multi.proof.push([0u8; 32]);
+        assert_eq!(OzMerkleTree::process_multi_proof(&multi), None);
+        assert_eq!(
+            oz_process_multi_proof(&multi.proof, &multi.proof_flags, &multi.leaves),
+            None
+        );
+    }
+
+    #[test]
+    fn multi_proofs_for_users_name_the_layout() {
+        let subscribers: Vec<(String, Expiration)> = (1..=3u8)
+            .map(|i| {
+                (
+                    format!("0x{}", hex::encode([i; 20])),
+                    Expiration::new(1).unwrap(),
+                )
+            })
+            .collect();
+        let leaves: Vec<_> = subscribers
+            .iter()
+            .map(|(address, exp)| compute_leaf(address, *exp).unwrap())
+            .collect();
+        let users = [subscribers[0].0.as_str(), subscribers[2].0.as_str()];
+
+        let layered = OzMerkleTree::from_leaves(&leaves);
+        let err = get_multi_proof_for_users(&layered, &subscribers, &users).unwrap_err();
+        assert!(err.to_string().contains("layered"));
+
+        let tree = standard(&leaves, true);
+        let multi = get_multi_proof_for_users(&tree, &subscribers, &users).unwrap();
+        assert!(OzMerkleTree::verify_multi(&tree.root(), &multi));
+        assert!(get_multi_proof_for_users(&tree, &subscribers, &["0x00"]).is_err());
+    }
 }
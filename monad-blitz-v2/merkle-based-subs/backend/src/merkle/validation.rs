@@ -0,0 +1,364 @@
+use anyhow::Result;
+use sqlx::PgPool;
+use std::collections::HashMap;
+
+use super::products::{compute_product_leaf, ProductSubscription};
+use super::tree::{compute_leaf, OzMerkleTree, TreeOptions};
+use crate::model::{Expiration, PlanId, ProductId, DEFAULT_PRODUCT};
+
+/// What a validated build does with rows that cannot become a leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValidationPolicy {
+    /// Leave rejected and duplicate rows out of the tree and list them in the report
+    #[default]
+    SkipAndReport,
+    /// Abort the build if any row is rejected or duplicated
+    Fail,
+}
+
+/// A row that failed validation and is left out of the tree
+#[derive(Debug, Clone)]
+pub struct RejectedRow {
+    pub wallet_address: String,
+    pub product_id: i32,
+    pub expiration_ts: i64,
+    pub reason: String,
+}
+
+/// A row whose address matches an accepted row once normalized, e.g. a checksummed
+/// and a lowercase spelling of the same wallet.
+#[derive(Debug, Clone)]
+pub struct DuplicateRow {
+    pub wallet_address: String,
+    pub product_id: i32,
+    pub expiration_ts: i64,
+    /// The accepted row's `wallet_address`
+    pub duplicate_of: String,
+}
+
+/// Outcome of the validation pass over `subscriber_storage`
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    /// `DEFAULT_PRODUCT` rows that become leaves, sorted by wallet_address like
+    /// `load_subscribers`
+    pub accepted: Vec<(String, Expiration)>,
+    /// Subscriptions to other products that become product leaves,
+    /// sorted by (wallet_address, product_id)
+    pub products: Vec<ProductSubscription>,
+    /// Rejected and duplicate rows of every product
+    pub rejected: Vec<RejectedRow>,
+    pub duplicates: Vec<DuplicateRow>,
+}
+
+impl ValidationReport {
+    /// True if every row was accepted
+    pub fn is_clean(&self) -> bool {
+        self.rejected.is_empty() && self.duplicates.is_empty()
+    }
+}
+
+/// Lowercase, `0x`-prefixed form of an address, used to detect duplicates.
+/// The prefix itself may be in either case (`0x` or `0X`).
+pub fn normalize_address(address: &str) -> String {
+    let hex_digits = match address.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("0x") => &address[2..],
+        _ => address,
+    };
+    format!("0x{}", hex_digits.to_ascii_lowercase())
+}
+
+/// Sort raw `(wallet_address, expiration_ts)` rows into accepted, rejected and duplicate.
+///
+/// Rows are visited in wallet_address order, so when several rows normalize to the same
+/// address the first one in that order is accepted and the rest are duplicates.
+pub fn validate_rows(mut rows: Vec<(String, i64)>) -> ValidationReport {
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut report = ValidationReport::default();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for (address, expiration_ts) in rows {
+        let checked = Expiration::from_unix(expiration_ts)
+            .and_then(|exp| compute_leaf(&address, exp).map(|_| exp));
+        let expiration = match checked {
+            Ok(expiration) => expiration,
+            Err(e) => {
+                report.rejected.push(RejectedRow {
+                    wallet_address: address,
+                    product_id: DEFAULT_PRODUCT as i32,
+                    expiration_ts,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        match seen.get(&normalize_address(&address)) {
+            Some(&index) => report.duplicates.push(DuplicateRow {
+                wallet_address: address,
+                product_id: DEFAULT_PRODUCT as i32,
+                expiration_ts,
+                duplicate_of: report.accepted[index].0.clone(),
+            }),
+            None => {
+                seen.insert(normalize_address(&address), report.accepted.len());
+                report.accepted.push((address, expiration));
+            }
+        }
+    }
+
+    report
+}
+
+/// Validate raw `(wallet_address, product_id, plan_id, expiration_ts)` rows of products other
+/// than `DEFAULT_PRODUCT` into `report`, like `validate_rows`: a row is a duplicate when its
+/// normalized address and product match an accepted subscription.
+pub fn validate_product_rows(
+    report: &mut ValidationReport,
+    mut rows: Vec<(String, i32, i32, i64)>,
+) {
+    rows.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+
+    let mut seen: HashMap<(String, i32), usize> = HashMap::new();
+    for (address, product_id, plan_id, expiration_ts) in rows {
+        let checked = ProductId::try_from(product_id)
+            .map_err(anyhow::Error::from)
+            .and_then(|product| {
+                let plan = PlanId::try_from(plan_id)?;
+                let expiration = Expiration::from_unix(expiration_ts)?;
+                compute_product_leaf(&address, product, plan, expiration)?;
+                Ok(ProductSubscription {
+                    wallet_address: address.clone(),
+                    product_id: product,
+                    plan_id: plan,
+                    expiration,
+                })
+            });
+        let subscription = match checked {
+            Ok(subscription) => subscription,
+            Err(e) => {
+                report.rejected.push(RejectedRow {
+                    wallet_address: address,
+                    product_id,
+                    expiration_ts,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let key = (normalize_address(&address), product_id);
+        match seen.get(&key) {
+            Some(&index) => report.duplicates.push(DuplicateRow {
+                wallet_address: address,
+                product_id,
+                expiration_ts,
+                duplicate_of: report.products[index].wallet_address.clone(),
+            }),
+            None => {
+                seen.insert(key, report.products.len());
+                report.products.push(subscription);
+            }
+        }
+    }
+}
+
+/// Read and validate every row of `subscriber_storage`: `DEFAULT_PRODUCT` rows with
+/// `validate_rows`, the subscriptions to other products with `validate_product_rows`.
+pub async fn validate_subscribers(pool: &PgPool) -> Result<ValidationReport> {
+    let rows = sqlx::query_as::<_, (String, i64)>(
+        "SELECT wallet_address, expiration_ts FROM subscriber_storage WHERE product_id = $1",
+    )
+    .bind(DEFAULT_PRODUCT as i32)
+    .fetch_all(pool)
+    .await?;
+    let mut report = validate_rows(rows);
+
+    let product_rows = sqlx::query_as::<_, (String, i32, i32, i64)>(
+        "SELECT wallet_address, product_id, plan_id, expiration_ts FROM subscriber_storage
+         WHERE product_id <> $1",
+    )
+    .bind(DEFAULT_PRODUCT as i32)
+    .fetch_all(pool)
+    .await?;
+    validate_product_rows(&mut report, product_rows);
+
+    Ok(report)
+}
+
+/// Replace the contents of `subscriber_quarantine` with the rejected and duplicate rows
+/// of `report`, so rows fixed since the last build drop out of it.
+pub async fn record_quarantine(pool: &PgPool, report: &ValidationReport) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM subscriber_quarantine")
+        .execute(&mut *tx)
+        .await?;
+
+    for row in &report.rejected {
+        sqlx::query!(
+            "INSERT INTO subscriber_quarantine (wallet_address, product_id, expiration_ts, reason)
+             VALUES ($1, $2, $3, $4)",
+            row.wallet_address,
+            row.product_id,
+            row.expiration_ts,
+            row.reason
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for row in &report.duplicates {
+        sqlx::query!(
+            "INSERT INTO subscriber_quarantine
+                 (wallet_address, product_id, expiration_ts, reason, duplicate_of)
+             VALUES ($1, $2, $3, $4, $5)",
+            row.wallet_address,
+            row.product_id,
+            row.expiration_ts,
+            "Duplicate address after normalization",
+            row.duplicate_of
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Validate `subscriber_storage` and write rejected and duplicate rows to
+/// `subscriber_quarantine`. With `ValidationPolicy::Fail` this then aborts if there are any.
+/// Every build that publishes a root starts with this pass.
+pub async fn validate_and_quarantine(
+    pool: &PgPool,
+    policy: ValidationPolicy,
+) -> Result<ValidationReport> {
+    let report = validate_subscribers(pool).await?;
+    record_quarantine(pool, &report).await?;
+
+    if policy == ValidationPolicy::Fail && !report.is_clean() {
+        let first = report
+            .rejected
+            .first()
+            .map(|row| format!("{}: {}", row.wallet_address, row.reason))
+            .or_else(|| {
+                report
+                    .duplicates
+                    .first()
+                    .map(|row| format!("{}: duplicate of {}", row.wallet_address, row.duplicate_of))
+            })
+            .unwrap_or_default();
+        return Err(anyhow::anyhow!(
+            "{} rejected and {} duplicate subscriber rows (first: {})",
+            report.rejected.len(),
+            report.duplicates.len(),
+            first
+        ));
+    }
+
+    // An empty table publishes the empty root; rows that were all rejected are an error
+    let rejected_default = report
+        .rejected
+        .iter()
+        .any(|row| row.product_id == DEFAULT_PRODUCT as i32);
+    if report.accepted.is_empty() && rejected_default {
+        return Err(anyhow::anyhow!("No valid subscribers found in database"));
+    }
+
+    Ok(report)
+}
+
+/// Build the tree from the rows that pass validation.
+///
+/// Rejected and duplicate rows are always written to `subscriber_quarantine`. With
+/// `ValidationPolicy::Fail` the build then aborts if there are any; with
+/// `ValidationPolicy::SkipAndReport` the tree is built from the accepted rows, giving the
+/// same root as `build_tree_from_db_with_options` over just those rows.
+pub async fn build_tree_from_db_validated(
+    pool: &PgPool,
+    options: TreeOptions,
+    policy: ValidationPolicy,
+) -> Result<(String, OzMerkleTree, ValidationReport)> {
+    let report = validate_and_quarantine(pool, policy).await?;
+
+    let leaves = report
+        .accepted
+        .iter()
+        .map(|(address, exp)| compute_leaf(address, *exp))
+        .collect::<Result<Vec<_>>>()?;
+    let tree = OzMerkleTree::from_leaves_with_options(&leaves, options);
+
+    Ok((hex::encode(tree.root()), tree, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_any_prefix_and_case() {
+        let normalized = "0xabcdefabcdefabcdefabcdefabcdefabcdefabcd";
+        for address in [
+            "0xabcdefabcdefabcdefabcdefabcdefabcdefabcd",
+            "0XABCDEFABCDEFABCDEFABCDEFABCDEFABCDEFABCD",
+            "0xAbCdEfAbCdEfAbCdEfAbCdEfAbCdEfAbCdEfAbCd",
+            "abcdefabcdefabcdefabcdefabcdefabcdefabcd",
+        ] {
+            assert_eq!(normalize_address(address), normalized);
+        }
+        assert_eq!(normalize_address(""), "0x");
+    }
+
+    #[test]
+    fn case_variants_are_duplicates() {
+        let rows = vec![
+            ("0xabcdefabcdefabcdefabcdefabcdefabcdefabcd".to_string(), 1),
+            ("0xABCDEFABCDEFABCDEFABCDEFABCDEFABCDEFABCD".to_string(), 2),
+            ("0x1111111111111111111111111111111111111111".to_string(), -1),
+        ];
+        let report = validate_rows(rows);
+        assert_eq!(report.accepted.len(), 1);
+        assert_eq!(report.duplicates.len(), 1);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.duplicates[0].duplicate_of, report.accepted[0].0);
+    }
+
+    #[test]
+    fn product_rows_are_validated_per_product() {
+        let address = "0xabcdefabcdefabcdefabcdefabcdefabcdefabcd".to_string();
+        let upper_case = "0xABCDEFABCDEFABCDEFABCDEFABCDEFABCDEFABCD".to_string();
+        let rows = vec![
+            (address.clone(), 7, 1, 1_700_000_000),
+            // Same wallet, other product: its own subscription
+            (upper_case.clone(), 8, 1, 1_700_000_000),
+            // Case variant for the same product
+            (upper_case.clone(), 7, 2, 1_700_000_000),
+            ("0x1234".to_string(), 7, 1, 1_700_000_000),
+            (
+                "0x1111111111111111111111111111111111111111".to_string(),
+                7,
+                1,
+                -1,
+            ),
+        ];
+        let mut report = ValidationReport::default();
+        validate_product_rows(&mut report, rows);
+
+        let accepted: Vec<_> = report
+            .products
+            .iter()
+            .map(|s| (s.wallet_address.as_str(), s.product_id))
+            .collect();
+        assert_eq!(
+            accepted,
+            [(upper_case.as_str(), 7), (upper_case.as_str(), 8)]
+        );
+        assert_eq!(report.duplicates.len(), 1);
+        assert_eq!(report.duplicates[0].wallet_address, address);
+        assert_eq!(report.duplicates[0].product_id, 7);
+        assert_eq!(report.rejected.len(), 2);
+        assert!(report.rejected.iter().all(|row| row.product_id == 7));
+        assert!(report.accepted.is_empty());
+    }
+}
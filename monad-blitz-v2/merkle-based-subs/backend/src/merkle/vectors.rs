@@ -0,0 +1,304 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+
+use super::encoding::SUBSCRIBER_LEAF_ENCODING;
+use super::hasher::{Keccak256Hasher, MerkleHasher};
+use super::tree::{compute_leaf, OzMerkleTree, Proof, TreeLayout, TreeOptions};
+use crate::model::Expiration;
+
+// Deterministic test vectors shared with the Solidity tests and the TypeScript frontend.
+// Every case lists its subscribers, the leaf `compute_leaf` gives each of them, the full
+// node structure, the root and one proof per subscriber, so the other implementations can
+// check their leaf encoding, tree construction and proof generation byte for byte.
+//
+// The inputs never change between runs: addresses are the last 20 bytes of
+// keccak256("subscriber-<i>") and expirations are a fixed base plus whole days.
+//
+// The golden copy is committed as `test-vectors/subscription-vectors-v1.json`; regenerate it
+// with `TEST_VECTORS_PATH=test-vectors/subscription-vectors-v1.json cargo run` after an
+// intentional change, and the unit tests fail on any other difference.
+
+/// Format tag of the test vector file
+pub const TEST_VECTORS_V1: &str = "subscription-vectors-v1";
+
+/// Expiration of the first generated subscriber
+const BASE_EXPIRATION: u64 = 1_700_000_000;
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestVectors {
+    pub format: String,
+    /// Solidity types of the leaf values, as in a `standard-v1` dump
+    pub leaf_encoding: Vec<String>,
+    pub cases: Vec<TestCase>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VectorSubscriber {
+    pub address: String,
+    pub expiration: Expiration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestCase {
+    pub name: String,
+    /// `layered` or `standard`
+    pub layout: String,
+    pub sort_leaves: bool,
+    pub subscribers: Vec<VectorSubscriber>,
+    /// 0x-prefixed leaf of each subscriber, in subscriber order
+    pub leaves: Vec<String>,
+    /// Node layers, leaves first and root last (layered layout only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layers: Option<Vec<Vec<String>>>,
+    /// Flat OZ node array, root first (standard layout only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree: Option<Vec<String>>,
+    pub root: String,
+    /// Proof of each subscriber, in subscriber order, leaf sibling first.
+    /// Equal subscribers get the proofs of distinct leaf positions.
+    pub proofs: Vec<Vec<String>>,
+}
+
+/// The i-th generated subscriber
+fn vector_subscriber(i: u64) -> VectorSubscriber {
+    let hash = Keccak256Hasher::hash(format!("subscriber-{}", i).as_bytes());
+    VectorSubscriber {
+        address: format!("0x{}", hex::encode(&hash[12..])),
+        expiration: Expiration::new(BASE_EXPIRATION + i * DAY).expect("fits in i64"),
+    }
+}
+
+fn to_hex(hash: &[u8; 32]) -> String {
+    format!("0x{}", hex::encode(hash))
+}
+
+fn hex_list(hashes: &[[u8; 32]]) -> Vec<String> {
+    hashes.iter().map(to_hex).collect()
+}
+
+/// Build one case from its inputs
+pub fn build_case(
+    name: &str,
+    options: TreeOptions,
+    subscribers: Vec<VectorSubscriber>,
+) -> Result<TestCase> {
+    let leaves = subscribers
+        .iter()
+        .map(|s| compute_leaf(&s.address, s.expiration))
+        .collect::<Result<Vec<_>>>()?;
+    let tree = OzMerkleTree::from_leaves_with_options(&leaves, options);
+
+    // Positions of each leaf hash, so equal leaves each get their own position
+    let mut positions: HashMap<[u8; 32], VecDeque<usize>> = HashMap::new();
+    for (position, leaf) in tree.leaf_positions() {
+        positions.entry(leaf).or_default().push_back(position);
+    }
+    let proofs = leaves
+        .iter()
+        .enumerate()
+        .map(|(value_index, leaf)| {
+            let position = match tree.tree_index(value_index) {
+                Some(tree_index) => Some(tree_index),
+                None => positions.get_mut(leaf).and_then(VecDeque::pop_front),
+            };
+            let proof = position
+                .and_then(|position| tree.get_proof_at(position))
+                .with_context(|| format!("{}: no proof for value {}", name, value_index))?;
+            Ok(hex_list(&proof))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(TestCase {
+        name: name.to_string(),
+        layout: options.layout.name().to_string(),
+        sort_leaves: options.sort_leaves,
+        subscribers,
+        leaves: hex_list(&leaves),
+        layers: tree
+            .layers()
+            .map(|layers| layers.iter().map(|layer| hex_list(layer)).collect()),
+        tree: tree.standard_tree().map(hex_list),
+        root: to_hex(&tree.root()),
+        proofs,
+    })
+}
+
+/// Generate the full vector set: both layouts, a single leaf, odd and power-of-two sizes,
+/// unsorted leaves, and duplicates (an exact repeat and a case-variant address).
+pub fn generate_test_vectors() -> Result<TestVectors> {
+    let generated = |count: u64| (0..count).map(vector_subscriber).collect::<Vec<_>>();
+
+    let mut case_variant = vector_subscriber(0);
+    case_variant.address = format!(
+        "0x{}",
+        case_variant.address.trim_start_matches("0x").to_uppercase()
+    );
+
+    let mut inputs: Vec<(&str, bool, Vec<VectorSubscriber>)> = vec![
+        ("single-leaf", true, generated(1)),
+        ("two-leaves", true, generated(2)),
+        ("three-leaves", true, generated(3)),
+        ("five-leaves", true, generated(5)),
+        ("seven-leaves", true, generated(7)),
+        ("eight-leaves", true, generated(8)),
+        ("unsorted-five-leaves", false, generated(5)),
+    ];
+    inputs.push((
+        "duplicate-value",
+        true,
+        vec![
+            vector_subscriber(0),
+            vector_subscriber(1),
+            vector_subscriber(0),
+            vector_subscriber(2),
+        ],
+    ));
+    inputs.push((
+        "case-variant-duplicate",
+        true,
+        vec![vector_subscriber(0), case_variant, vector_subscriber(1)],
+    ));
+
+    let mut cases = Vec::new();
+    for layout in [TreeLayout::Layered, TreeLayout::Standard] {
+        for (name, sort_leaves, subscribers) in &inputs {
+            let options = TreeOptions {
+                layout,
+                sort_leaves: *sort_leaves,
+                ..TreeOptions::default()
+            };
+            let name = format!("{}-{}", layout.name(), name);
+            cases.push(build_case(&name, options, subscribers.clone())?);
+        }
+    }
+
+    Ok(TestVectors {
+        format: TEST_VECTORS_V1.to_string(),
+        leaf_encoding: SUBSCRIBER_LEAF_ENCODING
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+        cases,
+    })
+}
+
+/// Re-check a vector set against this implementation: every leaf, node, root and proof
+/// must match a rebuild from the subscribers, and every proof must verify.
+pub fn check_test_vectors(vectors: &TestVectors) -> Result<()> {
+    if vectors.format != TEST_VECTORS_V1 {
+        return Err(anyhow::anyhow!(
+            "Unknown test vector format '{}'",
+            vectors.format
+        ));
+    }
+    if vectors.leaf_encoding != SUBSCRIBER_LEAF_ENCODING {
+        return Err(anyhow::anyhow!(
+            "Unsupported leaf encoding {:?}, expected {:?}",
+            vectors.leaf_encoding,
+            SUBSCRIBER_LEAF_ENCODING
+        ));
+    }
+
+    for case in &vectors.cases {
+        let options = TreeOptions {
+            layout: TreeLayout::from_name(&case.layout)?,
+            sort_leaves: case.sort_leaves,
+            ..TreeOptions::default()
+        };
+        let expected = build_case(&case.name, options, case.subscribers.clone())?;
+
+        let mismatch = |field: &str| anyhow::anyhow!("{}: {} do not match", case.name, field);
+        if case.leaves != expected.leaves {
+            return Err(mismatch("leaves"));
+        }
+        if case.layers != expected.layers || case.tree != expected.tree {
+            return Err(mismatch("nodes"));
+        }
+        if case.root != expected.root {
+            return Err(mismatch("roots"));
+        }
+        if case.proofs != expected.proofs {
+            return Err(mismatch("proofs"));
+        }
+
+        let root = decode_hash(&case.root)?;
+        for (value_index, (leaf, proof)) in case.leaves.iter().zip(&case.proofs).enumerate() {
+            let proof = Proof::new(
+                proof
+                    .iter()
+                    .map(|p| decode_hash(p))
+                    .collect::<Result<_>>()?,
+            );
+            if !OzMerkleTree::verify(&root, &proof, &decode_hash(leaf)?) {
+                return Err(anyhow::anyhow!(
+                    "{}: proof of value {} does not verify",
+                    case.name,
+                    value_index
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_hash(hex_str: &str) -> Result<[u8; 32]> {
+    hex::decode(hex_str.trim_start_matches("0x"))
+        .context("Invalid hash hex")?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Hash must be 32 bytes"))
+}
+
+pub fn write_test_vectors(path: impl AsRef<Path>, vectors: &TestVectors) -> Result<()> {
+    let json = serde_json::to_string_pretty(vectors)?;
+    fs::write(path, json + "\n").context("Failed to write test vectors")?;
+    Ok(())
+}
+
+pub fn read_test_vectors(path: impl AsRef<Path>) -> Result<TestVectors> {
+    let json = fs::read_to_string(path).context("Failed to read test vectors")?;
+    serde_json::from_str(&json).context("Failed to parse test vector JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOLDEN_PATH: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/test-vectors/subscription-vectors-v1.json"
+    );
+
+    #[test]
+    fn golden_vectors_match_this_implementation() {
+        let golden = read_test_vectors(GOLDEN_PATH).unwrap();
+        check_test_vectors(&golden).unwrap();
+        // Also catches cases added to or dropped from the generator
+        assert_eq!(golden, generate_test_vectors().unwrap());
+    }
+
+    #[test]
+    fn tampered_vectors_fail() {
+        let golden = read_test_vectors(GOLDEN_PATH).unwrap();
+
+        let mut tampered = golden.clone();
+        tampered.cases[3].root = tampered.cases[4].root.clone();
+        assert!(check_test_vectors(&tampered).is_err());
+
+        let mut tampered = golden.clone();
+        tampered.cases[0].subscribers[0].expiration = Expiration::new(1).unwrap();
+        assert!(check_test_vectors(&tampered).is_err());
+
+        let mut tampered = golden;
+        let last = tampered.cases.len() - 1;
+        tampered.cases[last].proofs[0].pop();
+        assert!(check_test_vectors(&tampered).is_err());
+    }
+}
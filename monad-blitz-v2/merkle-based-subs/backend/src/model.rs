@@ -1,15 +1,243 @@
-use chrono::{DateTime, Utc};
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use ethers::types::U256;
 use serde::{Deserialize, Serialize};
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef};
 use sqlx::prelude::FromRow;
+use sqlx::{Decode, Encode, Postgres, Type};
+use std::fmt;
+
+/// Subscription tier, as stored in `subscriber_storage.plan_id`.
+/// Opaque to the backend: the contract decides what each plan unlocks.
+pub type PlanId = u32;
+
+/// Product a subscription belongs to, as stored in `products.id`
+pub type ProductId = u32;
+
+/// Product of every subscription made before products existed. Trees whose leaves carry
+/// no product (plain and tiered) are built from this product's subscriptions only.
+pub const DEFAULT_PRODUCT: ProductId = 0;
+
+/// Credit epoch of usage-quota subscriptions, as stored in `credit_epochs.epoch`
+pub type Epoch = u64;
+
+/// Index of one entitlement (API access, premium content, ...) in an `Entitlements` bitmap
+pub type Entitlement = u8;
+
+/// 256-bit entitlement bitmap, as stored in `subscriber_storage.entitlements` (32 bytes,
+/// big-endian) and committed as the `uint256 entitlements` of entitled leaves.
+/// Entitlement k is bit k, so the contract checks `entitlements & required == required`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Entitlements(U256);
+
+impl Entitlements {
+    pub const NONE: Entitlements = Entitlements(U256::zero());
+
+    /// Bitmap with exactly the given entitlements
+    pub fn from_entitlements(entitlements: &[Entitlement]) -> Self {
+        entitlements
+            .iter()
+            .fold(Self::NONE, |bitmap, &entitlement| bitmap.with(entitlement))
+    }
+
+    /// Decode the 32-byte big-endian column value
+    pub fn from_be_slice(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 32 {
+            return Err(anyhow::anyhow!(
+                "Entitlement bitmap must be 32 bytes, got {}",
+                bytes.len()
+            ));
+        }
+        Ok(Entitlements(U256::from_big_endian(bytes)))
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        self.0.to_big_endian(&mut bytes);
+        bytes
+    }
+
+    /// The value as the contract's `uint256 entitlements`
+    pub fn to_u256(self) -> U256 {
+        self.0
+    }
+
+    pub fn with(self, entitlement: Entitlement) -> Self {
+        Entitlements(self.0 | (U256::one() << entitlement))
+    }
+
+    pub fn without(self, entitlement: Entitlement) -> Self {
+        Entitlements(self.0 & !(U256::one() << entitlement))
+    }
+
+    pub fn contains(self, entitlement: Entitlement) -> bool {
+        self.0.bit(entitlement as usize)
+    }
+
+    /// Whether every entitlement of `required` is granted
+    pub fn contains_all(self, required: Entitlements) -> bool {
+        self.0 & required.0 == required.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+impl From<U256> for Entitlements {
+    fn from(bitmap: U256) -> Self {
+        Entitlements(bitmap)
+    }
+}
+
+// Serialized as a 0x-prefixed 64-digit hex string, like the other 32-byte values in responses
+impl TryFrom<String> for Entitlements {
+    type Error = anyhow::Error;
+
+    fn try_from(hex_str: String) -> Result<Self> {
+        let bytes = hex::decode(hex_str.trim_start_matches("0x"))
+            .map_err(|e| anyhow::anyhow!("Invalid entitlement bitmap '{}': {}", hex_str, e))?;
+        Self::from_be_slice(&bytes)
+    }
+}
+
+impl From<Entitlements> for String {
+    fn from(entitlements: Entitlements) -> String {
+        entitlements.to_string()
+    }
+}
+
+impl fmt::Display for Entitlements {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{}", hex::encode(self.to_be_bytes()))
+    }
+}
+
+/// A subscription expiration as a Unix timestamp in seconds.
+/// Always non-negative and at most `i64::MAX`, so it round-trips through the BIGINT column
+/// and encodes to the same uint256 the contract compares against `block.timestamp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "i64", into = "i64")]
+pub struct Expiration(u64);
+
+impl Expiration {
+    /// Largest timestamp that still fits in the BIGINT column
+    pub const MAX: Expiration = Expiration(i64::MAX as u64);
+
+    pub fn new(timestamp: u64) -> Result<Self> {
+        if timestamp > Self::MAX.0 {
+            return Err(anyhow::anyhow!(
+                "Expiration {} does not fit in a BIGINT column",
+                timestamp
+            ));
+        }
+        Ok(Expiration(timestamp))
+    }
+
+    pub fn from_unix(timestamp: i64) -> Result<Self> {
+        u64::try_from(timestamp)
+            .map(Expiration)
+            .map_err(|_| anyhow::anyhow!("Expiration {} is negative", timestamp))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_i64(self) -> i64 {
+        self.0 as i64
+    }
+
+    /// The value as the contract's `uint256 expiration`
+    pub fn to_u256(self) -> U256 {
+        U256::from(self.0)
+    }
+}
+
+impl TryFrom<i64> for Expiration {
+    type Error = anyhow::Error;
+
+    fn try_from(timestamp: i64) -> Result<Self> {
+        Self::from_unix(timestamp)
+    }
+}
+
+impl TryFrom<U256> for Expiration {
+    type Error = anyhow::Error;
+
+    fn try_from(timestamp: U256) -> Result<Self> {
+        if timestamp > U256::from(Self::MAX.0) {
+            return Err(anyhow::anyhow!(
+                "Expiration {} does not fit in a BIGINT column",
+                timestamp
+            ));
+        }
+        Ok(Expiration(timestamp.as_u64()))
+    }
+}
+
+impl From<Expiration> for i64 {
+    fn from(expiration: Expiration) -> i64 {
+        expiration.as_i64()
+    }
+}
+
+impl fmt::Display for Expiration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// Stored as BIGINT; rows with a negative value fail to decode instead of producing a bad leaf
+impl Type<Postgres> for Expiration {
+    fn type_info() -> PgTypeInfo {
+        <i64 as Type<Postgres>>::type_info()
+    }
+}
+
+impl Encode<'_, Postgres> for Expiration {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        <i64 as Encode<Postgres>>::encode_by_ref(&self.as_i64(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for Expiration {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let timestamp = <i64 as Decode<Postgres>>::decode(value)?;
+        Ok(Expiration::from_unix(timestamp)?)
+    }
+}
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 
 pub struct SubscriberStorage {
     pub wallet_address: String,
-    pub expiration_ts: i64, // BIGINT - Unix timestamp
+    pub expiration_ts: Expiration, // BIGINT - Unix timestamp
+    pub plan_id: i32,
+    pub product_id: i32,
+    pub entitlements: Vec<u8>,         // BYTEA - 32-byte big-endian bitmap
+    pub credit_allowance: Option<i64>, // Credits per epoch, None for time-based only
     pub last_updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Product {
+    pub id: i32,
+    pub name: String,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct CreditEpoch {
+    pub epoch: i64,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+    pub root_hash: Option<String>,
+}
+
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct MerkleState {
     pub id: i32,
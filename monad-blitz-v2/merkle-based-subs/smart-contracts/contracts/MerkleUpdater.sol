@@ -0,0 +1,63 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.20;
+
+import "@openzeppelin/contracts/access/Ownable.sol";
+import "@openzeppelin/contracts/utils/cryptography/MerkleProof.sol";
+
+/// Holds the subscription root the backend publishes and verifies proofs against it.
+/// Leaves are OpenZeppelin StandardMerkleTree leaves,
+/// keccak256(bytes.concat(keccak256(abi.encode(...)))), built by the backend's `merkle` module.
+contract MerkleUpdater is Ownable {
+    /// Leads product leaves, as merkle::encoding::LeafKind::Product
+    uint8 private constant LEAF_KIND_PRODUCT = 1;
+
+    bytes32 public currentRoot;
+
+    event MerkleRootUpdated(bytes32 newRoot);
+    event SubscriptionVerified(address indexed subscriber, uint256 expiration);
+
+    constructor() Ownable(msg.sender) {}
+
+    function updateMerkleRoot(bytes32 newRoot) external onlyOwner returns (bool) {
+        currentRoot = newRoot;
+        emit MerkleRootUpdated(newRoot);
+        return true;
+    }
+
+    /// Plain leaf: abi.encode(msg.sender, expiration)
+    function verifySubscription(bytes32[] calldata proof, uint256 expiration) external {
+        _verify(proof, abi.encode(msg.sender, expiration), expiration);
+    }
+
+    /// Tiered leaf: abi.encode(msg.sender, planId, expiration) (see merkle::tiers).
+    /// Deciding what each plan unlocks is up to the caller.
+    function verifySubscription(bytes32[] calldata proof, uint256 planId, uint256 expiration)
+        external
+    {
+        _verify(proof, abi.encode(msg.sender, planId, expiration), expiration);
+    }
+
+    /// Product leaf: abi.encode(uint8(1), msg.sender, productId, planId, expiration), led by
+    /// the backend's LeafKind.Product (see merkle::products)
+    function verifySubscription(
+        bytes32[] calldata proof,
+        uint256 productId,
+        uint256 planId,
+        uint256 expiration
+    ) external {
+        _verify(
+            proof,
+            abi.encode(LEAF_KIND_PRODUCT, msg.sender, productId, planId, expiration),
+            expiration
+        );
+    }
+
+    function _verify(bytes32[] calldata proof, bytes memory encodedLeaf, uint256 expiration)
+        internal
+    {
+        require(expiration > block.timestamp, "Subscription expired");
+        bytes32 leaf = keccak256(bytes.concat(keccak256(encodedLeaf)));
+        require(MerkleProof.verify(proof, currentRoot, leaf), "Invalid proof");
+        emit SubscriptionVerified(msg.sender, expiration);
+    }
+}